@@ -1,11 +1,43 @@
 //! Module containing the multi-keychain [`Wallet`].
 
+mod address;
+mod anti_fee_sniping;
 mod changeset;
+mod coin_selection;
+mod cpfp;
+mod drain;
+mod fee_bump;
+mod history;
 pub mod keyring;
+mod payment_queue;
+mod read_only;
+mod snapshot;
+mod spending_priority;
+mod sweep;
+#[cfg(test)]
+mod test_utils;
+mod timelock;
+mod transaction;
+mod tx_builder;
 mod wallet;
 
+pub use address::*;
+pub use anti_fee_sniping::*;
 pub use changeset::*;
+pub use coin_selection::*;
+pub use cpfp::*;
+pub use drain::*;
+pub use fee_bump::*;
+pub use history::*;
 pub use keyring::KeyRing;
+pub use payment_queue::*;
+pub use read_only::*;
+pub use snapshot::*;
+pub use spending_priority::*;
+pub use sweep::*;
+pub use timelock::*;
+pub use transaction::*;
+pub use tx_builder::*;
 pub use wallet::*;
 
 /// Alias for [`DescriptorId`](bdk_chain::DescriptorId).
@@ -0,0 +1,290 @@
+//! Address lookups and management of the [`Wallet`].
+
+use core::fmt;
+
+use bitcoin::{address::NetworkUnchecked, Address, Network, Script, ScriptBuf};
+
+use crate::multi_keychain::{AddressInfo, Wallet};
+
+/// Number of derivation indices past the lookahead window of each keychain searched by
+/// [`Wallet::lookup_address`] and [`Wallet::lookup_script`].
+pub const LOOKUP_DERIVATION_LIMIT: u32 = 1_000;
+
+/// Where a script pubkey owned by the [`Wallet`] was found, see [`Wallet::lookup_address`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressLookup<K> {
+    /// Keychain the script pubkey belongs to.
+    pub keychain: K,
+    /// Derivation index of the script pubkey in the keychain.
+    pub index: u32,
+    /// Status of the script pubkey.
+    pub status: AddressStatus,
+}
+
+/// Status of a script pubkey owned by the [`Wallet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressStatus {
+    /// Revealed and used, either on-chain or marked as such.
+    Used,
+    /// Revealed but not used yet.
+    Revealed,
+    /// Not revealed yet but within the lookahead window of the keychain.
+    Lookahead,
+    /// Beyond the lookahead window of the keychain, so that outputs paying to it are not
+    /// detected until the addresses up to it are revealed, see
+    /// [`Wallet::reveal_addresses_to`].
+    BeyondLookahead,
+}
+
+/// Error returned when an address is not valid for the network of the [`Wallet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkMismatchError {
+    /// Network of the wallet.
+    pub expected: Network,
+}
+
+impl fmt::Display for NetworkMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "address is not valid for network {}", self.expected)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NetworkMismatchError {}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Find which keychain and derivation index an `address` belongs to.
+    ///
+    /// The address is first validated against the network of the wallet. Returns `Ok(None)` if
+    /// the address is not owned by any keychain, see [`Wallet::lookup_script`].
+    pub fn lookup_address(
+        &self,
+        address: Address<NetworkUnchecked>,
+    ) -> Result<Option<AddressLookup<K>>, NetworkMismatchError> {
+        let expected = self.network();
+        if !address.is_valid_for_network(expected) {
+            return Err(NetworkMismatchError { expected });
+        }
+        Ok(self.lookup_script(&address.assume_checked().script_pubkey()))
+    }
+
+    /// List the revealed addresses of `keychain` in ascending order of derivation index.
+    pub fn list_revealed_addresses(
+        &self,
+        keychain: K,
+    ) -> impl DoubleEndedIterator<Item = AddressInfo<K>> + '_ {
+        let spks = self.txout_index().revealed_keychain_spks(keychain.clone());
+        self.address_infos(keychain, spks)
+    }
+
+    /// List the revealed addresses of `keychain` that are unused, in ascending order of
+    /// derivation index.
+    ///
+    /// Addresses marked as used with [`Wallet::mark_used`] are not listed.
+    pub fn list_unused_addresses(
+        &self,
+        keychain: K,
+    ) -> impl DoubleEndedIterator<Item = AddressInfo<K>> + '_ {
+        let spks = self.txout_index().unused_keychain_spks(keychain.clone());
+        self.address_infos(keychain, spks)
+    }
+
+    fn address_infos<'a>(
+        &self,
+        keychain: K,
+        spks: impl DoubleEndedIterator<Item = (u32, ScriptBuf)> + 'a,
+    ) -> impl DoubleEndedIterator<Item = AddressInfo<K>> + 'a
+    where
+        K: 'a,
+    {
+        let network = self.network();
+        spks.map(move |(index, spk)| AddressInfo {
+            index,
+            address: Address::from_script(&spk, network).expect("script should have address form"),
+            keychain: keychain.clone(),
+        })
+    }
+
+    /// Find which keychain and derivation index a script pubkey belongs to.
+    ///
+    /// Script pubkeys beyond the lookahead window are derived, up to
+    /// [`LOOKUP_DERIVATION_LIMIT`] past the window of each keychain. Returns `None` if the
+    /// script pubkey is not owned by any keychain within these bounds.
+    pub fn lookup_script(&self, script: &Script) -> Option<AddressLookup<K>> {
+        let index = self.txout_index();
+        let (keychain, derivation_index) = match index.index_of_spk(script.into()) {
+            Some(spk_i) => spk_i.clone(),
+            None => {
+                let (keychain, index) = self.lookup_beyond_lookahead(script)?;
+                return Some(AddressLookup {
+                    keychain,
+                    index,
+                    status: AddressStatus::BeyondLookahead,
+                });
+            }
+        };
+        let status = match index.last_revealed_index(keychain.clone()) {
+            Some(last) if derivation_index <= last => {
+                if index.is_used(keychain.clone(), derivation_index) {
+                    AddressStatus::Used
+                } else {
+                    AddressStatus::Revealed
+                }
+            }
+            _ => AddressStatus::Lookahead,
+        };
+        Some(AddressLookup {
+            keychain,
+            index: derivation_index,
+            status,
+        })
+    }
+
+    /// Derive the script pubkeys past the lookahead window of each keychain to find `script`.
+    fn lookup_beyond_lookahead(&self, script: &Script) -> Option<(K, u32)> {
+        let index = self.txout_index();
+        index.keychains().find_map(|(keychain, descriptor)| {
+            if !descriptor.has_wildcard() {
+                return None;
+            }
+            // The first index not derived by the txout index.
+            let start = index
+                .last_revealed_index(keychain.clone())
+                .map_or(0, |last| last + 1)
+                .saturating_add(index.lookahead());
+            (start..start.saturating_add(LOOKUP_DERIVATION_LIMIT)).find_map(|i| {
+                let spk = descriptor.at_derivation_index(i).ok()?.script_pubkey();
+                (spk.as_script() == script).then(|| (keychain.clone(), i))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use bitcoin::{hashes::Hash, Address, Amount};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    #[test]
+    fn lookup_address() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        receive_confirmed(&mut wallet, k1, Amount::from_sat(10_000));
+        let revealed = wallet.reveal_next_address(k1).unwrap();
+
+        let lookup = |wallet: &Wallet<_>, address: &Address| {
+            wallet
+                .lookup_address(address.as_unchecked().clone())
+                .unwrap()
+        };
+
+        let used = wallet.txout_index().spk_at_index(k1, 0).unwrap();
+        let used = Address::from_script(&used, Network::Signet).unwrap();
+        assert_eq!(
+            lookup(&wallet, &used),
+            Some(AddressLookup {
+                keychain: k1,
+                index: 0,
+                status: AddressStatus::Used
+            })
+        );
+        assert_eq!(
+            lookup(&wallet, &revealed.address).map(|l| (l.index, l.status)),
+            Some((1, AddressStatus::Revealed))
+        );
+
+        let lookahead = wallet.txout_index().spk_at_index(k0, 5).unwrap();
+        let lookahead = Address::from_script(&lookahead, Network::Signet).unwrap();
+        assert_eq!(
+            lookup(&wallet, &lookahead).map(|l| (l.keychain, l.status)),
+            Some((k0, AddressStatus::Lookahead))
+        );
+
+        let derive = |i| {
+            wallet
+                .txout_index()
+                .get_descriptor(k0)
+                .unwrap()
+                .at_derivation_index(i)
+                .unwrap()
+                .address(Network::Signet)
+                .unwrap()
+        };
+        let beyond = derive(100);
+        assert_eq!(
+            lookup(&wallet, &beyond),
+            Some(AddressLookup {
+                keychain: k0,
+                index: 100,
+                status: AddressStatus::BeyondLookahead
+            })
+        );
+        let too_far = derive(5_000);
+        assert_eq!(lookup(&wallet, &too_far), None);
+        let foreign = Address::from_script(
+            &ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([1; 20])),
+            Network::Signet,
+        )
+        .unwrap();
+        assert_eq!(lookup(&wallet, &foreign), None);
+
+        // Once revealed, the address is detected.
+        let _ = wallet.reveal_addresses_to(k0, 100).count();
+        assert_eq!(
+            lookup(&wallet, &beyond).map(|l| l.status),
+            Some(AddressStatus::Revealed)
+        );
+
+        let mainnet = Address::from_script(&revealed.script_pubkey(), Network::Bitcoin).unwrap();
+        assert_eq!(
+            wallet.lookup_address(mainnet.into_unchecked()),
+            Err(NetworkMismatchError {
+                expected: Network::Signet
+            })
+        );
+    }
+
+    #[test]
+    fn reveal_and_list_addresses() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let _ = wallet.reveal_next_address(k0);
+
+        let revealed = wallet.reveal_addresses_to(k0, 4).collect::<Vec<_>>();
+        assert_eq!(
+            revealed.iter().map(|a| a.index).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(
+            wallet.staged().unwrap().indexer.last_revealed.get(&k0),
+            Some(&4)
+        );
+        assert_eq!(wallet.reveal_addresses_to(k0, 2).count(), 0);
+        let unknown = descriptor_id(DESCRIPTORS[5]);
+        assert_eq!(wallet.reveal_addresses_to(unknown, 2).count(), 0);
+
+        let listed = wallet.list_revealed_addresses(k0).collect::<Vec<_>>();
+        assert_eq!(listed.len(), 5);
+        assert_eq!(listed[1..], revealed[..]);
+        assert_eq!(wallet.list_revealed_addresses(k1).count(), 0);
+
+        // Used addresses, on-chain or marked, are not listed as unused.
+        wallet.mark_used(k0, 1);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        assert_eq!(
+            wallet
+                .list_unused_addresses(k0)
+                .map(|a| a.index)
+                .collect::<Vec<_>>(),
+            [0, 2, 3, 4]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Discouraging fee sniping with the `nLockTime` and `nSequence` of the transactions built by the
+//! [`Wallet`], see [`AntiFeeSniping`].
+
+use core::fmt;
+
+use bdk_chain::ChainPosition;
+use bitcoin::{absolute, Sequence, Transaction};
+use miniscript::descriptor::DescriptorType;
+
+use crate::bdk_chain;
+use crate::multi_keychain::{LocalOutput, SplitMix64, Wallet};
+
+/// Maximum number of blocks the `nLockTime` is backdated by.
+const MAX_BACKDATE: u64 = 100;
+
+/// How transactions built by the [`Wallet`] discourage fee sniping, i.e. miners reorganizing the
+/// chain to take the fees of recent blocks, by only being valid on top of the current tip.
+///
+/// See [`TxBuilder::anti_fee_sniping`](crate::multi_keychain::TxBuilder::anti_fee_sniping).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiFeeSniping {
+    /// Leave the `nLockTime` to zero, unless required by a spending path.
+    Disabled,
+    /// Set the `nLockTime` to the height of the tip of the local chain.
+    LockTime,
+    /// Do as Bitcoin Core, drawing the random choices from `seed`.
+    ///
+    /// If every input spends a confirmed taproot output, half of the time the `nSequence` of a
+    /// random input is set to its number of confirmations instead, as in BIP326, so that these
+    /// transactions look like those using relative timelocks. Otherwise the `nLockTime` is set to
+    /// the height of the tip, backdated by up to 99 blocks one time in ten to hide when the
+    /// transaction was built.
+    Randomized {
+        /// Seed of the random choices.
+        seed: u64,
+    },
+}
+
+impl Default for AntiFeeSniping {
+    /// [`AntiFeeSniping::Randomized`] with a random seed if the `std` feature is enabled,
+    /// [`AntiFeeSniping::LockTime`] otherwise.
+    fn default() -> Self {
+        #[cfg(feature = "std")]
+        {
+            Self::Randomized {
+                seed: bitcoin::secp256k1::rand::random(),
+            }
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            Self::LockTime
+        }
+    }
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// The descriptor type and confirmation height of `utxo`, as taken by
+    /// [`Wallet::discourage_fee_sniping`].
+    pub(crate) fn anti_fee_sniping_input(
+        &self,
+        utxo: &LocalOutput<K>,
+    ) -> (DescriptorType, Option<u32>) {
+        let desc_type = self
+            .txout_index()
+            .get_descriptor(utxo.keychain.clone())
+            .map_or(DescriptorType::Bare, |descriptor| descriptor.desc_type());
+        let height = match utxo.chain_position {
+            ChainPosition::Confirmed { anchor, .. } => Some(anchor.block_id.height),
+            ChainPosition::Unconfirmed { .. } => None,
+        };
+        (desc_type, height)
+    }
+
+    /// Set the `nLockTime` of `tx`, or the `nSequence` of one of its inputs, as required by
+    /// `anti_fee_sniping` at the current tip.
+    ///
+    /// `inputs` holds the descriptor type of the output spent by each input and its confirmation
+    /// height, if confirmed. An `nLockTime` in time or `nSequence` values set for the timelocks of
+    /// the spending paths are left untouched, and an `nLockTime` in blocks is only raised.
+    pub(crate) fn discourage_fee_sniping(
+        &self,
+        tx: &mut Transaction,
+        inputs: &[(DescriptorType, Option<u32>)],
+        anti_fee_sniping: AntiFeeSniping,
+    ) {
+        let tip = self.local_chain().tip().height();
+        let mut height = match anti_fee_sniping {
+            AntiFeeSniping::Disabled => return,
+            AntiFeeSniping::LockTime => tip,
+            AntiFeeSniping::Randomized { seed } => {
+                let mut rng = SplitMix64(seed);
+                // The nSequence of an input only discourages fee sniping if it is not final and
+                // has no other purpose.
+                let sequence_free = tx.lock_time == absolute::LockTime::ZERO
+                    && tx
+                        .input
+                        .iter()
+                        .all(|txin| txin.sequence == Sequence::ENABLE_RBF_NO_LOCKTIME);
+                let confirmations = inputs
+                    .iter()
+                    .map(|(desc_type, height)| match (desc_type, height) {
+                        (DescriptorType::Tr, Some(height)) => Some(tip + 1 - height),
+                        _ => None,
+                    })
+                    .collect::<Option<alloc::vec::Vec<_>>>()
+                    .filter(|confirmations| {
+                        confirmations.iter().all(|&c| c <= u32::from(u16::MAX))
+                    });
+                match confirmations {
+                    Some(confirmations)
+                        if sequence_free && !confirmations.is_empty() && rng.below(2) == 0 =>
+                    {
+                        let i = rng.below(confirmations.len() as u64) as usize;
+                        tx.input[i].sequence = Sequence::from_height(confirmations[i] as u16);
+                        return;
+                    }
+                    _ => {}
+                }
+                if rng.below(10) == 0 {
+                    tip.saturating_sub(rng.below(MAX_BACKDATE) as u32)
+                } else {
+                    tip
+                }
+            }
+        };
+        match tx.lock_time {
+            absolute::LockTime::Blocks(lock_time) => {
+                height = height.max(lock_time.to_consensus_u32());
+            }
+            absolute::LockTime::Seconds(_) => return,
+        }
+        tx.lock_time = absolute::LockTime::from_height(height).expect("valid block height");
+        // The nLockTime is only enforced if an input is not final.
+        if tx.input.iter().all(|txin| txin.sequence == Sequence::MAX) {
+            if let Some(txin) = tx.input.first_mut() {
+                txin.sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use bdk_chain::DescriptorId;
+    use bitcoin::{hashes::Hash, Amount, Psbt, ScriptBuf, WPubkeyHash};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    fn build(wallet: &mut Wallet<DescriptorId>, anti_fee_sniping: AntiFeeSniping) -> Psbt {
+        wallet
+            .build_tx()
+            .add_recipient(
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                Amount::from_sat(10_000),
+            )
+            .anti_fee_sniping(anti_fee_sniping)
+            .finish()
+            .unwrap()
+    }
+
+    fn extend_chain(wallet: &mut Wallet<DescriptorId>, height: u32) {
+        while wallet.latest_checkpoint().height() < height {
+            add_block(wallet, vec![]);
+        }
+    }
+
+    #[test]
+    fn lock_time_at_tip() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        extend_chain(&mut wallet, 150);
+        let tip = absolute::LockTime::from_height(150).unwrap();
+
+        let psbt = build(&mut wallet, AntiFeeSniping::LockTime);
+        assert_eq!(psbt.unsigned_tx.lock_time, tip);
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence,
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+        let psbt = build(&mut wallet, AntiFeeSniping::Disabled);
+        assert_eq!(psbt.unsigned_tx.lock_time, absolute::LockTime::ZERO);
+
+        // Without RBF, the nLockTime is still enforced.
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                Amount::from_sat(10_000),
+            )
+            .anti_fee_sniping(AntiFeeSniping::LockTime)
+            .rbf(false)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time, tip);
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence,
+            Sequence::ENABLE_LOCKTIME_NO_RBF
+        );
+        assert!(!psbt.unsigned_tx.is_explicitly_rbf());
+    }
+
+    #[test]
+    fn randomized_lock_time() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        extend_chain(&mut wallet, 150);
+
+        let mut backdated = 0;
+        for seed in 0..200 {
+            let psbt = build(&mut wallet, AntiFeeSniping::Randomized { seed });
+            let lock_time = psbt.unsigned_tx.lock_time.to_consensus_u32();
+            assert!((51..=150).contains(&lock_time), "{lock_time}");
+            if lock_time < 150 {
+                backdated += 1;
+            }
+            // Only taproot inputs have their nSequence changed.
+            assert_eq!(
+                psbt.unsigned_tx.input[0].sequence,
+                Sequence::ENABLE_RBF_NO_LOCKTIME
+            );
+        }
+        assert!((5..=40).contains(&backdated), "{backdated}");
+
+        // The choices only depend on the seed.
+        let lock_times = (0..20)
+            .map(|seed| {
+                build(&mut wallet, AntiFeeSniping::Randomized { seed })
+                    .unsigned_tx
+                    .lock_time
+            })
+            .collect::<Vec<_>>();
+        for (seed, lock_time) in (0..20).zip(lock_times) {
+            let psbt = build(&mut wallet, AntiFeeSniping::Randomized { seed });
+            assert_eq!(psbt.unsigned_tx.lock_time, lock_time);
+        }
+    }
+
+    #[test]
+    fn taproot_sequence() {
+        let mut wallet = new_wallet(&[TR_DESCRIPTOR]);
+        let k = descriptor_id(TR_DESCRIPTOR);
+        extend_chain(&mut wallet, 140);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        let height = wallet.latest_checkpoint().height();
+        extend_chain(&mut wallet, height + 9);
+        let confirmations = Sequence::from_height(10);
+
+        let mut with_sequence = 0;
+        for seed in 0..100 {
+            let tx = build(&mut wallet, AntiFeeSniping::Randomized { seed }).unsigned_tx;
+            if tx.input[0].sequence == confirmations {
+                assert_eq!(tx.lock_time, absolute::LockTime::ZERO);
+                with_sequence += 1;
+            } else {
+                assert_eq!(tx.input[0].sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+                assert!(tx.lock_time.to_consensus_u32() > 0);
+            }
+        }
+        assert!((25..=75).contains(&with_sequence), "{with_sequence}");
+
+        // An unconfirmed taproot input always gets the nLockTime.
+        wallet.set_keychain_trusted(k, true);
+        receive_unconfirmed(&mut wallet, k, Amount::from_sat(60_000), 1);
+        for seed in 0..20 {
+            let tx = wallet
+                .build_tx()
+                .add_recipient(
+                    ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                    Amount::from_sat(100_000),
+                )
+                .anti_fee_sniping(AntiFeeSniping::Randomized { seed })
+                .finish()
+                .unwrap()
+                .unsigned_tx;
+            assert_eq!(tx.input.len(), 2);
+            assert!(tx
+                .input
+                .iter()
+                .all(|txin| txin.sequence == Sequence::ENABLE_RBF_NO_LOCKTIME));
+            assert!(tx.lock_time.to_consensus_u32() > 0);
+        }
+    }
+}
@@ -1,10 +1,13 @@
+use alloc::string::String;
 use bdk_chain::{
     indexed_tx_graph, keychain_txout, local_chain, tx_graph, ConfirmationBlockTime, Merge,
 };
+use bitcoin::OutPoint;
 use serde::{Deserialize, Serialize};
 
 use crate::bdk_chain;
-use crate::multi_keychain::keyring;
+use crate::collections::BTreeMap;
+use crate::multi_keychain::{keyring, QueuedPayment, SpendingPriority, UtxoLock};
 
 /// Change set.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -17,6 +20,26 @@ pub struct ChangeSet<K: Ord> {
     pub tx_graph: tx_graph::ChangeSet<ConfirmationBlockTime>,
     /// Changes to [`KeychainTxOutIndex`](keychain_txout::KeychainTxOutIndex).
     pub indexer: keychain_txout::ChangeSet,
+    /// Script pubkeys marked as used (`true`) or unused (`false`) by keychain and derivation
+    /// index, see [`Wallet::mark_used`](crate::multi_keychain::Wallet::mark_used).
+    #[serde(default = "BTreeMap::new")]
+    pub marked_used: BTreeMap<K, BTreeMap<u32, bool>>,
+    /// Locks set on outpoints, `None` if removed, see
+    /// [`Wallet::lock_outpoint`](crate::multi_keychain::Wallet::lock_outpoint).
+    #[serde(default)]
+    pub utxo_locks: BTreeMap<OutPoint, Option<UtxoLock>>,
+    /// Payments of the queue by id, `None` if removed, see
+    /// [`Wallet::enqueue_payment`](crate::multi_keychain::Wallet::enqueue_payment).
+    #[serde(default)]
+    pub payments: BTreeMap<String, Option<QueuedPayment>>,
+    /// Keychains whose unconfirmed outputs are trusted (`true`) or no longer trusted (`false`),
+    /// see [`Wallet::set_keychain_trusted`](crate::multi_keychain::Wallet::set_keychain_trusted).
+    #[serde(default = "BTreeMap::new")]
+    pub trusted_keychains: BTreeMap<K, bool>,
+    /// The spending priority, if set, see
+    /// [`Wallet::set_spending_priority`](crate::multi_keychain::Wallet::set_spending_priority).
+    #[serde(default = "Option::default")]
+    pub spending_priority: Option<SpendingPriority<K>>,
 }
 
 impl<K: Ord> Default for ChangeSet<K> {
@@ -26,6 +49,11 @@ impl<K: Ord> Default for ChangeSet<K> {
             local_chain: Default::default(),
             tx_graph: Default::default(),
             indexer: Default::default(),
+            marked_used: Default::default(),
+            utxo_locks: Default::default(),
+            payments: Default::default(),
+            trusted_keychains: Default::default(),
+            spending_priority: Default::default(),
         }
     }
 }
@@ -39,6 +67,25 @@ impl<K: Ord> Merge for ChangeSet<K> {
         Merge::merge(&mut self.local_chain, other.local_chain);
         Merge::merge(&mut self.tx_graph, other.tx_graph);
         Merge::merge(&mut self.indexer, other.indexer);
+
+        // merge used marks, later marks override earlier ones
+        for (keychain, marks) in other.marked_used {
+            self.marked_used.entry(keychain).or_default().extend(marks);
+        }
+
+        // merge utxo locks, later locks override earlier ones
+        self.utxo_locks.extend(other.utxo_locks);
+
+        // merge queued payments, later states override earlier ones
+        self.payments.extend(other.payments);
+
+        // merge trusted keychains, later settings override earlier ones
+        self.trusted_keychains.extend(other.trusted_keychains);
+
+        // merge spending priority, a later priority replaces an earlier one
+        if other.spending_priority.is_some() {
+            self.spending_priority = other.spending_priority;
+        }
     }
 
     fn is_empty(&self) -> bool {
@@ -46,6 +93,11 @@ impl<K: Ord> Merge for ChangeSet<K> {
             && self.local_chain.is_empty()
             && self.tx_graph.is_empty()
             && self.indexer.is_empty()
+            && self.marked_used.is_empty()
+            && self.utxo_locks.is_empty()
+            && self.payments.is_empty()
+            && self.trusted_keychains.is_empty()
+            && self.spending_priority.is_none()
     }
 }
 
@@ -54,6 +106,12 @@ use bdk_chain::rusqlite;
 #[cfg(feature = "rusqlite")]
 use bdk_chain::DescriptorId;
 
+#[cfg(feature = "rusqlite")]
+use crate::multi_keychain::{
+    spending_priority::{descriptor_type_from_name, descriptor_type_name},
+    Preference,
+};
+
 #[cfg(feature = "rusqlite")]
 impl ChangeSet<DescriptorId> {
     /// Schema name for wallet.
@@ -62,6 +120,20 @@ impl ChangeSet<DescriptorId> {
     pub const WALLET_TABLE_NAME: &'static str = "bdk_wallet";
     /// Name of table to store wallet descriptors.
     pub const DESCRIPTORS_TABLE_NAME: &'static str = "bdk_descriptor";
+    /// Name of table to store keychain groups.
+    pub const KEYCHAIN_GROUPS_TABLE_NAME: &'static str = "bdk_keychain_group";
+    /// Name of table to store script pubkeys marked as used.
+    pub const MARKED_USED_TABLE_NAME: &'static str = "bdk_marked_used";
+    /// Name of table to store utxo locks.
+    pub const UTXO_LOCKS_TABLE_NAME: &'static str = "bdk_utxo_lock";
+    /// Name of table to store change keychain pairings.
+    pub const CHANGE_KEYCHAINS_TABLE_NAME: &'static str = "bdk_change_keychain";
+    /// Name of table to store the payment queue.
+    pub const PAYMENTS_TABLE_NAME: &'static str = "bdk_payment";
+    /// Name of table to store trusted keychains.
+    pub const TRUSTED_KEYCHAINS_TABLE_NAME: &'static str = "bdk_trusted_keychain";
+    /// Name of table to store the spending priority.
+    pub const SPENDING_PRIORITY_TABLE_NAME: &'static str = "bdk_spending_priority";
 
     /// Get v0 sqlite [ChangeSet] schema.
     pub fn schema_v0() -> alloc::string::String {
@@ -80,6 +152,96 @@ impl ChangeSet<DescriptorId> {
         )
     }
 
+    /// Get v1 sqlite [ChangeSet] schema. Adds keychain groups.
+    pub fn schema_v1() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                name TEXT NOT NULL, \
+                descriptor_id TEXT NOT NULL, \
+                PRIMARY KEY (name, descriptor_id) \
+            );",
+            Self::KEYCHAIN_GROUPS_TABLE_NAME,
+        )
+    }
+
+    /// Get v2 sqlite [ChangeSet] schema. Adds script pubkeys marked as used.
+    pub fn schema_v2() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                descriptor_id TEXT NOT NULL, \
+                derivation_index INTEGER NOT NULL, \
+                PRIMARY KEY (descriptor_id, derivation_index) \
+            );",
+            Self::MARKED_USED_TABLE_NAME,
+        )
+    }
+
+    /// Get v3 sqlite [ChangeSet] schema. Adds utxo locks.
+    pub fn schema_v3() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                txid TEXT NOT NULL, \
+                vout INTEGER NOT NULL, \
+                frozen BOOLEAN NOT NULL CHECK ( frozen IN (0,1) ), \
+                expiry INTEGER, \
+                PRIMARY KEY (txid, vout) \
+            );",
+            Self::UTXO_LOCKS_TABLE_NAME,
+        )
+    }
+
+    /// Get v4 sqlite [ChangeSet] schema. Adds change keychain pairings.
+    pub fn schema_v4() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                descriptor_id TEXT PRIMARY KEY NOT NULL, \
+                change_descriptor_id TEXT NOT NULL \
+            );",
+            Self::CHANGE_KEYCHAINS_TABLE_NAME,
+        )
+    }
+
+    /// Get v5 sqlite [ChangeSet] schema. Adds the payment queue.
+    pub fn schema_v5() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                id TEXT PRIMARY KEY NOT NULL, \
+                position INTEGER NOT NULL, \
+                script_pubkey BLOB NOT NULL, \
+                amount INTEGER NOT NULL, \
+                txid TEXT \
+            );",
+            Self::PAYMENTS_TABLE_NAME,
+        )
+    }
+
+    /// Get v6 sqlite [ChangeSet] schema. Adds trusted keychains.
+    pub fn schema_v6() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                descriptor_id TEXT PRIMARY KEY NOT NULL \
+            );",
+            Self::TRUSTED_KEYCHAINS_TABLE_NAME,
+        )
+    }
+
+    /// Get v7 sqlite [ChangeSet] schema. Adds the spending priority.
+    ///
+    /// Each row is a preference at a `position` in the order of the rule with maximum fee rate
+    /// `max_fee_rate`, in sat/kwu, or of the default order if `NULL`. A row with neither a
+    /// `descriptor_id` nor a `descriptor_type` records a rule with an empty order.
+    pub fn schema_v7() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                max_fee_rate INTEGER, \
+                position INTEGER NOT NULL, \
+                descriptor_id TEXT, \
+                descriptor_type TEXT \
+            );",
+            Self::SPENDING_PRIORITY_TABLE_NAME,
+        )
+    }
+
     /// Initializes tables and returns the aggregate data if the database is non-empty
     /// otherwise returns `Ok(None)`.
     pub fn initialize(db_tx: &rusqlite::Transaction) -> rusqlite::Result<Option<Self>> {
@@ -98,7 +260,16 @@ impl ChangeSet<DescriptorId> {
         bdk_chain::rusqlite_impl::migrate_schema(
             db_tx,
             Self::WALLET_SCHEMA_NAME,
-            &[&Self::schema_v0()],
+            &[
+                &Self::schema_v0(),
+                &Self::schema_v1(),
+                &Self::schema_v2(),
+                &Self::schema_v3(),
+                &Self::schema_v4(),
+                &Self::schema_v5(),
+                &Self::schema_v6(),
+                &Self::schema_v7(),
+            ],
         )?;
 
         local_chain::ChangeSet::init_sqlite_tables(db_tx)?;
@@ -110,7 +281,7 @@ impl ChangeSet<DescriptorId> {
 
     /// Construct self by reading all of the SQLite data. This should succeed
     /// even if attempting to read an empty database.
-    fn from_sqlite(db_tx: &rusqlite::Transaction) -> rusqlite::Result<Self> {
+    pub(crate) fn from_sqlite(db_tx: &rusqlite::Transaction) -> rusqlite::Result<Self> {
         use bdk_chain::Impl;
         use miniscript::{Descriptor, DescriptorPublicKey};
         use rusqlite::OptionalExtension;
@@ -150,6 +321,165 @@ impl ChangeSet<DescriptorId> {
             }
         }
 
+        // Read keychain groups
+        let mut group_stmt = db_tx.prepare(&format!(
+            "SELECT name, descriptor_id FROM {}",
+            Self::KEYCHAIN_GROUPS_TABLE_NAME
+        ))?;
+        let rows = group_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, alloc::string::String>("name")?,
+                row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
+            ))
+        })?;
+        for row in rows {
+            let (name, Impl(did)) = row?;
+            keyring.groups.entry(name).or_default().insert(did);
+        }
+
+        // Read change keychain pairings
+        let mut change_keychain_stmt = db_tx.prepare(&format!(
+            "SELECT descriptor_id, change_descriptor_id FROM {}",
+            Self::CHANGE_KEYCHAINS_TABLE_NAME
+        ))?;
+        let rows = change_keychain_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
+                row.get::<_, Impl<DescriptorId>>("change_descriptor_id")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(did), Impl(change_did)) = row?;
+            keyring.change_keychains.insert(did, change_did);
+        }
+
+        // Read script pubkeys marked as used
+        let mut marked_used_stmt = db_tx.prepare(&format!(
+            "SELECT descriptor_id, derivation_index FROM {}",
+            Self::MARKED_USED_TABLE_NAME
+        ))?;
+        let rows = marked_used_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
+                row.get::<_, u32>("derivation_index")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(did), index) = row?;
+            changeset
+                .marked_used
+                .entry(did)
+                .or_default()
+                .insert(index, true);
+        }
+
+        // Read utxo locks
+        let mut utxo_lock_stmt = db_tx.prepare(&format!(
+            "SELECT txid, vout, frozen, expiry FROM {}",
+            Self::UTXO_LOCKS_TABLE_NAME
+        ))?;
+        let rows = utxo_lock_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<bitcoin::Txid>>("txid")?,
+                row.get::<_, u32>("vout")?,
+                row.get::<_, bool>("frozen")?,
+                row.get::<_, Option<u32>>("expiry")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(txid), vout, frozen, expiry) = row?;
+            let lock = if frozen {
+                UtxoLock::Frozen
+            } else {
+                UtxoLock::Locked { expiry }
+            };
+            changeset
+                .utxo_locks
+                .insert(OutPoint::new(txid, vout), Some(lock));
+        }
+
+        // Read payment queue
+        let mut payment_stmt = db_tx.prepare(&format!(
+            "SELECT id, position, script_pubkey, amount, txid FROM {}",
+            Self::PAYMENTS_TABLE_NAME
+        ))?;
+        let rows = payment_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, alloc::string::String>("id")?,
+                row.get::<_, i64>("position")?,
+                row.get::<_, Impl<bitcoin::ScriptBuf>>("script_pubkey")?,
+                row.get::<_, Impl<bitcoin::Amount>>("amount")?,
+                row.get::<_, Option<Impl<bitcoin::Txid>>>("txid")?,
+            ))
+        })?;
+        for row in rows {
+            let (id, position, Impl(script_pubkey), Impl(amount), txid) = row?;
+            let payment = QueuedPayment {
+                position: position as u64,
+                script_pubkey,
+                amount,
+                txid: txid.map(|Impl(txid)| txid),
+            };
+            changeset.payments.insert(id, Some(payment));
+        }
+
+        // Read trusted keychains
+        let mut trusted_keychain_stmt = db_tx.prepare(&format!(
+            "SELECT descriptor_id FROM {}",
+            Self::TRUSTED_KEYCHAINS_TABLE_NAME
+        ))?;
+        let rows = trusted_keychain_stmt
+            .query_map([], |row| row.get::<_, Impl<DescriptorId>>("descriptor_id"))?;
+        for row in rows {
+            let Impl(did) = row?;
+            changeset.trusted_keychains.insert(did, true);
+        }
+
+        // Read spending priority
+        let mut spending_priority_stmt = db_tx.prepare(&format!(
+            "SELECT max_fee_rate, descriptor_id, descriptor_type FROM {} ORDER BY position",
+            Self::SPENDING_PRIORITY_TABLE_NAME
+        ))?;
+        let rows = spending_priority_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Option<u64>>("max_fee_rate")?,
+                row.get::<_, Option<Impl<DescriptorId>>>("descriptor_id")?,
+                row.get::<_, Option<alloc::string::String>>("descriptor_type")?,
+            ))
+        })?;
+        let mut orders = BTreeMap::<Option<u64>, alloc::vec::Vec<_>>::new();
+        for row in rows {
+            let (max_fee_rate, did, descriptor_type) = row?;
+            let order = orders.entry(max_fee_rate).or_default();
+            let preference = match (did, descriptor_type) {
+                (Some(Impl(did)), _) => Preference::Keychain(did),
+                (None, Some(name)) => {
+                    let descriptor_type = descriptor_type_from_name(&name).ok_or_else(|| {
+                        rusqlite::Error::InvalidColumnType(
+                            0,
+                            "descriptor_type".into(),
+                            rusqlite::types::Type::Text,
+                        )
+                    })?;
+                    Preference::DescriptorType(descriptor_type)
+                }
+                // An empty order.
+                (None, None) => continue,
+            };
+            order.push(preference);
+        }
+        if !orders.is_empty() {
+            let default = orders.remove(&None).unwrap_or_default();
+            let mut priority = SpendingPriority::new(default);
+            for (max_fee_rate, order) in orders {
+                let max_fee_rate = bitcoin::FeeRate::from_sat_per_kwu(
+                    max_fee_rate.expect("default order is removed"),
+                );
+                priority = priority.up_to_fee_rate(max_fee_rate, order);
+            }
+            changeset.spending_priority = Some(priority);
+        }
+
         changeset.keyring = keyring;
         changeset.local_chain = local_chain::ChangeSet::from_sqlite(db_tx)?;
         changeset.tx_graph = tx_graph::ChangeSet::from_sqlite(db_tx)?;
@@ -207,6 +537,176 @@ impl ChangeSet<DescriptorId> {
             add_default_stmt.execute(named_params! { ":descriptor_id": Impl(default_did),})?;
         }
 
+        // Write keychain groups, each one replacing the previous group of the same name
+        let mut remove_group_stmt = db_tx.prepare_cached(&format!(
+            "DELETE FROM {} WHERE name = :name",
+            Self::KEYCHAIN_GROUPS_TABLE_NAME,
+        ))?;
+        let mut add_group_member_stmt = db_tx.prepare_cached(&format!(
+            "INSERT INTO {}(name, descriptor_id) VALUES(:name, :descriptor_id)",
+            Self::KEYCHAIN_GROUPS_TABLE_NAME,
+        ))?;
+        for (name, keychains) in &keyring.groups {
+            remove_group_stmt.execute(named_params! { ":name": name })?;
+            for &did in keychains {
+                add_group_member_stmt.execute(named_params! {
+                    ":name": name,
+                    ":descriptor_id": Impl(did),
+                })?;
+            }
+        }
+
+        // Write change keychain pairings
+        let mut change_keychain_stmt = db_tx.prepare_cached(&format!(
+            "REPLACE INTO {}(descriptor_id, change_descriptor_id) VALUES(:descriptor_id, :change_descriptor_id)",
+            Self::CHANGE_KEYCHAINS_TABLE_NAME,
+        ))?;
+        for (&did, &change_did) in &keyring.change_keychains {
+            change_keychain_stmt.execute(named_params! {
+                ":descriptor_id": Impl(did),
+                ":change_descriptor_id": Impl(change_did),
+            })?;
+        }
+
+        // Write script pubkeys marked as used, removing the ones marked as unused
+        let mut mark_used_stmt = db_tx.prepare_cached(&format!(
+            "INSERT OR IGNORE INTO {}(descriptor_id, derivation_index) VALUES(:descriptor_id, :derivation_index)",
+            Self::MARKED_USED_TABLE_NAME,
+        ))?;
+        let mut unmark_used_stmt = db_tx.prepare_cached(&format!(
+            "DELETE FROM {} WHERE descriptor_id = :descriptor_id AND derivation_index = :derivation_index",
+            Self::MARKED_USED_TABLE_NAME,
+        ))?;
+        for (&did, marks) in &self.marked_used {
+            for (&index, &used) in marks {
+                let stmt = if used {
+                    &mut mark_used_stmt
+                } else {
+                    &mut unmark_used_stmt
+                };
+                stmt.execute(named_params! {
+                    ":descriptor_id": Impl(did),
+                    ":derivation_index": index,
+                })?;
+            }
+        }
+
+        // Write utxo locks, removing the ones unlocked
+        let mut lock_stmt = db_tx.prepare_cached(&format!(
+            "REPLACE INTO {}(txid, vout, frozen, expiry) VALUES(:txid, :vout, :frozen, :expiry)",
+            Self::UTXO_LOCKS_TABLE_NAME,
+        ))?;
+        let mut unlock_stmt = db_tx.prepare_cached(&format!(
+            "DELETE FROM {} WHERE txid = :txid AND vout = :vout",
+            Self::UTXO_LOCKS_TABLE_NAME,
+        ))?;
+        for (outpoint, lock) in &self.utxo_locks {
+            match lock {
+                Some(lock) => {
+                    let (frozen, expiry) = match *lock {
+                        UtxoLock::Locked { expiry } => (false, expiry),
+                        UtxoLock::Frozen => (true, None),
+                    };
+                    lock_stmt.execute(named_params! {
+                        ":txid": Impl(outpoint.txid),
+                        ":vout": outpoint.vout,
+                        ":frozen": frozen,
+                        ":expiry": expiry,
+                    })?;
+                }
+                None => {
+                    unlock_stmt.execute(named_params! {
+                        ":txid": Impl(outpoint.txid),
+                        ":vout": outpoint.vout,
+                    })?;
+                }
+            }
+        }
+
+        // Write payment queue, removing the payments removed
+        let mut payment_stmt = db_tx.prepare_cached(&format!(
+            "REPLACE INTO {}(id, position, script_pubkey, amount, txid) VALUES(:id, :position, :script_pubkey, :amount, :txid)",
+            Self::PAYMENTS_TABLE_NAME,
+        ))?;
+        let mut remove_payment_stmt = db_tx.prepare_cached(&format!(
+            "DELETE FROM {} WHERE id = :id",
+            Self::PAYMENTS_TABLE_NAME,
+        ))?;
+        for (id, payment) in &self.payments {
+            match payment {
+                Some(payment) => {
+                    payment_stmt.execute(named_params! {
+                        ":id": id,
+                        ":position": payment.position as i64,
+                        ":script_pubkey": Impl(payment.script_pubkey.clone()),
+                        ":amount": Impl(payment.amount),
+                        ":txid": payment.txid.map(Impl),
+                    })?;
+                }
+                None => {
+                    remove_payment_stmt.execute(named_params! { ":id": id })?;
+                }
+            }
+        }
+
+        // Write trusted keychains, removing the ones no longer trusted
+        let mut trust_stmt = db_tx.prepare_cached(&format!(
+            "INSERT OR IGNORE INTO {}(descriptor_id) VALUES(:descriptor_id)",
+            Self::TRUSTED_KEYCHAINS_TABLE_NAME,
+        ))?;
+        let mut distrust_stmt = db_tx.prepare_cached(&format!(
+            "DELETE FROM {} WHERE descriptor_id = :descriptor_id",
+            Self::TRUSTED_KEYCHAINS_TABLE_NAME,
+        ))?;
+        for (&did, &trusted) in &self.trusted_keychains {
+            let stmt = if trusted {
+                &mut trust_stmt
+            } else {
+                &mut distrust_stmt
+            };
+            stmt.execute(named_params! { ":descriptor_id": Impl(did) })?;
+        }
+
+        // Write spending priority, replacing the previous one
+        if let Some(priority) = &self.spending_priority {
+            db_tx.execute(
+                &format!("DELETE FROM {}", Self::SPENDING_PRIORITY_TABLE_NAME),
+                (),
+            )?;
+            let mut preference_stmt = db_tx.prepare_cached(&format!(
+                "INSERT INTO {}(max_fee_rate, position, descriptor_id, descriptor_type) VALUES(:max_fee_rate, :position, :descriptor_id, :descriptor_type)",
+                Self::SPENDING_PRIORITY_TABLE_NAME,
+            ))?;
+            let orders = core::iter::once((None, priority.order(None))).chain(
+                priority
+                    .rules()
+                    .map(|(max_fee_rate, order)| (Some(max_fee_rate.to_sat_per_kwu()), order)),
+            );
+            for (max_fee_rate, order) in orders {
+                if order.is_empty() {
+                    // Only the rule is recorded.
+                    preference_stmt.execute(named_params! {
+                        ":max_fee_rate": max_fee_rate,
+                        ":position": 0,
+                        ":descriptor_id": None::<Impl<DescriptorId>>,
+                        ":descriptor_type": None::<&str>,
+                    })?;
+                }
+                for (position, preference) in order.iter().enumerate() {
+                    let (did, descriptor_type) = match preference {
+                        Preference::Keychain(did) => (Some(Impl(*did)), None),
+                        Preference::DescriptorType(t) => (None, Some(descriptor_type_name(*t))),
+                    };
+                    preference_stmt.execute(named_params! {
+                        ":max_fee_rate": max_fee_rate,
+                        ":position": position,
+                        ":descriptor_id": did,
+                        ":descriptor_type": descriptor_type,
+                    })?;
+                }
+            }
+        }
+
         self.local_chain.persist_to_sqlite(db_tx)?;
         self.tx_graph.persist_to_sqlite(db_tx)?;
         self.indexer.persist_to_sqlite(db_tx)?;
@@ -0,0 +1,559 @@
+//! Coin selection algorithms used by the [`TxBuilder`](crate::multi_keychain::TxBuilder).
+//!
+//! An algorithm implements [`CoinSelectionAlgorithm`] and is set with
+//! [`TxBuilder::coin_selection`](crate::multi_keychain::TxBuilder::coin_selection). Every
+//! candidate input is weighted with the maximum weight needed to satisfy the descriptor of its
+//! keychain, so inputs of cheaper script types have a higher effective value.
+
+use alloc::{vec, vec::Vec};
+use core::{cmp::Reverse, fmt};
+
+use bitcoin::{Amount, Weight};
+
+use crate::multi_keychain::{FeePolicy, LocalOutput};
+
+/// An unspent output of the wallet along with the weight of the input spending it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightedUtxo<K> {
+    /// The unspent output.
+    pub utxo: LocalOutput<K>,
+    /// Weight of the input spending the output once satisfied, including the satisfaction
+    /// weight of the keychain's descriptor.
+    pub weight: Weight,
+}
+
+impl<K> WeightedUtxo<K> {
+    /// The value of the output minus the fee paid to spend it.
+    ///
+    /// This is negative if spending the output costs more than it is worth.
+    pub fn effective_value(&self, fee_policy: &FeePolicy) -> i64 {
+        self.utxo.txout.value.to_sat() as i64 - fee_policy.marginal_fee(self.weight).to_sat() as i64
+    }
+}
+
+/// What a [`CoinSelectionAlgorithm`] must fund.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionParams {
+    /// Total amount paid to the recipients.
+    pub target: Amount,
+    /// Weight of the transaction without its inputs nor change output.
+    pub base_weight: Weight,
+    /// How the fee is computed.
+    pub fee_policy: FeePolicy,
+    /// Weight of the change output.
+    pub change_weight: Weight,
+    /// Weight of an input spending the change output in the future.
+    pub change_spend_weight: Weight,
+    /// Smallest change value which is not dust.
+    pub min_change: Amount,
+    /// Smallest fee the transaction must pay, whatever the fee policy.
+    pub min_fee: Amount,
+}
+
+impl SelectionParams {
+    /// The fee of the transaction spending inputs of `input_weight` in total, with or without a
+    /// change output.
+    pub fn fee(&self, input_weight: Weight, with_change: bool) -> Amount {
+        let mut weight = self.base_weight + input_weight;
+        if with_change {
+            weight += self.change_weight;
+        }
+        self.fee_policy.fee(weight).max(self.min_fee)
+    }
+
+    /// The cost of creating a change output and spending it later.
+    pub fn cost_of_change(&self) -> Amount {
+        self.fee_policy.marginal_fee(self.change_weight)
+            + self.fee_policy.marginal_fee(self.change_spend_weight)
+    }
+
+    /// Whether inputs of `input_value` and `input_weight` in total fund the transaction, without
+    /// a change output.
+    pub fn is_funded(&self, input_value: Amount, input_weight: Weight) -> bool {
+        input_value >= self.target + self.fee(input_weight, false)
+    }
+
+    /// Compute the fee and change of a transaction spending `selected`.
+    ///
+    /// A change output is added if what is left once the recipients and fee are paid is not
+    /// dust, otherwise it goes to the fee.
+    pub fn finish<K>(
+        &self,
+        selected: Vec<WeightedUtxo<K>>,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+        let input_value = selected.iter().map(|w| w.utxo.txout.value).sum::<Amount>();
+        let input_weight = selected.iter().map(|w| w.weight).sum::<Weight>();
+        let fee_without_change = self.fee(input_weight, false);
+        if !self.is_funded(input_value, input_weight) {
+            return Err(InsufficientFunds {
+                needed: self.target + fee_without_change,
+                available: input_value,
+            });
+        }
+
+        let fee_with_change = self.fee(input_weight, true);
+        let change = input_value
+            .checked_sub(self.target + fee_with_change)
+            .filter(|change| *change >= self.min_change);
+        let fee = match change {
+            Some(_) => fee_with_change,
+            None => input_value - self.target,
+        };
+        Ok(CoinSelectionResult {
+            selected,
+            fee,
+            change,
+        })
+    }
+}
+
+/// The inputs chosen by a [`CoinSelectionAlgorithm`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelectionResult<K> {
+    /// Selected inputs, required ones first.
+    pub selected: Vec<WeightedUtxo<K>>,
+    /// Fee paid by the transaction.
+    pub fee: Amount,
+    /// Value of the change output, if any.
+    pub change: Option<Amount>,
+}
+
+/// Error returned when the candidate inputs can not fund a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsufficientFunds {
+    /// Amount needed, including the fee.
+    pub needed: Amount,
+    /// Amount available.
+    pub available: Amount,
+}
+
+impl fmt::Display for InsufficientFunds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "insufficient funds: {} available of {} needed",
+            self.available, self.needed
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InsufficientFunds {}
+
+/// Chooses the inputs of a transaction.
+pub trait CoinSelectionAlgorithm<K>: fmt::Debug {
+    /// Select inputs among `optional` to fund the transaction described by `params`, on top of
+    /// the `required` inputs which must all be spent.
+    fn coin_select(
+        &self,
+        required: Vec<WeightedUtxo<K>>,
+        optional: Vec<WeightedUtxo<K>>,
+        params: &SelectionParams,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds>;
+}
+
+/// Add the `optional` inputs in order until the transaction is funded.
+fn select_in_order<K>(
+    mut selected: Vec<WeightedUtxo<K>>,
+    optional: impl IntoIterator<Item = WeightedUtxo<K>>,
+    params: &SelectionParams,
+) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+    let mut input_value = selected.iter().map(|w| w.utxo.txout.value).sum::<Amount>();
+    let mut input_weight = selected.iter().map(|w| w.weight).sum::<Weight>();
+    for candidate in optional {
+        if params.is_funded(input_value, input_weight) {
+            break;
+        }
+        input_value += candidate.utxo.txout.value;
+        input_weight += candidate.weight;
+        selected.push(candidate);
+    }
+    params.finish(selected)
+}
+
+/// Spend the largest outputs first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LargestFirst;
+
+impl<K> CoinSelectionAlgorithm<K> for LargestFirst {
+    fn coin_select(
+        &self,
+        required: Vec<WeightedUtxo<K>>,
+        mut optional: Vec<WeightedUtxo<K>>,
+        params: &SelectionParams,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+        optional.sort_by_key(|w| Reverse(w.utxo.txout.value));
+        select_in_order(required, optional, params)
+    }
+}
+
+/// Spend the oldest outputs first, i.e. those confirmed at the lowest height. Unconfirmed outputs
+/// are spent last.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OldestFirst;
+
+impl<K> CoinSelectionAlgorithm<K> for OldestFirst {
+    fn coin_select(
+        &self,
+        required: Vec<WeightedUtxo<K>>,
+        mut optional: Vec<WeightedUtxo<K>>,
+        params: &SelectionParams,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+        optional.sort_by_key(|w| {
+            let height = w
+                .utxo
+                .chain_position
+                .confirmation_height_upper_bound()
+                .unwrap_or(u32::MAX);
+            (height, w.utxo.outpoint)
+        });
+        select_in_order(required, optional, params)
+    }
+}
+
+/// The splitmix64 pseudo-random number generator, seeded by its state.
+pub(crate) struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    /// The next pseudo-random number.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// The next pseudo-random number below `n`, which must not be zero.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Spend outputs picked at random until the transaction is funded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SingleRandomDraw {
+    seed: u64,
+}
+
+impl SingleRandomDraw {
+    /// Draw outputs in an order determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SingleRandomDraw {
+    /// Draw outputs in a random order.
+    fn default() -> Self {
+        Self::new(bitcoin::secp256k1::rand::random())
+    }
+}
+
+impl<K> CoinSelectionAlgorithm<K> for SingleRandomDraw {
+    fn coin_select(
+        &self,
+        required: Vec<WeightedUtxo<K>>,
+        mut optional: Vec<WeightedUtxo<K>>,
+        params: &SelectionParams,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+        // Fisher-Yates shuffle.
+        let mut rng = SplitMix64(self.seed);
+        for i in (1..optional.len()).rev() {
+            optional.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        select_in_order(required, optional, params)
+    }
+}
+
+/// Search for a set of outputs funding the transaction without a change output, falling back to
+/// another algorithm if there is none.
+///
+/// The outputs found are worth the recipients and fee plus at most the
+/// [cost of change](SelectionParams::cost_of_change), which is given up to the fee rather than
+/// creating a change output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchAndBound<F = LargestFirst> {
+    fallback: F,
+}
+
+impl<F> BranchAndBound<F> {
+    /// Maximum number of branches explored before falling back.
+    pub const MAX_TRIES: usize = 100_000;
+
+    /// Use `fallback` when no change-less solution is found.
+    pub fn new(fallback: F) -> Self {
+        Self { fallback }
+    }
+}
+
+impl<K, F> CoinSelectionAlgorithm<K> for BranchAndBound<F>
+where
+    F: CoinSelectionAlgorithm<K>,
+{
+    fn coin_select(
+        &self,
+        required: Vec<WeightedUtxo<K>>,
+        mut optional: Vec<WeightedUtxo<K>>,
+        params: &SelectionParams,
+    ) -> Result<CoinSelectionResult<K>, InsufficientFunds> {
+        let fee_policy = &params.fee_policy;
+        let required_value = required
+            .iter()
+            .map(|w| w.effective_value(fee_policy))
+            .sum::<i64>();
+        let target = (params.target + params.fee(Weight::ZERO, false)).to_sat() as i64;
+        let upper_bound = target + params.cost_of_change().to_sat() as i64;
+
+        optional.sort_by_key(|w| Reverse(w.effective_value(fee_policy)));
+        let values = optional
+            .iter()
+            .map(|w| w.effective_value(fee_policy))
+            .collect::<Vec<_>>();
+
+        match search(&values, required_value, target, upper_bound) {
+            Some(picked) => {
+                let mut selected = required;
+                selected.extend(
+                    optional
+                        .into_iter()
+                        .zip(picked)
+                        .filter_map(|(w, picked)| picked.then_some(w)),
+                );
+                params.finish(selected)
+            }
+            None => self.fallback.coin_select(required, optional, params),
+        }
+    }
+}
+
+/// Depth first search of the inclusion of `values` (sorted in descending order) such that
+/// `base + sum` lies in `target..=upper_bound`, minimizing the excess over `target`.
+fn search(values: &[i64], base: i64, target: i64, upper_bound: i64) -> Option<Vec<bool>> {
+    // The sum of the positive values from each index onwards.
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i].max(0);
+    }
+
+    let mut best: Option<(i64, Vec<bool>)> = None;
+    // Whether each value is picked, up to the depth of the last explored node.
+    let mut picked = vec![false; values.len()];
+    // The nodes left to explore, as the depth, the sum of the picked values and whether the
+    // value before this depth is picked. Including a value is explored first.
+    let mut stack = vec![(0, base, false)];
+    let mut tries = 0;
+    while let Some((i, sum, last_picked)) = stack.pop() {
+        if i > 0 {
+            picked[i - 1] = last_picked;
+        }
+        tries += 1;
+        if tries > BranchAndBound::<LargestFirst>::MAX_TRIES {
+            break;
+        }
+        if sum > upper_bound {
+            continue;
+        }
+        if sum >= target {
+            let excess = sum - target;
+            if best
+                .as_ref()
+                .map_or(true, |(best_excess, _)| excess < *best_excess)
+            {
+                let mut selection = picked[..i].to_vec();
+                selection.resize(values.len(), false);
+                best = Some((excess, selection));
+            }
+            // No selection has less excess than an exact match.
+            if excess == 0 {
+                break;
+            }
+            continue;
+        }
+        // Not enough left to reach the target, or only inputs not worth spending.
+        if i == values.len() || sum + remaining[i] < target || values[i] <= 0 {
+            continue;
+        }
+        stack.push((i + 1, sum, false));
+        stack.push((i + 1, sum + values[i], true));
+    }
+    best.map(|(_, picked)| picked)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{FeeRate, OutPoint};
+
+    use super::*;
+    use crate::bdk_chain::DescriptorId;
+    use crate::multi_keychain::test_utils::*;
+    use crate::multi_keychain::Wallet;
+
+    fn weighted_utxos(wallet: &Wallet<DescriptorId>) -> Vec<WeightedUtxo<DescriptorId>> {
+        wallet
+            .list_spendable()
+            .map(|utxo| WeightedUtxo {
+                weight: wallet.input_weight(&utxo.keychain).unwrap(),
+                utxo,
+            })
+            .collect()
+    }
+
+    fn params(target: u64) -> SelectionParams {
+        SelectionParams {
+            target: Amount::from_sat(target),
+            base_weight: Weight::from_wu(200),
+            fee_policy: FeePolicy::FeeRate(FeeRate::from_sat_per_vb(2).unwrap()),
+            change_weight: Weight::from_wu(124),
+            change_spend_weight: Weight::from_wu(272),
+            min_change: Amount::from_sat(294),
+            min_fee: Amount::ZERO,
+        }
+    }
+
+    fn outpoints(result: &CoinSelectionResult<DescriptorId>) -> Vec<OutPoint> {
+        result.selected.iter().map(|w| w.utxo.outpoint).collect()
+    }
+
+    /// A wallet with outputs of 10k, 20k and 50k sats confirmed in that order, and an unconfirmed
+    /// output of 70k sats on a trusted keychain.
+    fn wallet_with_utxos() -> (Wallet<DescriptorId>, [OutPoint; 4]) {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        wallet.set_keychain_trusted(k, true);
+        let ops = [
+            receive_confirmed(&mut wallet, k, Amount::from_sat(10_000)),
+            receive_confirmed(&mut wallet, k, Amount::from_sat(20_000)),
+            receive_confirmed(&mut wallet, k, Amount::from_sat(50_000)),
+            receive_unconfirmed(&mut wallet, k, Amount::from_sat(70_000), 1),
+        ];
+        (wallet, ops)
+    }
+
+    #[test]
+    fn largest_and_oldest_first() {
+        let (wallet, ops) = wallet_with_utxos();
+        let params = params(60_000);
+
+        let result = LargestFirst
+            .coin_select(Vec::new(), weighted_utxos(&wallet), &params)
+            .unwrap();
+        assert_eq!(outpoints(&result), [ops[3]]);
+        assert!(result.change.is_some());
+
+        let result = OldestFirst
+            .coin_select(Vec::new(), weighted_utxos(&wallet), &params)
+            .unwrap();
+        assert_eq!(outpoints(&result), [ops[0], ops[1], ops[2]]);
+
+        // Required inputs are always spent.
+        let (required, optional) = weighted_utxos(&wallet)
+            .into_iter()
+            .partition(|w| w.utxo.outpoint == ops[0]);
+        let result = LargestFirst
+            .coin_select(required, optional, &params)
+            .unwrap();
+        assert_eq!(outpoints(&result), [ops[0], ops[3]]);
+
+        let utxos = weighted_utxos(&wallet);
+        let input_weight = utxos.iter().map(|w| w.weight).sum();
+        let params = SelectionParams {
+            target: Amount::from_sat(150_000),
+            ..params
+        };
+        assert_eq!(
+            LargestFirst.coin_select(Vec::new(), utxos, &params),
+            Err(InsufficientFunds {
+                needed: Amount::from_sat(150_000) + params.fee(input_weight, false),
+                available: Amount::from_sat(150_000),
+            })
+        );
+    }
+
+    #[test]
+    fn single_random_draw_is_seeded() {
+        let (wallet, _) = wallet_with_utxos();
+        let params = params(25_000);
+        let select = |seed| {
+            SingleRandomDraw::new(seed)
+                .coin_select(Vec::new(), weighted_utxos(&wallet), &params)
+                .unwrap()
+        };
+        assert_eq!(select(7), select(7));
+        assert!((0..32)
+            .map(|seed| outpoints(&select(seed)))
+            .any(|ops| ops != outpoints(&select(7))));
+    }
+
+    #[test]
+    fn branch_and_bound_avoids_change() {
+        let (wallet, ops) = wallet_with_utxos();
+        let utxos = weighted_utxos(&wallet);
+        let fee_policy = FeePolicy::FeeRate(FeeRate::from_sat_per_vb(2).unwrap());
+
+        // What the 10k and 50k outputs are worth once the fee is paid, give or take the rounding of
+        // the fee of each input.
+        let mut params = params(0);
+        params.target = Amount::from_sat(60_000 - 2) - params.fee(utxos[0].weight * 2, false);
+        let result = BranchAndBound::<LargestFirst>::default()
+            .coin_select(Vec::new(), utxos.clone(), &params)
+            .unwrap();
+        let mut selected = outpoints(&result);
+        selected.sort();
+        let mut expected = vec![ops[0], ops[2]];
+        expected.sort();
+        assert_eq!(selected, expected);
+        assert_eq!(result.change, None);
+        assert!(utxos.iter().all(|w| w.effective_value(&fee_policy) > 0));
+
+        // No exact match, fall back to the largest outputs.
+        params.target = Amount::from_sat(145_000);
+        let result = BranchAndBound::new(LargestFirst)
+            .coin_select(Vec::new(), utxos, &params)
+            .unwrap();
+        assert_eq!(outpoints(&result), [ops[3], ops[2], ops[1], ops[0]]);
+    }
+
+    #[test]
+    fn search_many_values() {
+        // Deep enough to overflow the stack if the search recursed once per value.
+        let values = vec![1; 60_000];
+        let picked = search(&values, 0, 50_000, 50_000).unwrap();
+        assert_eq!(picked.iter().filter(|&&p| p).count(), 50_000);
+        assert!(picked[..50_000].iter().all(|&p| p));
+
+        // The first selection with the least excess is kept.
+        assert_eq!(search(&[5, 3, 2], 0, 4, 10), Some(vec![true, false, false]));
+        assert_eq!(search(&[5, 3, 2], 0, 5, 10), Some(vec![true, false, false]));
+        assert_eq!(search(&[3, 2], 0, 4, 4), None);
+    }
+
+    #[test]
+    fn satisfaction_weight() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], TR_DESCRIPTOR, PKH_DESCRIPTOR]);
+        for desc in [DESCRIPTORS[0], TR_DESCRIPTOR, PKH_DESCRIPTOR] {
+            receive_confirmed(&mut wallet, descriptor_id(desc), Amount::from_sat(10_000));
+        }
+        let fee_policy = FeePolicy::FeeRate(FeeRate::from_sat_per_vb(10).unwrap());
+        let value_of = |desc| {
+            weighted_utxos(&wallet)
+                .into_iter()
+                .find(|w| w.utxo.keychain == descriptor_id(desc))
+                .unwrap()
+                .effective_value(&fee_policy)
+        };
+        let (tr, wpkh, pkh) = (
+            value_of(TR_DESCRIPTOR),
+            value_of(DESCRIPTORS[0]),
+            value_of(PKH_DESCRIPTOR),
+        );
+        assert!(tr > wpkh && wpkh > pkh, "{tr} {wpkh} {pkh}");
+
+        // An absolute fee does not depend on the inputs.
+        assert_eq!(
+            weighted_utxos(&wallet)[0].effective_value(&FeePolicy::FeeAmount(Amount::ONE_SAT)),
+            10_000
+        );
+    }
+}
@@ -0,0 +1,513 @@
+//! Child-pays-for-parent transactions of the [`Wallet`], see [`Wallet::build_cpfp`].
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use bitcoin::{
+    transaction, Amount, FeeRate, Psbt, Sequence, Transaction, TxIn, TxOut, Txid, Weight,
+};
+use miniscript::plan::Plan;
+
+use crate::multi_keychain::{
+    fee_for_weight, plans_lock_time, update_psbt_plans, AntiFeeSniping, CreateTxError, FeePolicy,
+    LocalOutput, TxStatus, Wallet, SEGWIT_MARKER_WEIGHT, TXIN_BASE_WEIGHT,
+};
+
+/// Builds a child transaction spending the outputs of the [`Wallet`] created by an unconfirmed
+/// parent, so that the package of the child, the parent and its unconfirmed ancestors pays a
+/// target fee rate, see
+/// [`Wallet::build_cpfp`].
+///
+/// The child spends every spendable and unlocked output of the wallet in the parent and sends them,
+/// minus the fee, to a single change output. The outputs of keychains with assets, see
+/// [`Wallet::set_keychain_assets`], are spent through their cheapest spending path available with
+/// these assets, like with a [`TxBuilder`](crate::multi_keychain::TxBuilder).
+#[derive(Debug)]
+#[must_use]
+pub struct CpfpBuilder<'a, K: Ord> {
+    wallet: &'a mut Wallet<K>,
+    /// Total weight of the parent and its unconfirmed ancestors.
+    ancestors_weight: Weight,
+    /// Total fee of the parent and its unconfirmed ancestors, or the first of them whose fee is
+    /// unknown.
+    ancestors_fee: Result<Amount, Txid>,
+    /// Fee of the parent and its unconfirmed ancestors given by the caller.
+    parent_fee: Option<Amount>,
+    utxos: Vec<LocalOutput<K>>,
+    /// Spending path of each of the `utxos` whose keychain has assets.
+    plans: Vec<Option<Plan>>,
+    fee_rate: FeeRate,
+    change_keychain: Option<K>,
+    anti_fee_sniping: AntiFeeSniping,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Start building a child-pays-for-parent transaction for the unconfirmed transaction
+    /// `parent`.
+    ///
+    /// The unconfirmed ancestors of the parent are mined along with it, so the package the fee
+    /// rate applies to includes them. Their fees and the fee of the parent must be known, i.e.
+    /// the graph must contain the outputs they spend, including those of other parties, or else
+    /// be given with [`CpfpBuilder::parent_fee`].
+    ///
+    /// Outputs that can not be spent in the next block are left out, such as those whose
+    /// spending path has a relative timelock, which is never reached while the parent is
+    /// unconfirmed.
+    pub fn build_cpfp(&mut self, parent: Txid) -> Result<CpfpBuilder<'_, K>, BuildCpfpError<K>> {
+        let details = self
+            .get_tx(parent)
+            .ok_or(BuildCpfpError::TransactionNotFound(parent))?;
+        match details.status {
+            TxStatus::Unconfirmed { .. } => {}
+            TxStatus::Confirmed { .. } => return Err(BuildCpfpError::TransactionConfirmed(parent)),
+            _ => return Err(BuildCpfpError::TransactionNotFound(parent)),
+        }
+        let mut ancestors_weight = details.tx.weight();
+        let mut ancestors_fee = details.fee.ok_or(parent);
+        let graph = self.tx_graph().graph();
+        // Confirmed ancestors end the walk, their own ancestors being confirmed too.
+        let ancestors = graph.walk_ancestors(details.tx.clone(), |_, tx| {
+            let txid = tx.compute_txid();
+            match self.get_tx(txid)?.status {
+                TxStatus::Unconfirmed { .. } => Some((txid, tx.weight(), graph.calculate_fee(&tx))),
+                _ => None,
+            }
+        });
+        for (txid, weight, fee) in ancestors {
+            ancestors_weight += weight;
+            ancestors_fee = match (ancestors_fee, fee) {
+                (Ok(total), Ok(fee)) => Ok(total + fee),
+                (Ok(_), Err(_)) => Err(txid),
+                (Err(unknown), _) => Err(unknown),
+            };
+        }
+
+        let mut utxos = Vec::new();
+        let mut plans = Vec::new();
+        let spendable = self.list_unspent().filter(|utxo| {
+            utxo.outpoint.txid == parent && utxo.is_spendable && utxo.lock.is_none()
+        });
+        for utxo in spendable {
+            let plan = match self.keychain_assets(&utxo.keychain) {
+                Some(assets) => match self.plan(&utxo, assets) {
+                    Some(plan) => Some(plan),
+                    None => continue,
+                },
+                None => None,
+            };
+            utxos.push(utxo);
+            plans.push(plan);
+        }
+        if utxos.is_empty() {
+            return Err(BuildCpfpError::NoOutputToSpend(parent));
+        }
+
+        Ok(CpfpBuilder {
+            wallet: self,
+            ancestors_weight,
+            ancestors_fee,
+            parent_fee: None,
+            utxos,
+            plans,
+            fee_rate: FeeRate::BROADCAST_MIN,
+            change_keychain: None,
+            anti_fee_sniping: AntiFeeSniping::default(),
+        })
+    }
+}
+
+impl<K> CpfpBuilder<'_, K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// The fee rate the package of the child, the parent and its unconfirmed ancestors must
+    /// reach. The child pays at least this fee rate for itself in any case.
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    /// Set the total fee paid by the parent and its unconfirmed ancestors in the wallet, when the
+    /// wallet can not compute it, e.g. for an incoming payment whose inputs belong to the sender.
+    pub fn parent_fee(mut self, fee: Amount) -> Self {
+        self.parent_fee = Some(fee);
+        self
+    }
+
+    /// Send the output of the child to `keychain`. By default it goes to the change keychain of
+    /// the keychain of the first output spent, see [`Wallet::change_keychain`].
+    pub fn change_keychain(mut self, keychain: K) -> Self {
+        self.change_keychain = Some(keychain);
+        self
+    }
+
+    /// Discourage fee sniping with `anti_fee_sniping`, see
+    /// [`TxBuilder::anti_fee_sniping`](crate::multi_keychain::TxBuilder::anti_fee_sniping).
+    pub fn anti_fee_sniping(mut self, anti_fee_sniping: AntiFeeSniping) -> Self {
+        self.anti_fee_sniping = anti_fee_sniping;
+        self
+    }
+
+    /// Build the unsigned child transaction.
+    ///
+    /// The address of its output is revealed and marked as used on the change keychain, which
+    /// is staged in the wallet.
+    pub fn finish(self) -> Result<Psbt, BuildCpfpError<K>> {
+        let Self {
+            wallet,
+            ancestors_weight,
+            ancestors_fee,
+            parent_fee,
+            utxos,
+            plans,
+            fee_rate,
+            change_keychain,
+            anti_fee_sniping,
+        } = self;
+        FeePolicy::FeeRate(fee_rate).check()?;
+        let ancestors_fee = match parent_fee {
+            Some(fee) => fee,
+            None => ancestors_fee.map_err(BuildCpfpError::UnknownParentFee)?,
+        };
+
+        let change_keychain =
+            change_keychain.unwrap_or_else(|| wallet.change_keychain(&utxos[0].keychain));
+        let (change_index, change_spk) = wallet
+            .peek_next_change(&change_keychain)
+            .ok_or_else(|| CreateTxError::UnknownKeychain(change_keychain.clone()))?;
+
+        let mut input_weight = Weight::ZERO;
+        for (utxo, plan) in utxos.iter().zip(&plans) {
+            input_weight += match plan {
+                Some(plan) => TXIN_BASE_WEIGHT + Weight::from_wu(plan.satisfaction_weight() as u64),
+                None => wallet
+                    .input_weight(&utxo.keychain)
+                    .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(utxo.keychain.clone()))?,
+            };
+        }
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: plans_lock_time(&plans)?,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: change_spk,
+            }],
+        };
+        let child_weight = tx.weight() + SEGWIT_MARKER_WEIGHT + input_weight;
+        tx.input = utxos
+            .iter()
+            .zip(&plans)
+            .map(|(utxo, plan)| TxIn {
+                previous_output: utxo.outpoint,
+                sequence: plan
+                    .as_ref()
+                    .and_then(|plan| plan.relative_timelock)
+                    .map_or(Sequence::ENABLE_RBF_NO_LOCKTIME, |older| {
+                        older.to_sequence()
+                    }),
+                ..Default::default()
+            })
+            .collect();
+
+        let package_fee = fee_for_weight(fee_rate, ancestors_weight + child_weight);
+        let fee = package_fee
+            .checked_sub(ancestors_fee)
+            .unwrap_or(Amount::ZERO)
+            .max(fee_for_weight(fee_rate, child_weight));
+        let available = utxos.iter().map(|utxo| utxo.txout.value).sum::<Amount>();
+        let needed = fee + tx.output[0].script_pubkey.minimal_non_dust();
+        if available < needed {
+            return Err(CreateTxError::InsufficientFunds { needed, available }.into());
+        }
+        tx.output[0].value = available - fee;
+        let inputs = utxos
+            .iter()
+            .map(|utxo| wallet.anti_fee_sniping_input(utxo))
+            .collect::<Vec<_>>();
+        wallet.discourage_fee_sniping(&mut tx, &inputs, anti_fee_sniping);
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect("transaction is unsigned");
+        wallet.reveal_change(change_keychain, change_index);
+        wallet.update_psbt(&mut psbt, &utxos);
+        update_psbt_plans(&mut psbt, &plans);
+
+        Ok(psbt)
+    }
+}
+
+/// Error returned by [`Wallet::build_cpfp`] and [`CpfpBuilder::finish`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildCpfpError<K> {
+    /// The parent is not in the wallet or not canonical.
+    TransactionNotFound(Txid),
+    /// The parent is already confirmed.
+    TransactionConfirmed(Txid),
+    /// The fee of the parent, or of the given unconfirmed ancestor, is unknown because some of
+    /// the outputs it spends are missing, see [`CpfpBuilder::parent_fee`].
+    UnknownParentFee(Txid),
+    /// The parent has no unspent, spendable and unlocked output of the wallet.
+    NoOutputToSpend(Txid),
+    /// Building the child failed.
+    CreateTx(CreateTxError<K>),
+}
+
+impl<K> From<CreateTxError<K>> for BuildCpfpError<K> {
+    fn from(e: CreateTxError<K>) -> Self {
+        Self::CreateTx(e)
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for BuildCpfpError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TransactionNotFound(txid) => write!(f, "transaction {txid} not found"),
+            Self::TransactionConfirmed(txid) => write!(f, "transaction {txid} is confirmed"),
+            Self::UnknownParentFee(txid) => write!(f, "the fee of transaction {txid} is unknown"),
+            Self::NoOutputToSpend(txid) => {
+                write!(f, "transaction {txid} has no output of the wallet to spend")
+            }
+            Self::CreateTx(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug> std::error::Error for BuildCpfpError<K> {}
+
+#[cfg(test)]
+mod test {
+    use core::str::FromStr;
+
+    use bitcoin::{hashes::Hash, relative, OutPoint, ScriptBuf, WPubkeyHash};
+    use miniscript::{plan::Assets, DescriptorPublicKey};
+
+    use super::*;
+    use crate::bdk_chain::{collections::BTreeMap, DescriptorId, TxUpdate};
+    use crate::multi_keychain::test_utils::*;
+    use crate::multi_keychain::Update;
+
+    #[test]
+    fn child_pays_for_parent() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], DESCRIPTORS[1]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let k_child = descriptor_id(DESCRIPTORS[1]);
+        let parent = receive_tx(&mut wallet, k, Amount::from_sat(50_000));
+        let parent_txid = parent.compute_txid();
+        wallet.apply_unconfirmed_txs([(parent.clone(), 1)]);
+
+        // The output spent by the parent belongs to someone else.
+        assert_eq!(
+            wallet
+                .build_cpfp(parent_txid)
+                .unwrap()
+                .finish()
+                .unwrap_err(),
+            BuildCpfpError::UnknownParentFee(parent_txid)
+        );
+        let fee_rate = FeeRate::from_sat_per_vb_u32(10);
+        let given_fee = wallet
+            .build_cpfp(parent_txid)
+            .unwrap()
+            .parent_fee(Amount::from_sat(200))
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap()
+            .fee()
+            .unwrap();
+        let mut tx_update = TxUpdate::default();
+        tx_update.txouts.insert(
+            parent.input[0].previous_output,
+            TxOut {
+                value: Amount::from_sat(50_200),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+            },
+        );
+        wallet.apply_update(Update {
+            chain: None,
+            tx_update,
+            last_active_indices: BTreeMap::new(),
+        });
+
+        let psbt = wallet
+            .build_cpfp(parent_txid)
+            .unwrap()
+            .fee_rate(fee_rate)
+            .change_keychain(k_child)
+            .finish()
+            .unwrap();
+        let child = &psbt.unsigned_tx;
+        assert_eq!(child.input.len(), 1);
+        assert_eq!(
+            child.input[0].previous_output,
+            OutPoint::new(parent_txid, 0)
+        );
+        assert_eq!(child.output.len(), 1);
+        assert_eq!(
+            wallet
+                .txout_index()
+                .index_of_spk(child.output[0].script_pubkey.clone()),
+            Some(&(k_child, 0))
+        );
+
+        // The package pays the target fee rate, the child making up for the 200 sat paid by the
+        // parent.
+        let child_weight = estimated_weight(&wallet, child);
+        assert_eq!(
+            psbt.fee().unwrap(),
+            fee_for_weight(fee_rate, parent.weight() + child_weight) - Amount::from_sat(200)
+        );
+        assert!(psbt.fee().unwrap() > fee_for_weight(fee_rate, child_weight));
+        assert_eq!(psbt.fee().unwrap(), given_fee);
+    }
+
+    #[test]
+    fn child_uses_spending_path() {
+        let xpub = "tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7";
+        let primary = format!("{xpub}/8/*");
+        let recovery = format!("{xpub}/9/*");
+        let descriptor = format!("wsh(or_d(pk({primary}),and_v(v:pk({recovery}),older(6))))");
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], &descriptor]);
+        let k = descriptor_id(&descriptor);
+        let parent = receive_tx(&mut wallet, k, Amount::from_sat(50_000));
+        let parent_txid = parent.compute_txid();
+        wallet.apply_unconfirmed_txs([(parent.clone(), 1)]);
+
+        // The relative timelock can not be reached while the parent is unconfirmed.
+        let recovery_key = DescriptorPublicKey::from_str(&recovery).unwrap();
+        wallet.set_keychain_assets(
+            k,
+            Some(
+                Assets::new()
+                    .add(recovery_key)
+                    .older(relative::LockTime::from_height(6)),
+            ),
+        );
+        assert_eq!(
+            wallet.build_cpfp(parent_txid).unwrap_err(),
+            BuildCpfpError::NoOutputToSpend(parent_txid)
+        );
+
+        let primary_key = DescriptorPublicKey::from_str(&primary).unwrap();
+        wallet.set_keychain_assets(k, Some(Assets::new().add(primary_key)));
+        let utxo = wallet.list_unspent().next().unwrap();
+        let plan = wallet
+            .plan(&utxo, wallet.keychain_assets(&k).unwrap())
+            .unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb_u32(10);
+        let psbt = wallet
+            .build_cpfp(parent_txid)
+            .unwrap()
+            .parent_fee(Amount::from_sat(200))
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        let child = &psbt.unsigned_tx;
+        assert_eq!(child.input[0].sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+        // Only the key of the spending path signs.
+        assert_eq!(psbt.inputs[0].bip32_derivation.len(), 1);
+        let outputs = Transaction {
+            input: Vec::new(),
+            ..child.clone()
+        };
+        let child_weight = outputs.weight()
+            + SEGWIT_MARKER_WEIGHT
+            + TXIN_BASE_WEIGHT
+            + Weight::from_wu(plan.satisfaction_weight() as u64);
+        assert_eq!(
+            psbt.fee().unwrap(),
+            fee_for_weight(fee_rate, parent.weight() + child_weight) - Amount::from_sat(200)
+        );
+    }
+
+    #[test]
+    fn child_pays_for_ancestors() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        let grandparent = wallet
+            .build_tx()
+            .add_recipient(
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                Amount::from_sat(10_000),
+            )
+            .fee_absolute(Amount::from_sat(150))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        wallet.apply_unconfirmed_txs([(grandparent.clone(), 1)]);
+        let parent = wallet
+            .build_cpfp(grandparent.compute_txid())
+            .unwrap()
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let parent_txid = parent.compute_txid();
+        wallet.apply_unconfirmed_txs([(parent.clone(), 2)]);
+        let graph = wallet.tx_graph().graph();
+        let ancestors_fee =
+            graph.calculate_fee(&grandparent).unwrap() + graph.calculate_fee(&parent).unwrap();
+
+        let fee_rate = FeeRate::from_sat_per_vb_u32(10);
+        let psbt = wallet
+            .build_cpfp(parent_txid)
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        let child = &psbt.unsigned_tx;
+        let package_weight =
+            grandparent.weight() + parent.weight() + estimated_weight(&wallet, child);
+        assert_eq!(
+            psbt.fee().unwrap(),
+            fee_for_weight(fee_rate, package_weight) - ancestors_fee
+        );
+    }
+
+    /// The weight of the child once its inputs spending outputs of the default keychain are
+    /// satisfied, as estimated when building it.
+    fn estimated_weight(wallet: &Wallet<DescriptorId>, child: &Transaction) -> Weight {
+        let input_weight = wallet.input_weight(&wallet.default_keychain()).unwrap();
+        let outputs = Transaction {
+            input: Vec::new(),
+            ..child.clone()
+        };
+        outputs.weight() + SEGWIT_MARKER_WEIGHT + input_weight * child.input.len() as u64
+    }
+
+    #[test]
+    fn invalid_cpfp() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let confirmed = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        assert_eq!(
+            wallet.build_cpfp(confirmed.txid).unwrap_err(),
+            BuildCpfpError::TransactionConfirmed(confirmed.txid)
+        );
+        assert_eq!(
+            wallet.build_cpfp(Txid::all_zeros()).unwrap_err(),
+            BuildCpfpError::TransactionNotFound(Txid::all_zeros())
+        );
+
+        // A parent with no output of the wallet.
+        let tx = wallet
+            .build_tx()
+            .add_recipient(
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                Amount::from_sat(40_000),
+            )
+            .fee_absolute(Amount::from_sat(10_000))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx, 1)]);
+        assert_eq!(
+            wallet.build_cpfp(txid).unwrap_err(),
+            BuildCpfpError::NoOutputToSpend(txid)
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Draining keychains of the [`Wallet`] into another keychain, see [`Wallet::drain_keychains`].
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use bdk_chain::collections::BTreeSet;
+use bitcoin::{
+    absolute, transaction, Amount, FeeRate, Psbt, Sequence, Transaction, TxIn, TxOut, Weight,
+};
+
+use crate::bdk_chain;
+use crate::multi_keychain::{
+    fee_for_weight, AntiFeeSniping, CreateTxError, FeePolicy, LocalOutput, Wallet, WeightedUtxo,
+    SEGWIT_MARKER_WEIGHT,
+};
+
+/// Maximum weight of a standard transaction.
+pub const MAX_STANDARD_TX_WEIGHT: Weight = Weight::from_wu(400_000);
+
+/// A transaction moving funds to one of the keychains of the [`Wallet`], see
+/// [`Wallet::drain_keychains`] and [`Wallet::sweep`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrainTx {
+    /// The transaction, unsigned when built by [`Wallet::drain_keychains`], signed and finalized
+    /// when built by [`Wallet::sweep`].
+    pub psbt: Psbt,
+    /// Amount received by the destination keychain.
+    pub amount: Amount,
+    /// Fee paid by the transaction.
+    pub fee: Amount,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Move every spendable output of the `keychains` to newly revealed addresses of the
+    /// `destination` keychain, paying `fee_rate`. The `destination` can not be one of the
+    /// `keychains`.
+    ///
+    /// The outputs are spent by as few transactions as possible without exceeding
+    /// [`MAX_STANDARD_TX_WEIGHT`], each paying to its own address. Outputs worth less than the fee
+    /// to spend them are left out. Returns no transaction if there is nothing to drain.
+    ///
+    /// Discourage fee sniping with `anti_fee_sniping`, see
+    /// [`TxBuilder::anti_fee_sniping`](crate::multi_keychain::TxBuilder::anti_fee_sniping).
+    pub fn drain_keychains(
+        &mut self,
+        keychains: impl IntoIterator<Item = K>,
+        destination: K,
+        fee_rate: FeeRate,
+        anti_fee_sniping: AntiFeeSniping,
+    ) -> Result<Vec<DrainTx>, CreateTxError<K>> {
+        self.drain_keychains_in_batches(
+            keychains,
+            destination,
+            fee_rate,
+            anti_fee_sniping,
+            MAX_STANDARD_TX_WEIGHT,
+        )
+    }
+
+    /// [`Wallet::drain_keychains`] with transactions of at most `max_weight`.
+    pub(crate) fn drain_keychains_in_batches(
+        &mut self,
+        keychains: impl IntoIterator<Item = K>,
+        destination: K,
+        fee_rate: FeeRate,
+        anti_fee_sniping: AntiFeeSniping,
+        max_weight: Weight,
+    ) -> Result<Vec<DrainTx>, CreateTxError<K>> {
+        let keychains = keychains.into_iter().collect::<BTreeSet<_>>();
+        if keychains.contains(&destination) {
+            return Err(CreateTxError::InvalidDestination(destination));
+        }
+        let fee_policy = FeePolicy::FeeRate(fee_rate);
+        fee_policy.check()?;
+        let mut utxos = Vec::new();
+        for keychain in &keychains {
+            if self
+                .txout_index()
+                .get_descriptor(keychain.clone())
+                .is_none()
+            {
+                return Err(CreateTxError::UnknownKeychain(keychain.clone()));
+            }
+            let weight = self
+                .input_weight(keychain)
+                .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(keychain.clone()))?;
+            utxos.extend(
+                self.list_spendable()
+                    .filter(|utxo| &utxo.keychain == keychain)
+                    .map(|utxo| WeightedUtxo { utxo, weight })
+                    .filter(|w| w.effective_value(&fee_policy) > 0),
+            );
+        }
+        let (_, script_pubkey) = self
+            .peek_next_change(&destination)
+            .ok_or_else(|| CreateTxError::UnknownKeychain(destination.clone()))?;
+
+        let template = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey,
+            }],
+        };
+        let base_weight = template.weight() + SEGWIT_MARKER_WEIGHT;
+
+        // Split the outputs in batches, then check every batch can pay its fee before revealing
+        // any address.
+        let mut batches = Vec::<(Vec<LocalOutput<K>>, Weight)>::new();
+        for WeightedUtxo { utxo, weight } in utxos {
+            match batches.last_mut() {
+                Some((batch, batch_weight)) if *batch_weight + weight <= max_weight => {
+                    batch.push(utxo);
+                    *batch_weight += weight;
+                }
+                _ => batches.push((vec![utxo], base_weight + weight)),
+            }
+        }
+        let mut drained = Vec::new();
+        for (batch, weight) in batches {
+            let available = batch.iter().map(|utxo| utxo.txout.value).sum::<Amount>();
+            let fee = fee_for_weight(fee_rate, weight);
+            let needed = fee + template.output[0].script_pubkey.minimal_non_dust();
+            if available < needed {
+                return Err(CreateTxError::InsufficientFunds { needed, available });
+            }
+            drained.push((batch, available - fee, fee));
+        }
+
+        let mut txs = Vec::new();
+        for (i, (batch, amount, fee)) in drained.into_iter().enumerate() {
+            let address = self
+                .reveal_next_address(destination.clone())
+                .expect("keychain must exist");
+            let mut tx = Transaction {
+                input: batch
+                    .iter()
+                    .map(|utxo| TxIn {
+                        previous_output: utxo.outpoint,
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                        ..Default::default()
+                    })
+                    .collect(),
+                output: vec![TxOut {
+                    value: amount,
+                    script_pubkey: address.script_pubkey(),
+                }],
+                ..template.clone()
+            };
+            let inputs = batch
+                .iter()
+                .map(|utxo| self.anti_fee_sniping_input(utxo))
+                .collect::<Vec<_>>();
+            // Draw independent choices for each transaction of the drain.
+            let anti_fee_sniping = match anti_fee_sniping {
+                AntiFeeSniping::Randomized { seed } => AntiFeeSniping::Randomized {
+                    seed: seed.wrapping_add(i as u64),
+                },
+                policy => policy,
+            };
+            self.discourage_fee_sniping(&mut tx, &inputs, anti_fee_sniping);
+            let mut psbt = Psbt::from_unsigned_tx(tx).expect("transaction is unsigned");
+            self.update_psbt(&mut psbt, &batch);
+            txs.push(DrainTx { psbt, amount, fee });
+        }
+        Ok(txs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    #[test]
+    fn drain_into_keychain() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], PKH_DESCRIPTOR, TR_DESCRIPTOR]);
+        let k_wpkh = descriptor_id(DESCRIPTORS[0]);
+        let k_pkh = descriptor_id(PKH_DESCRIPTOR);
+        let k_tr = descriptor_id(TR_DESCRIPTOR);
+        let mut drained = BTreeSet::new();
+        for value in [20_000, 30_000] {
+            drained.insert(receive_confirmed(
+                &mut wallet,
+                k_pkh,
+                Amount::from_sat(value),
+            ));
+        }
+        drained.insert(receive_confirmed(
+            &mut wallet,
+            k_wpkh,
+            Amount::from_sat(40_000),
+        ));
+        // Not worth spending at this fee rate.
+        receive_confirmed(&mut wallet, k_pkh, Amount::from_sat(500));
+        receive_confirmed(&mut wallet, k_tr, Amount::from_sat(50_000));
+        let fee_rate = FeeRate::from_sat_per_vb_u32(5);
+
+        let txs = wallet
+            .drain_keychains([k_pkh, k_wpkh], k_tr, fee_rate, AntiFeeSniping::LockTime)
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        let DrainTx { psbt, amount, fee } = &txs[0];
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<BTreeSet<_>>(),
+            drained
+        );
+        assert_eq!(*amount + *fee, Amount::from_sat(90_000));
+        assert_eq!(psbt.fee().unwrap(), *fee);
+        assert_eq!(
+            tx.lock_time,
+            absolute::LockTime::from_height(wallet.latest_checkpoint().height()).unwrap()
+        );
+        assert!(*fee >= fee_for_weight(fee_rate, tx.weight()));
+        assert_eq!(
+            wallet
+                .txout_index()
+                .index_of_spk(tx.output[0].script_pubkey.clone()),
+            Some(&(k_tr, 1))
+        );
+
+        assert_eq!(
+            wallet
+                .drain_keychains([k_wpkh], k_pkh, fee_rate, AntiFeeSniping::default())
+                .unwrap()[0]
+                .psbt
+                .unsigned_tx
+                .input
+                .len(),
+            1
+        );
+        let unknown = descriptor_id(DESCRIPTORS[5]);
+        assert_eq!(
+            wallet.drain_keychains([unknown], k_tr, fee_rate, AntiFeeSniping::default()),
+            Err(CreateTxError::UnknownKeychain(unknown))
+        );
+        // Draining a keychain into itself would only pay fees.
+        assert_eq!(
+            wallet.drain_keychains([k_wpkh, k_tr], k_tr, fee_rate, AntiFeeSniping::default()),
+            Err(CreateTxError::InvalidDestination(k_tr))
+        );
+    }
+
+    #[test]
+    fn drain_in_batches() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], DESCRIPTORS[1]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let k_new = descriptor_id(DESCRIPTORS[1]);
+        for _ in 0..5 {
+            receive_confirmed(&mut wallet, k, Amount::from_sat(10_000));
+        }
+        let input_weight = wallet.input_weight(&k).unwrap();
+        let max_weight = Weight::from_wu(200) + input_weight * 2;
+
+        let txs = wallet
+            .drain_keychains_in_batches(
+                [k],
+                k_new,
+                FeeRate::BROADCAST_MIN,
+                AntiFeeSniping::default(),
+                max_weight,
+            )
+            .unwrap();
+        assert_eq!(
+            txs.iter()
+                .map(|drain| drain.psbt.unsigned_tx.input.len())
+                .collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+        let spks = txs
+            .iter()
+            .map(|drain| drain.psbt.unsigned_tx.output[0].script_pubkey.clone())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(spks.len(), 3);
+        assert_eq!(wallet.txout_index().last_revealed_index(k_new), Some(2));
+        let total = txs
+            .iter()
+            .map(|drain| drain.amount + drain.fee)
+            .sum::<Amount>();
+        assert_eq!(total, Amount::from_sat(50_000));
+    }
+}
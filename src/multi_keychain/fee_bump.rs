@@ -0,0 +1,776 @@
+//! Replace-by-fee of the transactions of the [`Wallet`], see [`Wallet::build_fee_bump`].
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+
+use bdk_chain::collections::{BTreeMap, BTreeSet};
+use bitcoin::{Amount, FeeRate, OutPoint, Psbt, Sequence, Transaction, TxIn, TxOut, Txid, Weight};
+
+use crate::bdk_chain;
+use crate::multi_keychain::{
+    fee_for_weight, select_by_tier, BranchAndBound, CoinSelectionAlgorithm, CoinSelectionResult,
+    CreateTxError, FeePolicy, LocalOutput, SelectionParams, TxStatus, Wallet, WeightedUtxo,
+    SEGWIT_MARKER_WEIGHT,
+};
+
+/// Fee rate by which a replacement must increase the fee paid for its own size (BIP125 rule 4),
+/// the default `-incrementalrelayfee` of Bitcoin Core.
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kwu(250);
+
+/// Builds a transaction replacing an unconfirmed transaction of the [`Wallet`] with a higher fee,
+/// see [`Wallet::build_fee_bump`] and [`Wallet::build_cancel_tx`].
+///
+/// The replacement spends the same inputs and pays the same recipients, unless it cancels the
+/// original transaction. The fee is taken from the change output of the original transaction, and
+/// inputs are added if it is not enough.
+#[derive(Debug)]
+#[must_use]
+pub struct FeeBumpBuilder<'a, K: Ord, Cs = BranchAndBound> {
+    wallet: &'a Wallet<K>,
+    tx: Arc<Transaction>,
+    required: Vec<WeightedUtxo<K>>,
+    /// Index in the original transaction of its change output, and its keychain.
+    change: Option<(usize, K)>,
+    /// Fees of the original transaction and its unconfirmed descendants.
+    replaced_fee: Amount,
+    original_fee_rate: FeeRate,
+    spend_from: Option<BTreeSet<K>>,
+    change_keychain: K,
+    fee_policy: Option<FeePolicy>,
+    coin_selection: Cs,
+    /// Whether to drop the recipients, see [`Wallet::build_cancel_tx`].
+    cancel: bool,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Start building a transaction replacing the unconfirmed transaction `txid` with a higher
+    /// fee, following the rules of BIP125.
+    ///
+    /// The transaction must signal replaceability and only spend outputs of the wallet. The inputs
+    /// added to pay the higher fee only spend confirmed outputs.
+    ///
+    /// Nothing is staged: the change output of the replacement, if new, uses the next address of
+    /// the change keychain not revealed yet, which is only revealed once the replacement is
+    /// applied with [`Wallet::apply_unconfirmed_txs`].
+    pub fn build_fee_bump(
+        &self,
+        txid: Txid,
+    ) -> Result<FeeBumpBuilder<'_, K>, BuildFeeBumpError<K>> {
+        self.replacement_builder(txid, false)
+    }
+
+    /// Start building a transaction cancelling the unconfirmed transaction `txid`: it spends
+    /// the same inputs, and only them, to the change output of the original transaction or to the
+    /// next address of the change keychain not revealed yet, with a higher fee following the rules
+    /// of BIP125.
+    ///
+    /// Like with [`Wallet::build_fee_bump`] nothing is staged. Once the cancellation is applied
+    /// with [`Wallet::apply_unconfirmed_txs`] the original transaction is no longer canonical, so
+    /// it no longer counts towards the balance and [`Wallet::get_tx`] reports it as replaced.
+    pub fn build_cancel_tx(
+        &self,
+        txid: Txid,
+    ) -> Result<FeeBumpBuilder<'_, K>, BuildFeeBumpError<K>> {
+        self.replacement_builder(txid, true)
+    }
+
+    fn replacement_builder(
+        &self,
+        txid: Txid,
+        cancel: bool,
+    ) -> Result<FeeBumpBuilder<'_, K>, BuildFeeBumpError<K>> {
+        let details = self
+            .get_tx(txid)
+            .ok_or(BuildFeeBumpError::TransactionNotFound(txid))?;
+        match details.status {
+            TxStatus::Unconfirmed { .. } => {}
+            TxStatus::Confirmed { .. } => {
+                return Err(BuildFeeBumpError::TransactionConfirmed(txid))
+            }
+            _ => return Err(BuildFeeBumpError::TransactionNotFound(txid)),
+        }
+        let tx = details.tx;
+        if !tx.is_explicitly_rbf() {
+            return Err(BuildFeeBumpError::IrreplaceableTransaction(txid));
+        }
+
+        let mut required = Vec::new();
+        for txin in &tx.input {
+            let utxo = self
+                .local_output(txin.previous_output)
+                .ok_or(BuildFeeBumpError::ForeignInput(txin.previous_output))?;
+            let weight = self
+                .input_weight(&utxo.keychain)
+                .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(utxo.keychain.clone()))?;
+            required.push(WeightedUtxo { utxo, weight });
+        }
+
+        // The last output paying to the change keychain of one of the inputs. Keychains without a
+        // separate change keychain also receive payments to the wallet itself, so their only
+        // output is a payment rather than change when the transaction has no other output.
+        let mut change_keychains = BTreeSet::new();
+        for w in &required {
+            let keychain = &w.utxo.keychain;
+            let change_keychain = self.change_keychain(keychain);
+            if change_keychain != *keychain || tx.output.len() > 1 {
+                change_keychains.insert(change_keychain);
+            }
+        }
+        let change = details
+            .outputs
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, spk_i)| match spk_i {
+                Some((keychain, _)) if change_keychains.contains(keychain) => {
+                    Some((i, keychain.clone()))
+                }
+                _ => None,
+            });
+        let change_keychain = match &change {
+            Some((_, keychain)) => keychain.clone(),
+            None => self.change_keychain(&required[0].utxo.keychain),
+        };
+
+        let graph = self.tx_graph().graph();
+        let original_fee = graph
+            .calculate_fee(&tx)
+            .expect("all inputs belong to the wallet");
+        let mut descendants_fee = Amount::ZERO;
+        for txid in graph.walk_descendants(txid, |_, txid| Some(txid)) {
+            let details = match self.get_tx(txid) {
+                Some(details) => details,
+                None => continue,
+            };
+            if let TxStatus::Unconfirmed { .. } = details.status {
+                descendants_fee += details
+                    .fee
+                    .ok_or(BuildFeeBumpError::UnknownDescendantFee(txid))?;
+            }
+        }
+
+        Ok(FeeBumpBuilder {
+            wallet: self,
+            original_fee_rate: original_fee / tx.weight(),
+            replaced_fee: original_fee + descendants_fee,
+            tx,
+            required,
+            change,
+            spend_from: None,
+            change_keychain,
+            fee_policy: None,
+            coin_selection: BranchAndBound::default(),
+            cancel,
+        })
+    }
+}
+
+impl<'a, K, Cs> FeeBumpBuilder<'a, K, Cs>
+where
+    K: fmt::Debug + Clone + Ord,
+    Cs: CoinSelectionAlgorithm<K>,
+{
+    /// Pay at least `fee_rate`, which must be higher than the fee rate of the original
+    /// transaction. By default the fee rate is increased by [`INCREMENTAL_RELAY_FEE`].
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_policy = Some(FeePolicy::FeeRate(fee_rate));
+        self
+    }
+
+    /// Pay exactly `fee`, which must cover the fees of the replaced transactions and the
+    /// [`INCREMENTAL_RELAY_FEE`] for the size of the replacement.
+    pub fn fee_absolute(mut self, fee: Amount) -> Self {
+        self.fee_policy = Some(FeePolicy::FeeAmount(fee));
+        self
+    }
+
+    /// Only add inputs from the given `keychains`. By default all keychains are spent from.
+    ///
+    /// A cancellation never adds inputs.
+    pub fn spend_from(mut self, keychains: impl IntoIterator<Item = K>) -> Self {
+        self.spend_from = Some(keychains.into_iter().collect());
+        self
+    }
+
+    /// Choose the added inputs with `coin_selection`, see
+    /// [`TxBuilder::coin_selection`](crate::multi_keychain::TxBuilder::coin_selection).
+    pub fn coin_selection<P>(self, coin_selection: P) -> FeeBumpBuilder<'a, K, P>
+    where
+        P: CoinSelectionAlgorithm<K>,
+    {
+        FeeBumpBuilder {
+            wallet: self.wallet,
+            tx: self.tx,
+            required: self.required,
+            change: self.change,
+            replaced_fee: self.replaced_fee,
+            original_fee_rate: self.original_fee_rate,
+            spend_from: self.spend_from,
+            change_keychain: self.change_keychain,
+            fee_policy: self.fee_policy,
+            coin_selection,
+            cancel: self.cancel,
+        }
+    }
+
+    /// Build the unsigned replacement transaction.
+    pub fn finish(self) -> Result<Psbt, BuildFeeBumpError<K>> {
+        let Self {
+            wallet,
+            tx,
+            required,
+            change,
+            replaced_fee,
+            original_fee_rate,
+            spend_from,
+            change_keychain,
+            fee_policy,
+            coin_selection,
+            cancel,
+        } = self;
+        let txid = tx.compute_txid();
+
+        let fee_policy = fee_policy.unwrap_or(FeePolicy::FeeRate(FeeRate::from_sat_per_kwu(
+            original_fee_rate.to_sat_per_kwu() + INCREMENTAL_RELAY_FEE.to_sat_per_kwu(),
+        )));
+        fee_policy.check()?;
+        if let FeePolicy::FeeRate(rate) = fee_policy {
+            if rate <= original_fee_rate {
+                return Err(BuildFeeBumpError::FeeRateTooLow {
+                    original: original_fee_rate,
+                });
+            }
+        }
+
+        let spend_from = match spend_from {
+            Some(keychains) => keychains,
+            None => wallet.keychains().map(|(keychain, _)| keychain).collect(),
+        };
+        // Outputs of the original transaction and its descendants are replaced along with it.
+        let replaced = core::iter::once(txid)
+            .chain(
+                wallet
+                    .tx_graph()
+                    .graph()
+                    .walk_descendants(txid, |_, txid| Some(txid)),
+            )
+            .collect::<BTreeSet<_>>();
+        let tiers = if cancel {
+            BTreeMap::new()
+        } else {
+            // The replacement may only add confirmed inputs (BIP125 rule 2).
+            wallet.candidates_by_tier(&spend_from, &fee_policy, &BTreeMap::new(), |utxo| {
+                utxo.chain_position.is_confirmed() && !replaced.contains(&utxo.outpoint.txid)
+            })?
+        };
+
+        let change_index = change.as_ref().map(|(i, _)| *i);
+        let change_script = match change_index {
+            Some(i) => tx.output[i].script_pubkey.clone(),
+            None => {
+                wallet
+                    .peek_next_change(&change_keychain)
+                    .ok_or_else(|| CreateTxError::UnknownKeychain(change_keychain.clone()))?
+                    .1
+            }
+        };
+        let change_spend_weight = wallet
+            .input_weight(&change_keychain)
+            .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(change_keychain.clone()))?;
+        let change_txout = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: change_script,
+        };
+
+        let is_recipient = |i: usize| !cancel && Some(i) != change_index;
+        let recipients = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| is_recipient(*i))
+            .map(|(_, txout)| txout.clone())
+            .collect::<Vec<_>>();
+        let template = Transaction {
+            version: tx.version,
+            lock_time: tx.lock_time,
+            input: Vec::new(),
+            output: recipients.clone(),
+        };
+        let base_weight = template.weight() + SEGWIT_MARKER_WEIGHT;
+        let mut params = SelectionParams {
+            target: recipients.iter().map(|txout| txout.value).sum(),
+            base_weight,
+            fee_policy,
+            change_weight: change_txout.weight(),
+            change_spend_weight,
+            min_change: change_txout.script_pubkey.minimal_non_dust(),
+            min_fee: Amount::ZERO,
+        };
+
+        // The replacement must pay for the replaced transactions and for its own relay (BIP125
+        // rules 3 and 4), which depends on its weight and so on the selected inputs.
+        let result = loop {
+            let result = select_by_tier(&coin_selection, required.clone(), tiers.clone(), &params)?;
+            let mut weight = base_weight + result.selected.iter().map(|w| w.weight).sum::<Weight>();
+            if result.change.is_some() {
+                weight += params.change_weight;
+            }
+            let required_fee = replaced_fee + fee_for_weight(INCREMENTAL_RELAY_FEE, weight);
+            if result.fee >= required_fee {
+                break result;
+            }
+            if let FeePolicy::FeeAmount(_) = fee_policy {
+                return Err(BuildFeeBumpError::FeeTooLow {
+                    required: required_fee,
+                });
+            }
+            params.min_fee = required_fee;
+        };
+        let CoinSelectionResult {
+            selected,
+            change,
+            fee,
+        } = result;
+        if cancel && change.is_none() {
+            // The cancellation needs an output.
+            return Err(CreateTxError::InsufficientFunds {
+                needed: fee + params.min_change,
+                available: selected.iter().map(|w| w.utxo.txout.value).sum(),
+            }
+            .into());
+        }
+        let selected = selected
+            .into_iter()
+            .map(|w| w.utxo)
+            .collect::<Vec<LocalOutput<K>>>();
+
+        let mut output = Vec::new();
+        for (i, txout) in tx.output.iter().enumerate() {
+            if is_recipient(i) {
+                output.push(txout.clone());
+            } else if let (true, Some(value)) = (Some(i) == change_index, change) {
+                output.push(TxOut {
+                    value,
+                    ..change_txout.clone()
+                });
+            }
+        }
+        if let (None, Some(value)) = (change_index, change) {
+            output.push(TxOut {
+                value,
+                ..change_txout
+            });
+        }
+        let input = selected
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: utxo.outpoint,
+                sequence: tx
+                    .input
+                    .iter()
+                    .find(|txin| txin.previous_output == utxo.outpoint)
+                    .map_or(Sequence::ENABLE_RBF_NO_LOCKTIME, |txin| txin.sequence),
+                ..Default::default()
+            })
+            .collect();
+
+        let replacement = Transaction {
+            input,
+            output,
+            ..template
+        };
+        let mut psbt = Psbt::from_unsigned_tx(replacement).expect("transaction is unsigned");
+        wallet.update_psbt(&mut psbt, &selected);
+
+        Ok(psbt)
+    }
+}
+
+/// Error returned by [`Wallet::build_fee_bump`] and [`FeeBumpBuilder::finish`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildFeeBumpError<K> {
+    /// The transaction is not in the wallet or not canonical.
+    TransactionNotFound(Txid),
+    /// The transaction is already confirmed.
+    TransactionConfirmed(Txid),
+    /// The transaction does not signal replaceability.
+    IrreplaceableTransaction(Txid),
+    /// The transaction spends an output not belonging to the wallet.
+    ForeignInput(OutPoint),
+    /// The fee of the given unconfirmed descendant is unknown because some of the outputs it
+    /// spends are missing, so the fee the replacement must pay can not be computed.
+    UnknownDescendantFee(Txid),
+    /// The fee rate is not higher than the fee rate of the original transaction.
+    FeeRateTooLow {
+        /// Fee rate of the original transaction.
+        original: FeeRate,
+    },
+    /// The absolute fee does not cover the replaced transactions and the incremental relay fee.
+    FeeTooLow {
+        /// Smallest fee to pay.
+        required: Amount,
+    },
+    /// Building the replacement failed.
+    CreateTx(CreateTxError<K>),
+}
+
+impl<K> From<CreateTxError<K>> for BuildFeeBumpError<K> {
+    fn from(e: CreateTxError<K>) -> Self {
+        Self::CreateTx(e)
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for BuildFeeBumpError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TransactionNotFound(txid) => write!(f, "transaction {txid} not found"),
+            Self::TransactionConfirmed(txid) => write!(f, "transaction {txid} is confirmed"),
+            Self::IrreplaceableTransaction(txid) => {
+                write!(f, "transaction {txid} does not signal replaceability")
+            }
+            Self::ForeignInput(outpoint) => {
+                write!(f, "input {outpoint} does not belong to the wallet")
+            }
+            Self::UnknownDescendantFee(txid) => {
+                write!(f, "the fee of descendant transaction {txid} is unknown")
+            }
+            Self::FeeRateTooLow { original } => write!(
+                f,
+                "the fee rate must be higher than the original {} sat/kwu",
+                original.to_sat_per_kwu()
+            ),
+            Self::FeeTooLow { required } => write!(f, "the fee must be at least {required}"),
+            Self::CreateTx(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug> std::error::Error for BuildFeeBumpError<K> {}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{absolute, hashes::Hash, transaction, ScriptBuf, WPubkeyHash};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+    use crate::multi_keychain::LargestFirst;
+
+    fn recipient() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]))
+    }
+
+    fn fee_of(wallet: &Wallet<bdk_chain::DescriptorId>, tx: &Transaction) -> Amount {
+        wallet.tx_graph().graph().calculate_fee(tx).unwrap()
+    }
+
+    #[test]
+    fn bump_reduces_change() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let op = receive_confirmed(&mut wallet, k, Amount::from_sat(100_000));
+        let tx = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(30_000))
+            .fee_rate(FeeRate::from_sat_per_vb_u32(2))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx.clone(), 1)]);
+        let _ = wallet.take_staged();
+
+        let fee_rate = FeeRate::from_sat_per_vb_u32(5);
+        let psbt = wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        assert!(wallet.staged().is_none());
+
+        let replacement = &psbt.unsigned_tx;
+        assert_eq!(replacement.input.len(), 1);
+        assert_eq!(replacement.input[0].previous_output, op);
+        assert_eq!(replacement.input[0].sequence, tx.input[0].sequence);
+        assert_eq!(replacement.output[0], tx.output[0]);
+        assert_eq!(
+            replacement.output[1].script_pubkey,
+            tx.output[1].script_pubkey
+        );
+        assert!(replacement.output[1].value < tx.output[1].value);
+        let fee = psbt.fee().unwrap();
+        let original_fee = fee_of(&wallet, &tx);
+        assert!(fee >= fee_for_weight(fee_rate, replacement.weight()));
+        assert!(fee >= original_fee + fee_for_weight(INCREMENTAL_RELAY_FEE, replacement.weight()));
+
+        // The replacement takes over once applied.
+        wallet.apply_unconfirmed_txs([(psbt.unsigned_tx.clone(), 2)]);
+        assert_eq!(
+            wallet.get_tx(txid).unwrap().replaced_by,
+            [psbt.unsigned_tx.compute_txid()]
+        );
+    }
+
+    #[test]
+    fn bump_adds_inputs() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], DESCRIPTORS[1]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let k_change = descriptor_id(DESCRIPTORS[1]);
+        wallet.set_change_keychain(k, k_change);
+        let op_small = receive_confirmed(&mut wallet, k, Amount::from_sat(30_000));
+        let op_large = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        // No change output, the original only spends the large output.
+        let tx = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(49_000))
+            .fee_absolute(Amount::from_sat(1_000))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        assert_eq!(tx.output.len(), 1);
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx, 1)]);
+        let _ = wallet.take_staged();
+
+        let psbt = wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(20))
+            .finish()
+            .unwrap();
+        assert!(wallet.staged().is_none());
+
+        let replacement = &psbt.unsigned_tx;
+        assert_eq!(
+            replacement
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            [op_large, op_small]
+        );
+        assert_eq!(replacement.output.len(), 2);
+        let (_, change_spk) = wallet.peek_next_change(&k_change).unwrap();
+        assert_eq!(replacement.output[1].script_pubkey, change_spk);
+        assert_eq!(
+            wallet.txout_index().last_revealed_index(k_change),
+            None,
+            "the change address is not revealed"
+        );
+    }
+
+    #[test]
+    fn bump_keeps_self_payment() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let op_small = receive_confirmed(&mut wallet, k, Amount::from_sat(30_000));
+        let op_large = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        // A payment to the wallet itself, with no change output.
+        let address = wallet.reveal_next_address(k).unwrap().address;
+        let tx = wallet
+            .build_tx()
+            .add_recipient(address.script_pubkey(), Amount::from_sat(49_000))
+            .fee_absolute(Amount::from_sat(1_000))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        assert_eq!(tx.output.len(), 1);
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx.clone(), 1)]);
+        let _ = wallet.take_staged();
+
+        let psbt = wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(20))
+            .finish()
+            .unwrap();
+        let replacement = &psbt.unsigned_tx;
+        assert_eq!(
+            replacement
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            [op_large, op_small]
+        );
+        assert_eq!(replacement.output.len(), 2);
+        assert_eq!(replacement.output[0], tx.output[0]);
+    }
+
+    #[test]
+    fn bump_adds_confirmed_inputs_only() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        wallet.set_keychain_trusted(k, true);
+        let op_confirmed = receive_confirmed(&mut wallet, k, Amount::from_sat(30_000));
+        let op_original = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        let tx = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(49_000))
+            .fee_absolute(Amount::from_sat(1_000))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx, 1)]);
+        // Larger, and spendable by the wallet since the keychain is trusted.
+        let op_unconfirmed = receive_unconfirmed(&mut wallet, k, Amount::from_sat(100_000), 2);
+        assert!(wallet
+            .list_spendable()
+            .any(|utxo| utxo.outpoint == op_unconfirmed));
+
+        let psbt = wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(20))
+            .coin_selection(LargestFirst)
+            .finish()
+            .unwrap();
+        let inputs = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, [op_original, op_confirmed]);
+
+        // Not enough confirmed outputs left to pay for the bump.
+        match wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(1_000))
+            .finish()
+        {
+            Err(BuildFeeBumpError::CreateTx(CreateTxError::InsufficientFunds {
+                available,
+                ..
+            })) => assert_eq!(available, Amount::from_sat(80_000)),
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn cancel_tx() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], DESCRIPTORS[1]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let k_change = descriptor_id(DESCRIPTORS[1]);
+        wallet.set_change_keychain(k, k_change);
+        let op = receive_confirmed(&mut wallet, k, Amount::from_sat(100_000));
+        let tx = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(30_000))
+            .fee_rate(FeeRate::from_sat_per_vb_u32(2))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx.clone(), 1)]);
+        let _ = wallet.take_staged();
+
+        let psbt = wallet.build_cancel_tx(txid).unwrap().finish().unwrap();
+        assert!(wallet.staged().is_none());
+        let cancel = psbt.unsigned_tx.clone();
+        assert_eq!(
+            cancel
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            [op]
+        );
+        assert_eq!(cancel.output.len(), 1);
+        assert_eq!(cancel.output[0].script_pubkey, tx.output[1].script_pubkey);
+        let fee = psbt.fee().unwrap();
+        assert!(
+            fee >= fee_of(&wallet, &tx) + fee_for_weight(INCREMENTAL_RELAY_FEE, cancel.weight())
+        );
+
+        wallet.apply_unconfirmed_txs([(cancel.clone(), 2)]);
+        let details = wallet.get_tx(txid).unwrap();
+        assert_eq!(details.status, TxStatus::Conflicted);
+        assert_eq!(details.replaced_by, [cancel.compute_txid()]);
+        assert_eq!(wallet.balance().total(), Amount::from_sat(100_000) - fee);
+        assert!(wallet
+            .transactions()
+            .iter()
+            .all(|summary| summary.txid != txid));
+    }
+
+    #[test]
+    fn invalid_fee_bumps() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let confirmed = receive_confirmed(&mut wallet, k, Amount::from_sat(100_000));
+        let foreign = receive_unconfirmed(&mut wallet, k, Amount::from_sat(10_000), 1);
+
+        assert_eq!(
+            wallet.build_fee_bump(Txid::all_zeros()).unwrap_err(),
+            BuildFeeBumpError::TransactionNotFound(Txid::all_zeros())
+        );
+        assert_eq!(
+            wallet.build_fee_bump(confirmed.txid).unwrap_err(),
+            BuildFeeBumpError::TransactionConfirmed(confirmed.txid)
+        );
+        assert_eq!(
+            wallet.build_fee_bump(foreign.txid).unwrap_err(),
+            BuildFeeBumpError::IrreplaceableTransaction(foreign.txid)
+        );
+
+        let tx = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(30_000))
+            .fee_rate(FeeRate::from_sat_per_vb_u32(2))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx.clone(), 2)]);
+
+        let original_fee = fee_of(&wallet, &tx);
+        assert_eq!(
+            wallet
+                .build_fee_bump(txid)
+                .unwrap()
+                .fee_rate(original_fee / tx.weight())
+                .finish(),
+            Err(BuildFeeBumpError::FeeRateTooLow {
+                original: original_fee / tx.weight()
+            })
+        );
+        assert!(matches!(
+            wallet
+                .build_fee_bump(txid)
+                .unwrap()
+                .fee_absolute(original_fee)
+                .finish(),
+            Err(BuildFeeBumpError::FeeTooLow { required }) if required > original_fee
+        ));
+
+        // A descendant spending an unknown output makes the fee to replace unknown.
+        let child = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: [OutPoint::new(txid, 1), OutPoint::new(Txid::all_zeros(), 0)]
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(60_000),
+                script_pubkey: recipient(),
+            }],
+        };
+        wallet.apply_unconfirmed_txs([(child.clone(), 3)]);
+        assert_eq!(
+            wallet.build_fee_bump(txid).unwrap_err(),
+            BuildFeeBumpError::UnknownDescendantFee(child.compute_txid())
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Transaction history of the [`Wallet`].
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{cmp::Reverse, fmt};
+
+use bdk_chain::{CanonicalizationParams, ChainPosition, ConfirmationBlockTime, Indexer, TxGraph};
+use bitcoin::{Amount, FeeRate, SignedAmount, Transaction, Txid};
+
+use crate::bdk_chain;
+use crate::collections::BTreeMap;
+use crate::multi_keychain::Wallet;
+
+/// A canonical transaction of the [`Wallet`] along with how it affects the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxSummary<K> {
+    /// Transaction id.
+    pub txid: Txid,
+    /// The transaction.
+    pub tx: Arc<Transaction>,
+    /// Position of the transaction in the chain.
+    pub chain_position: ChainPosition<ConfirmationBlockTime>,
+    /// Fee paid by the transaction, if all of its previous outputs are known.
+    pub fee: Option<Amount>,
+    /// Fee rate of the transaction, if its fee is known.
+    pub fee_rate: Option<FeeRate>,
+    /// Total value of the wallet outputs spent by the transaction.
+    pub sent: Amount,
+    /// Total value of the transaction outputs paying to the wallet.
+    pub received: Amount,
+    /// Net value the transaction adds to (or removes from) each keychain it involves.
+    pub net_by_keychain: BTreeMap<K, SignedAmount>,
+}
+
+/// Status of a transaction relative to the canonical history of the [`Wallet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Confirmed in the best chain.
+    Confirmed {
+        /// Height of the confirming block.
+        height: u32,
+        /// Time of the confirming block.
+        time: u64,
+    },
+    /// Canonical but not confirmed yet.
+    Unconfirmed {
+        /// When the transaction was last seen in the mempool, if ever.
+        last_seen: Option<u64>,
+    },
+    /// Evicted from the mempool.
+    Evicted {
+        /// When the transaction was last evicted.
+        evicted_at: u64,
+    },
+    /// Not canonical because it conflicts with the canonical history, possibly through one of
+    /// its ancestors.
+    Conflicted,
+}
+
+impl From<ChainPosition<ConfirmationBlockTime>> for TxStatus {
+    fn from(pos: ChainPosition<ConfirmationBlockTime>) -> Self {
+        match pos {
+            ChainPosition::Confirmed { anchor, .. } => Self::Confirmed {
+                height: anchor.block_id.height,
+                time: anchor.confirmation_time,
+            },
+            ChainPosition::Unconfirmed { last_seen, .. } => Self::Unconfirmed { last_seen },
+        }
+    }
+}
+
+/// Details of a single transaction of the [`Wallet`], see [`Wallet::get_tx`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxDetails<K> {
+    /// Transaction id.
+    pub txid: Txid,
+    /// The transaction.
+    pub tx: Arc<Transaction>,
+    /// Status of the transaction.
+    pub status: TxStatus,
+    /// Fee paid by the transaction, if all of its previous outputs are known.
+    pub fee: Option<Amount>,
+    /// Keychain and derivation index of the output spent by each input, if owned by the wallet.
+    pub inputs: Vec<Option<(K, u32)>>,
+    /// Keychain and derivation index of each output, if owned by the wallet.
+    pub outputs: Vec<Option<(K, u32)>>,
+    /// Canonical transactions spending the same outputs as this one.
+    ///
+    /// This is non-empty if the transaction has been replaced.
+    pub replaced_by: Vec<Txid>,
+}
+
+impl<K> TxSummary<K> {
+    /// Net value the transaction adds to (or removes from) the wallet.
+    pub fn net(&self) -> SignedAmount {
+        signed(self.received) - signed(self.sent)
+    }
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// List the canonical transactions relevant to the wallet, most recent first.
+    ///
+    /// Unconfirmed transactions come first, ordered by when they were last seen, followed by
+    /// the confirmed transactions in descending order of confirmation height.
+    pub fn transactions(&self) -> Vec<TxSummary<K>> {
+        self.transactions_page(0, usize::MAX)
+    }
+
+    /// List at most `limit` of the transactions returned by [`Wallet::transactions`], skipping
+    /// the first `offset` ones.
+    ///
+    /// Only the transactions of the page are summarized, and only those up to the end of the page
+    /// are fully sorted.
+    pub fn transactions_page(&self, offset: usize, limit: usize) -> Vec<TxSummary<K>> {
+        let chain = self.local_chain();
+        let graph = self.tx_graph().graph();
+        let index = self.txout_index();
+
+        let mut txs = graph
+            .list_canonical_txs(
+                chain,
+                chain.tip().block_id(),
+                CanonicalizationParams::default(),
+            )
+            .filter(|c_tx| index.is_tx_relevant(&c_tx.tx_node.tx))
+            .map(|c_tx| (c_tx.chain_position, c_tx.tx_node.txid, c_tx.tx_node.tx))
+            .collect::<Vec<_>>();
+        let key = |(pos, txid, _): &(_, Txid, _)| (Reverse(recency(pos)), *txid);
+        let end = offset.saturating_add(limit);
+        if end < txs.len() {
+            txs.select_nth_unstable_by_key(end, key);
+            txs.truncate(end);
+        }
+        txs.sort_unstable_by_key(key);
+
+        txs.into_iter()
+            .skip(offset)
+            .map(|(chain_position, txid, tx)| self.summarize_tx(graph, chain_position, txid, tx))
+            .collect()
+    }
+
+    /// Get the details of the transaction with `txid`.
+    ///
+    /// Returns `None` if the full transaction is not in the wallet's transaction graph.
+    pub fn get_tx(&self, txid: Txid) -> Option<TxDetails<K>> {
+        let chain = self.local_chain();
+        let graph = self.tx_graph().graph();
+        let index = self.txout_index();
+        let tx_node = graph.get_tx_node(txid)?;
+
+        // A transaction anchored in the best chain is canonical and its conflicts are not.
+        // Otherwise the canonical history is only walked until the transaction and its conflicts
+        // are found.
+        let anchor = tx_node.anchors.iter().find(|anchor| {
+            chain
+                .get(anchor.block_id.height)
+                .map_or(false, |cp| cp.hash() == anchor.block_id.hash)
+        });
+        let mut conflicts = graph
+            .direct_conflicts(&tx_node.tx)
+            .map(|(_, conflict)| conflict)
+            .collect::<Vec<_>>();
+        conflicts.sort();
+        conflicts.dedup();
+        let mut canonical = BTreeMap::new();
+        match anchor {
+            Some(anchor) => {
+                let pos = ChainPosition::Confirmed {
+                    anchor: *anchor,
+                    transitively: None,
+                };
+                canonical.insert(txid, pos);
+            }
+            None => {
+                let mut pending = conflicts.len() + 1;
+                let c_txs = graph.list_canonical_txs(
+                    chain,
+                    chain.tip().block_id(),
+                    CanonicalizationParams::default(),
+                );
+                for c_tx in c_txs {
+                    let c_txid = c_tx.tx_node.txid;
+                    if c_txid == txid || conflicts.binary_search(&c_txid).is_ok() {
+                        canonical.insert(c_txid, c_tx.chain_position);
+                        pending -= 1;
+                        if pending == 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let replaced_by = conflicts
+            .into_iter()
+            .filter(|conflict| canonical.contains_key(conflict))
+            .collect::<Vec<_>>();
+
+        let status = match canonical.get(&txid) {
+            Some(pos) => TxStatus::from(*pos),
+            None => match graph.get_last_evicted(txid) {
+                Some(evicted_at) if tx_node.last_seen.map_or(true, |seen| evicted_at >= seen) => {
+                    TxStatus::Evicted { evicted_at }
+                }
+                _ if replaced_by.is_empty() && tx_node.last_seen.is_none() => {
+                    TxStatus::Unconfirmed { last_seen: None }
+                }
+                _ => TxStatus::Conflicted,
+            },
+        };
+
+        let tx = tx_node.tx;
+        Some(TxDetails {
+            txid,
+            status,
+            fee: graph.calculate_fee(&tx).ok(),
+            inputs: tx
+                .input
+                .iter()
+                .map(|txin| index.txout(txin.previous_output).map(|(spk_i, _)| spk_i))
+                .collect(),
+            outputs: tx
+                .output
+                .iter()
+                .map(|txout| index.index_of_spk(txout.script_pubkey.clone()).cloned())
+                .collect(),
+            replaced_by,
+            tx,
+        })
+    }
+
+    /// Build the [`TxSummary`] of a transaction of the wallet.
+    pub(crate) fn summarize_tx(
+        &self,
+        graph: &TxGraph<ConfirmationBlockTime>,
+        chain_position: ChainPosition<ConfirmationBlockTime>,
+        txid: Txid,
+        tx: Arc<Transaction>,
+    ) -> TxSummary<K> {
+        let index = self.txout_index();
+        let (sent, received) = index.sent_and_received(&tx, ..);
+
+        let mut net_by_keychain = BTreeMap::<K, SignedAmount>::new();
+        for txin in &tx.input {
+            if let Some(((keychain, _), txout)) = index.txout(txin.previous_output) {
+                *net_by_keychain.entry(keychain).or_default() -= signed(txout.value);
+            }
+        }
+        for txout in &tx.output {
+            if let Some((keychain, _)) = index.index_of_spk(txout.script_pubkey.clone()) {
+                *net_by_keychain.entry(keychain.clone()).or_default() += signed(txout.value);
+            }
+        }
+
+        let fee = graph.calculate_fee(&tx).ok();
+        TxSummary {
+            txid,
+            fee_rate: fee.map(|fee| fee / tx.weight()),
+            fee,
+            sent,
+            received,
+            net_by_keychain,
+            chain_position,
+            tx,
+        }
+    }
+}
+
+/// Orders chain positions from oldest to most recent.
+///
+/// Confirmed transactions are older than unconfirmed ones. Among unconfirmed transactions, those
+/// never seen in the mempool are the oldest.
+fn recency(pos: &ChainPosition<ConfirmationBlockTime>) -> (bool, u32, Option<u64>) {
+    match pos {
+        ChainPosition::Confirmed { anchor, .. } => (false, anchor.block_id.height, None),
+        ChainPosition::Unconfirmed { last_seen, .. } => (true, 0, *last_seen),
+    }
+}
+
+fn signed(amount: Amount) -> SignedAmount {
+    amount.to_signed().expect("amount is within range")
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{sync::Arc, vec};
+
+    use bitcoin::{
+        absolute, transaction, Amount, OutPoint, ScriptBuf, SignedAmount, Transaction, TxIn, TxOut,
+    };
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    fn spend(prevouts: &[OutPoint], outputs: &[(Amount, ScriptBuf)]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: prevouts
+                .iter()
+                .map(|&previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(value, script_pubkey)| TxOut {
+                    value: *value,
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn transactions_with_net_value_per_keychain() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let k2 = descriptor_id(DESCRIPTORS[2]);
+        let op0 = receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+        let op1 = receive_confirmed(&mut wallet, k1, Amount::from_sat(30_000));
+
+        // Spend both outputs, paying an external party, a new k2 address and a fee.
+        let k2_addr = wallet.reveal_next_address(k2).unwrap();
+        let spend = spend(
+            &[op0, op1],
+            &[
+                (Amount::from_sat(60_000), ScriptBuf::new_op_return([])),
+                (Amount::from_sat(19_000), k2_addr.script_pubkey()),
+            ],
+        );
+        wallet.apply_unconfirmed_txs([(Arc::new(spend.clone()), 200)]);
+
+        let txs = wallet.transactions();
+        assert_eq!(txs.len(), 3);
+        let latest = &txs[0];
+        assert_eq!(latest.txid, spend.compute_txid());
+        assert!(!latest.chain_position.is_confirmed());
+        assert_eq!(latest.fee, Some(Amount::from_sat(1_000)));
+        assert!(latest.fee_rate.is_some());
+        assert_eq!(latest.sent, Amount::from_sat(80_000));
+        assert_eq!(latest.received, Amount::from_sat(19_000));
+        assert_eq!(latest.net(), SignedAmount::from_sat(-61_000));
+        assert_eq!(
+            latest.net_by_keychain,
+            [
+                (k0, SignedAmount::from_sat(-50_000)),
+                (k1, SignedAmount::from_sat(-30_000)),
+                (k2, SignedAmount::from_sat(19_000)),
+            ]
+            .into()
+        );
+
+        // Confirmed transactions follow, most recent first.
+        assert_eq!(txs[1].txid, op1.txid);
+        assert_eq!(txs[2].txid, op0.txid);
+        assert_eq!(
+            txs[2].net_by_keychain,
+            [(k0, SignedAmount::from_sat(50_000))].into()
+        );
+        // The previous outputs of the funding transactions are unknown.
+        assert_eq!(txs[2].fee, None);
+
+        let page = wallet.transactions_page(1, 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].txid, op1.txid);
+        assert!(wallet.transactions_page(3, 10).is_empty());
+    }
+
+    #[test]
+    fn irrelevant_transactions_are_not_listed() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        let unrelated = spend(&[OutPoint::null()], &[]);
+        wallet.apply_unconfirmed_txs([(unrelated, 100)]);
+
+        assert_eq!(wallet.transactions().len(), 1);
+    }
+
+    #[test]
+    fn get_tx_details() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let op = receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+        let height = wallet.latest_checkpoint().height();
+
+        let details = wallet.get_tx(op.txid).unwrap();
+        assert!(matches!(details.status, TxStatus::Confirmed { height: h, .. } if h == height));
+        assert_eq!(details.outputs, vec![Some((k0, 0))]);
+        assert_eq!(details.inputs, vec![None]);
+        assert_eq!(details.fee, None);
+        assert!(details.replaced_by.is_empty());
+
+        // Spend to an external output and k1, then replace it with a higher fee version.
+        let k1_spk = wallet.reveal_next_address(k1).unwrap().script_pubkey();
+        let original = spend(
+            &[op],
+            &[
+                (Amount::from_sat(30_000), ScriptBuf::new_op_return([])),
+                (Amount::from_sat(19_000), k1_spk.clone()),
+            ],
+        );
+        let replacement = spend(
+            &[op],
+            &[
+                (Amount::from_sat(30_000), ScriptBuf::new_op_return([])),
+                (Amount::from_sat(18_000), k1_spk),
+            ],
+        );
+        wallet.apply_unconfirmed_txs([(original.clone(), 100)]);
+        let details = wallet.get_tx(original.compute_txid()).unwrap();
+        assert_eq!(
+            details.status,
+            TxStatus::Unconfirmed {
+                last_seen: Some(100)
+            }
+        );
+        assert_eq!(details.fee, Some(Amount::from_sat(1_000)));
+        assert_eq!(details.inputs, vec![Some((k0, 0))]);
+        assert_eq!(details.outputs, vec![None, Some((k1, 0))]);
+
+        wallet.apply_unconfirmed_txs([(replacement.clone(), 200)]);
+        let details = wallet.get_tx(original.compute_txid()).unwrap();
+        assert_eq!(details.status, TxStatus::Conflicted);
+        assert_eq!(details.replaced_by, vec![replacement.compute_txid()]);
+
+        wallet.apply_evicted_txs([(replacement.compute_txid(), 300)]);
+        let details = wallet.get_tx(replacement.compute_txid()).unwrap();
+        assert_eq!(details.status, TxStatus::Evicted { evicted_at: 300 });
+
+        assert!(wallet.get_tx(OutPoint::null().txid).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 //! [`KeyRing`].
 
+use alloc::string::String;
 use bdk_chain::{DescriptorExt, Merge};
 use bdk_wallet::descriptor::IntoWalletDescriptor;
 use bitcoin::{
@@ -10,7 +11,7 @@ use miniscript::{Descriptor, DescriptorPublicKey};
 use serde::{Deserialize, Serialize};
 
 use crate::bdk_chain;
-use crate::collections::BTreeMap;
+use crate::collections::{BTreeMap, BTreeSet};
 use crate::multi_keychain::Did;
 
 /// KeyRing.
@@ -20,6 +21,8 @@ pub struct KeyRing<K> {
     pub(crate) network: Network,
     pub(crate) descriptors: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
     pub(crate) default_keychain: K,
+    pub(crate) groups: BTreeMap<String, BTreeSet<K>>,
+    pub(crate) change_keychains: BTreeMap<K, K>,
 }
 
 impl<K> KeyRing<K>
@@ -44,6 +47,8 @@ where
             network,
             descriptors: BTreeMap::from([(keychain.clone(), descriptor)]),
             default_keychain: keychain.clone(),
+            groups: BTreeMap::new(),
+            change_keychains: BTreeMap::new(),
         }
     }
 
@@ -84,12 +89,44 @@ where
         &self.descriptors
     }
 
+    /// Define a named group of keychains, replacing any existing group with the same `name`.
+    ///
+    /// Groups let you treat a set of keychains as one account, e.g. to compute its balance with
+    /// [`Wallet::balance_by_group`](crate::multi_keychain::Wallet::balance_by_group).
+    pub fn add_group(&mut self, name: impl Into<String>, keychains: impl IntoIterator<Item = K>) {
+        self.groups
+            .insert(name.into(), keychains.into_iter().collect());
+    }
+
+    /// Return the named groups of keychains.
+    pub fn groups(&self) -> &BTreeMap<String, BTreeSet<K>> {
+        &self.groups
+    }
+
+    /// Pair `keychain` with the keychain receiving the change of transactions spending from it.
+    pub fn set_change_keychain(&mut self, keychain: K, change_keychain: K) {
+        self.change_keychains.insert(keychain, change_keychain);
+    }
+
+    /// Returns the keychain receiving the change of transactions spending from `keychain`.
+    ///
+    /// This is `keychain` itself unless another keychain was paired with it using
+    /// [`KeyRing::set_change_keychain`].
+    pub fn change_keychain(&self, keychain: &K) -> K {
+        self.change_keychains
+            .get(keychain)
+            .unwrap_or(keychain)
+            .clone()
+    }
+
     /// Initial changeset.
     pub fn initial_changeset(&self) -> ChangeSet<K> {
         ChangeSet {
             network: Some(self.network),
             descriptors: self.descriptors.clone(),
             default_keychain: Some(self.default_keychain.clone()),
+            groups: self.groups.clone(),
+            change_keychains: self.change_keychains.clone(),
         }
     }
 
@@ -100,6 +137,12 @@ where
             network: changeset.network?,
             descriptors: changeset.descriptors,
             default_keychain: changeset.default_keychain?,
+            groups: changeset
+                .groups
+                .into_iter()
+                .filter(|(_, keychains)| !keychains.is_empty())
+                .collect(),
+            change_keychains: changeset.change_keychains,
         })
     }
 }
@@ -134,6 +177,12 @@ pub struct ChangeSet<K: Ord> {
     pub descriptors: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
     /// Default keychain
     pub default_keychain: Option<K>,
+    /// Keychain groups by name. A group with no keychains has been removed.
+    #[serde(default = "BTreeMap::new")]
+    pub groups: BTreeMap<String, BTreeSet<K>>,
+    /// Change keychain paired with each keychain.
+    #[serde(default = "BTreeMap::new")]
+    pub change_keychains: BTreeMap<K, K>,
 }
 
 impl<K: Ord> Default for ChangeSet<K> {
@@ -142,6 +191,8 @@ impl<K: Ord> Default for ChangeSet<K> {
             network: None,
             descriptors: Default::default(),
             default_keychain: None,
+            groups: Default::default(),
+            change_keychains: Default::default(),
         }
     }
 }
@@ -159,9 +210,18 @@ impl<K: Ord> Merge for ChangeSet<K> {
         if other.default_keychain.is_some() {
             self.default_keychain = other.default_keychain;
         }
+
+        // groups are replaced as a whole
+        self.groups.extend(other.groups);
+
+        // change keychain pairings are replaced
+        self.change_keychains.extend(other.change_keychains);
     }
 
     fn is_empty(&self) -> bool {
-        self.network.is_none() && self.descriptors.is_empty()
+        self.network.is_none()
+            && self.descriptors.is_empty()
+            && self.groups.is_empty()
+            && self.change_keychains.is_empty()
     }
 }
@@ -0,0 +1,664 @@
+//! Queue of payments of the [`Wallet`] sent in batches, see [`Wallet::enqueue_payment`] and
+//! [`Wallet::build_payment_batch`].
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use bitcoin::{Amount, FeeRate, Psbt, ScriptBuf, Txid, Weight};
+use miniscript::descriptor::DescriptorType;
+use serde::{Deserialize, Serialize};
+
+use crate::multi_keychain::{CreateTxError, CreatedTx, TxStatus, Wallet, MAX_STANDARD_TX_WEIGHT};
+
+/// A payment in the queue of the [`Wallet`], see [`Wallet::enqueue_payment`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedPayment {
+    /// Position in the queue, payments are batched in increasing order.
+    pub position: u64,
+    /// Script pubkey paid.
+    pub script_pubkey: ScriptBuf,
+    /// Amount paid.
+    pub amount: Amount,
+    /// The transaction the payment was batched in, if any.
+    pub txid: Option<Txid>,
+}
+
+/// Status of a payment in the queue, see [`Wallet::payment_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    /// Waiting to be batched.
+    Pending,
+    /// Batched in a transaction.
+    Batched {
+        /// The transaction paying the payment, which is the canonical replacement of the batch
+        /// paying it too if any, e.g. a fee bump.
+        txid: Txid,
+        /// Status of the transaction, `None` if the wallet has not seen it yet.
+        tx_status: Option<TxStatus>,
+    },
+}
+
+/// A transaction paying a batch of queued payments, see [`Wallet::build_payment_batch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentBatch {
+    /// The unsigned transaction.
+    pub psbt: Psbt,
+    /// Ids of the payments paid, the `i`-th one by the `i`-th output.
+    pub payments: Vec<String>,
+}
+
+/// Builds a transaction paying as many pending payments of the queue as fit in its budgets, see
+/// [`Wallet::build_payment_batch`].
+#[derive(Debug)]
+#[must_use]
+pub struct PaymentBatchBuilder<'a, K: Ord> {
+    wallet: &'a mut Wallet<K>,
+    fee_rate: FeeRate,
+    max_weight: Weight,
+    max_fee: Option<Amount>,
+    change_keychain: Option<K>,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Add a payment of `amount` to `script_pubkey` at the end of the queue, under `id`.
+    ///
+    /// The payment is sent by a later [`Wallet::build_payment_batch`]. The queue is staged.
+    pub fn enqueue_payment(
+        &mut self,
+        id: impl Into<String>,
+        script_pubkey: impl Into<ScriptBuf>,
+        amount: Amount,
+    ) -> Result<(), EnqueuePaymentError> {
+        let id = id.into();
+        let script_pubkey = script_pubkey.into();
+        if self.payment_queue().contains_key(&id) {
+            return Err(EnqueuePaymentError::DuplicateId(id));
+        }
+        if amount < script_pubkey.minimal_non_dust() {
+            return Err(EnqueuePaymentError::BelowDustLimit(id));
+        }
+        let position = self
+            .payment_queue()
+            .values()
+            .map(|payment| payment.position + 1)
+            .max()
+            .unwrap_or(0);
+        self.set_queued_payment(
+            id,
+            Some(QueuedPayment {
+                position,
+                script_pubkey,
+                amount,
+                txid: None,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Get the payment of the queue with `id`.
+    pub fn queued_payment(&self, id: &str) -> Option<&QueuedPayment> {
+        self.payment_queue().get(id)
+    }
+
+    /// Iterate over the payments of the queue in order, batched or not.
+    pub fn queued_payments(&self) -> impl Iterator<Item = (&str, &QueuedPayment)> {
+        let mut payments = self
+            .payment_queue()
+            .iter()
+            .map(|(id, payment)| (id.as_str(), payment))
+            .collect::<Vec<_>>();
+        payments.sort_by_key(|(_, payment)| payment.position);
+        payments.into_iter()
+    }
+
+    /// Get the status of the payment with `id`, following its transaction through confirmation
+    /// and replacement.
+    pub fn payment_status(&self, id: &str) -> Option<PaymentStatus> {
+        let payment = self.queued_payment(id)?;
+        let txid = match payment.txid {
+            None => return Some(PaymentStatus::Pending),
+            Some(txid) => txid,
+        };
+        let details = self.get_tx(txid);
+        let replacement = details
+            .iter()
+            .flat_map(|details| &details.replaced_by)
+            .filter_map(|&txid| self.get_tx(txid))
+            .find(|replacement| {
+                replacement.tx.output.iter().any(|txout| {
+                    txout.script_pubkey == payment.script_pubkey && txout.value == payment.amount
+                })
+            });
+        Some(match replacement {
+            Some(replacement) => PaymentStatus::Batched {
+                txid: replacement.txid,
+                tx_status: Some(replacement.status),
+            },
+            None => PaymentStatus::Batched {
+                txid,
+                tx_status: details.map(|details| details.status),
+            },
+        })
+    }
+
+    /// Put the batched payment with `id` back in the queue, after its transaction was evicted or
+    /// conflicted, e.g. cancelled, or if it was `never_broadcast`.
+    ///
+    /// A transaction the wallet does not know of may have been broadcast without being synced
+    /// yet, so the payment is only requeued in that case if the caller tells it was
+    /// `never_broadcast`. Otherwise the next batch could pay it a second time.
+    ///
+    /// Returns `false`, doing nothing, if the payment is not batched or if its transaction, or a
+    /// replacement of it paying the same amount to the same script pubkey, is canonical, i.e.
+    /// unconfirmed or confirmed. The change is staged.
+    pub fn requeue_payment(&mut self, id: &str, never_broadcast: bool) -> bool {
+        match self.payment_status(id) {
+            Some(PaymentStatus::Batched { tx_status, .. })
+                if match tx_status {
+                    Some(TxStatus::Evicted { .. } | TxStatus::Conflicted) => true,
+                    None => never_broadcast,
+                    Some(_) => false,
+                } =>
+            {
+                let mut payment = self.payment_queue()[id].clone();
+                payment.txid = None;
+                self.set_queued_payment(id.into(), Some(payment));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the payment with `id` from the queue, whether it is batched or not.
+    ///
+    /// The change is staged.
+    pub fn remove_payment(&mut self, id: &str) -> Option<QueuedPayment> {
+        let payment = self.payment_queue().get(id)?.clone();
+        self.set_queued_payment(id.into(), None);
+        Some(payment)
+    }
+
+    /// Start building a transaction paying the pending payments of the queue.
+    pub fn build_payment_batch(&mut self) -> PaymentBatchBuilder<'_, K> {
+        PaymentBatchBuilder {
+            wallet: self,
+            fee_rate: FeeRate::BROADCAST_MIN,
+            max_weight: MAX_STANDARD_TX_WEIGHT,
+            max_fee: None,
+            change_keychain: None,
+        }
+    }
+}
+
+impl<K> PaymentBatchBuilder<'_, K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Pay at least `fee_rate`. This is the default, at 1 sat/vB.
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    /// Build a transaction of at most `max_weight` once signed. The default is
+    /// [`MAX_STANDARD_TX_WEIGHT`].
+    pub fn max_weight(mut self, max_weight: Weight) -> Self {
+        self.max_weight = max_weight;
+        self
+    }
+
+    /// Pay at most `max_fee`. There is no limit by default.
+    pub fn max_fee(mut self, max_fee: Amount) -> Self {
+        self.max_fee = Some(max_fee);
+        self
+    }
+
+    /// Send the change to `keychain`, see
+    /// [`TxBuilder::change_keychain`](crate::multi_keychain::TxBuilder::change_keychain).
+    pub fn change_keychain(mut self, keychain: K) -> Self {
+        self.change_keychain = Some(keychain);
+        self
+    }
+
+    /// Build the unsigned transaction, or `None` if no payment is pending.
+    ///
+    /// The pending payments are added in queue order, skipping those that would make the
+    /// transaction exceed its budgets or that the wallet can not fund. The payments included are
+    /// marked as batched in the transaction and, if it has a change output, its address is
+    /// revealed and marked as used on the change keychain, which is all staged in the wallet.
+    ///
+    /// Only the outputs of native segwit keychains are spent: signing their inputs leaves the
+    /// txid unchanged, so that the payments can be followed by the txid of the unsigned
+    /// transaction.
+    pub fn finish(self) -> Result<Option<PaymentBatch>, BuildBatchError<K>> {
+        let Self {
+            wallet,
+            fee_rate,
+            max_weight,
+            max_fee,
+            change_keychain,
+        } = self;
+
+        let pending = wallet
+            .queued_payments()
+            .filter(|(_, payment)| payment.txid.is_none())
+            .map(|(id, payment)| (String::from(id), payment.clone()))
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        let segwit_keychains = wallet
+            .keychains()
+            .filter(|(_, descriptor)| {
+                matches!(
+                    descriptor.desc_type(),
+                    DescriptorType::Wpkh
+                        | DescriptorType::Wsh
+                        | DescriptorType::WshSortedMulti
+                        | DescriptorType::Tr
+                )
+            })
+            .map(|(keychain, _)| keychain)
+            .collect::<Vec<_>>();
+        let change_keychain =
+            change_keychain.unwrap_or_else(|| wallet.change_keychain(&wallet.default_keychain()));
+        let mut builder = wallet
+            .build_tx()
+            .fee_rate(fee_rate)
+            .spend_from(segwit_keychains)
+            .change_keychain(change_keychain);
+        let mut recipients = Vec::new();
+        let mut payments = Vec::new();
+        let mut first_error = None;
+        for (id, payment) in pending {
+            let mut candidate = recipients.clone();
+            candidate.push((payment.script_pubkey, payment.amount));
+            builder = builder.set_recipients(candidate.clone());
+            let error = match builder.create() {
+                Ok(CreatedTx { psbt, weight, .. }) => {
+                    let fee = psbt.fee().expect("inputs are known");
+                    if weight > max_weight {
+                        BuildBatchError::WeightBudgetExceeded
+                    } else if max_fee.map_or(false, |max_fee| fee > max_fee) {
+                        BuildBatchError::FeeBudgetExceeded
+                    } else {
+                        recipients = candidate;
+                        payments.push(id);
+                        continue;
+                    }
+                }
+                Err(e) => BuildBatchError::CreateTx(e),
+            };
+            first_error.get_or_insert(error);
+        }
+        if payments.is_empty() {
+            return Err(first_error.expect("a payment is pending"));
+        }
+
+        let psbt = builder.set_recipients(recipients).finish()?;
+        let txid = psbt.unsigned_tx.compute_txid();
+        for id in &payments {
+            let mut payment = wallet.payment_queue()[id].clone();
+            payment.txid = Some(txid);
+            wallet.set_queued_payment(id.clone(), Some(payment));
+        }
+
+        Ok(Some(PaymentBatch { psbt, payments }))
+    }
+}
+
+/// Error returned by [`Wallet::enqueue_payment`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnqueuePaymentError {
+    /// A payment with this id is already in the queue.
+    DuplicateId(String),
+    /// The amount of the payment with this id is below the dust limit.
+    BelowDustLimit(String),
+}
+
+impl fmt::Display for EnqueuePaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateId(id) => write!(f, "payment {id} is already queued"),
+            Self::BelowDustLimit(id) => {
+                write!(f, "the amount of payment {id} is below the dust limit")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EnqueuePaymentError {}
+
+/// Error returned by [`PaymentBatchBuilder::finish`] when no pending payment can be batched.
+///
+/// This is the reason the first pending payment was left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildBatchError<K> {
+    /// The transaction would exceed the weight budget.
+    WeightBudgetExceeded,
+    /// The transaction would exceed the fee budget.
+    FeeBudgetExceeded,
+    /// Building the transaction failed.
+    CreateTx(CreateTxError<K>),
+}
+
+impl<K> From<CreateTxError<K>> for BuildBatchError<K> {
+    fn from(e: CreateTxError<K>) -> Self {
+        Self::CreateTx(e)
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for BuildBatchError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WeightBudgetExceeded => write!(f, "the batch exceeds the weight budget"),
+            Self::FeeBudgetExceeded => write!(f, "the batch exceeds the fee budget"),
+            Self::CreateTx(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug> std::error::Error for BuildBatchError<K> {}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+
+    use bitcoin::{hashes::Hash, WPubkeyHash};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    fn recipient(i: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([i; 20]))
+    }
+
+    #[test]
+    fn batch_payments() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        receive_confirmed(&mut wallet, k, Amount::from_sat(30_000));
+        assert!(wallet.build_payment_batch().finish().unwrap().is_none());
+
+        wallet
+            .enqueue_payment("a", recipient(1), Amount::from_sat(10_000))
+            .unwrap();
+        wallet
+            .enqueue_payment("b", recipient(2), Amount::from_sat(200_000))
+            .unwrap();
+        wallet
+            .enqueue_payment("c", recipient(3), Amount::from_sat(20_000))
+            .unwrap();
+        assert_eq!(
+            wallet.enqueue_payment("a", recipient(4), Amount::from_sat(10_000)),
+            Err(EnqueuePaymentError::DuplicateId("a".into()))
+        );
+        assert_eq!(
+            wallet.enqueue_payment("d", recipient(4), Amount::from_sat(1)),
+            Err(EnqueuePaymentError::BelowDustLimit("d".into()))
+        );
+        assert_eq!(
+            wallet
+                .queued_payments()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            ["a", "b", "c"]
+        );
+
+        // The payment the wallet can not fund is skipped.
+        let PaymentBatch { psbt, payments } = wallet
+            .build_payment_batch()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(2))
+            .finish()
+            .unwrap()
+            .unwrap();
+        assert_eq!(payments, ["a", "c"]);
+        let tx = psbt.unsigned_tx;
+        assert_eq!(tx.output[0].script_pubkey, recipient(1));
+        assert_eq!(tx.output[1].script_pubkey, recipient(3));
+        let txid = tx.compute_txid();
+        assert_eq!(
+            wallet.payment_status("a"),
+            Some(PaymentStatus::Batched {
+                txid,
+                tx_status: None
+            })
+        );
+        assert_eq!(wallet.payment_status("b"), Some(PaymentStatus::Pending));
+        assert_eq!(wallet.payment_status("d"), None);
+        assert_eq!(
+            wallet.staged().unwrap().payments["c"]
+                .as_ref()
+                .unwrap()
+                .txid,
+            Some(txid)
+        );
+
+        // The batch is followed through confirmation.
+        wallet.apply_unconfirmed_txs([(Arc::new(tx.clone()), 1)]);
+        assert!(matches!(
+            wallet.payment_status("c"),
+            Some(PaymentStatus::Batched {
+                tx_status: Some(TxStatus::Unconfirmed { .. }),
+                ..
+            })
+        ));
+        let height = add_block(&mut wallet, vec![tx]);
+        assert!(matches!(
+            wallet.payment_status("c"),
+            Some(PaymentStatus::Batched {
+                tx_status: Some(TxStatus::Confirmed { height: h, .. }),
+                ..
+            }) if h == height
+        ));
+
+        assert!(matches!(
+            wallet.build_payment_batch().finish(),
+            Err(BuildBatchError::CreateTx(
+                CreateTxError::InsufficientFunds { .. }
+            ))
+        ));
+        assert!(wallet.remove_payment("b").is_some());
+        assert!(wallet.build_payment_batch().finish().unwrap().is_none());
+    }
+
+    #[test]
+    fn batch_budgets() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(100_000));
+        for i in 0..3 {
+            wallet
+                .enqueue_payment(
+                    alloc::format!("{i}"),
+                    recipient(i),
+                    Amount::from_sat(10_000),
+                )
+                .unwrap();
+        }
+        let fee_rate = FeeRate::from_sat_per_vb_u32(10);
+
+        let input_weight = wallet.input_weight(&k).unwrap();
+        let output_weight = Weight::from_wu(31 * 4);
+        // Room for the change and one payment only.
+        let max_weight = Weight::from_wu(60) + input_weight + output_weight * 2;
+        let batch = wallet
+            .build_payment_batch()
+            .fee_rate(fee_rate)
+            .max_weight(max_weight)
+            .finish()
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.payments, ["0"]);
+
+        assert_eq!(
+            wallet
+                .build_payment_batch()
+                .fee_rate(fee_rate)
+                .max_fee(Amount::from_sat(100))
+                .finish(),
+            Err(BuildBatchError::FeeBudgetExceeded)
+        );
+        let batch = wallet
+            .build_payment_batch()
+            .fee_rate(fee_rate)
+            .max_fee(Amount::from_sat(5_000))
+            .finish()
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.payments, ["1", "2"]);
+        assert!(batch.psbt.fee().unwrap() <= Amount::from_sat(5_000));
+    }
+
+    #[test]
+    fn requeue_payment() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        wallet
+            .enqueue_payment("a", recipient(1), Amount::from_sat(10_000))
+            .unwrap();
+        assert!(!wallet.requeue_payment("a", true));
+
+        // The batch may have been broadcast without the wallet knowing yet.
+        let _ = wallet.build_payment_batch().finish().unwrap().unwrap();
+        assert!(!wallet.requeue_payment("a", false));
+        // The batch is never broadcast.
+        assert!(wallet.requeue_payment("a", true));
+        assert_eq!(wallet.payment_status("a"), Some(PaymentStatus::Pending));
+
+        let batch = wallet.build_payment_batch().finish().unwrap().unwrap();
+        wallet.apply_unconfirmed_txs([(Arc::new(batch.psbt.unsigned_tx), 1)]);
+        assert!(!wallet.requeue_payment("a", true));
+        assert!(!wallet.requeue_payment("b", true));
+    }
+
+    #[test]
+    fn batch_spends_segwit_inputs_only() {
+        // Signing the legacy input would change the txid of the batch.
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], PKH_DESCRIPTOR]);
+        let k_wpkh = descriptor_id(DESCRIPTORS[0]);
+        let k_pkh = descriptor_id(PKH_DESCRIPTOR);
+        receive_confirmed(&mut wallet, k_pkh, Amount::from_sat(100_000));
+        let op_wpkh = receive_confirmed(&mut wallet, k_wpkh, Amount::from_sat(30_000));
+        wallet
+            .enqueue_payment("a", recipient(1), Amount::from_sat(50_000))
+            .unwrap();
+        wallet
+            .enqueue_payment("b", recipient(2), Amount::from_sat(10_000))
+            .unwrap();
+
+        let batch = wallet.build_payment_batch().finish().unwrap().unwrap();
+        assert_eq!(batch.payments, ["b"]);
+        let tx = &batch.psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, op_wpkh);
+        assert!(matches!(
+            wallet.build_payment_batch().finish(),
+            Err(BuildBatchError::CreateTx(
+                CreateTxError::InsufficientFunds { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn follow_replaced_batch() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        wallet
+            .enqueue_payment("a", recipient(1), Amount::from_sat(10_000))
+            .unwrap();
+        let batch = wallet.build_payment_batch().finish().unwrap().unwrap();
+        let tx = batch.psbt.unsigned_tx;
+        let txid = tx.compute_txid();
+        wallet.apply_unconfirmed_txs([(tx, 1)]);
+
+        // A fee bump pays the payment too.
+        let bump = wallet
+            .build_fee_bump(txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(5))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        let bump_txid = bump.compute_txid();
+        wallet.apply_unconfirmed_txs([(bump, 2)]);
+        assert_eq!(
+            wallet.payment_status("a"),
+            Some(PaymentStatus::Batched {
+                txid: bump_txid,
+                tx_status: Some(TxStatus::Unconfirmed { last_seen: Some(2) })
+            })
+        );
+        assert!(!wallet.requeue_payment("a", false));
+
+        // A cancellation does not.
+        let cancel = wallet
+            .build_cancel_tx(bump_txid)
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb_u32(10))
+            .finish()
+            .unwrap()
+            .unsigned_tx;
+        wallet.apply_unconfirmed_txs([(cancel, 3)]);
+        assert_eq!(
+            wallet.payment_status("a"),
+            Some(PaymentStatus::Batched {
+                txid,
+                tx_status: Some(TxStatus::Conflicted)
+            })
+        );
+        assert!(wallet.requeue_payment("a", false));
+        assert_eq!(wallet.payment_status("a"), Some(PaymentStatus::Pending));
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_payment_queue() -> anyhow::Result<()> {
+        use bdk_chain::rusqlite;
+
+        use crate::bdk_chain;
+
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        for (id, i) in [("a", 1), ("b", 2), ("c", 3)] {
+            wallet.enqueue_payment(id, recipient(i), Amount::from_sat(10_000))?;
+        }
+        wallet.persist_to_sqlite(&mut conn)?;
+        let batch = wallet.build_payment_batch().finish()?.unwrap();
+        wallet.remove_payment("c");
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let restored = Wallet::from_sqlite(&mut conn)?.unwrap();
+        let payments = restored
+            .queued_payments()
+            .map(|(id, payment)| (id, payment.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            payments,
+            wallet
+                .queued_payments()
+                .map(|(id, payment)| (id, payment.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            restored.queued_payment("a").unwrap().txid,
+            Some(batch.psbt.unsigned_tx.compute_txid())
+        );
+        let restored = Wallet::from_snapshot(restored.to_snapshot()).unwrap();
+        assert_eq!(restored.queued_payments().count(), 2);
+
+        Ok(())
+    }
+}
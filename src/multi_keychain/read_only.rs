@@ -0,0 +1,216 @@
+//! [`ReadOnlyWallet`].
+
+use core::{fmt, ops::Deref};
+
+#[cfg(feature = "rusqlite")]
+use alloc::string::String;
+#[cfg(feature = "rusqlite")]
+use bdk_chain::{rusqlite, DescriptorId};
+
+#[cfg(feature = "rusqlite")]
+use crate::bdk_chain;
+#[cfg(feature = "rusqlite")]
+use crate::collections::BTreeMap;
+#[cfg(feature = "rusqlite")]
+use crate::multi_keychain::ChangeSet;
+use crate::multi_keychain::Wallet;
+
+/// A [`Wallet`] that can only be read from.
+///
+/// It dereferences to the [`Wallet`] but not mutably, so that only the methods taking `&self`
+/// (balances, history, addresses already revealed, ...) are available.
+#[derive(Debug)]
+pub struct ReadOnlyWallet<K: Ord>(Wallet<K>);
+
+impl<K> Clone for ReadOnlyWallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// The copies share the transaction graph, which can not be mutated.
+    fn clone(&self) -> Self {
+        Self(self.0.checkpoint())
+    }
+}
+
+impl<K: Ord> From<Wallet<K>> for ReadOnlyWallet<K> {
+    fn from(wallet: Wallet<K>) -> Self {
+        Self(wallet)
+    }
+}
+
+impl<K: Ord> Deref for ReadOnlyWallet<K> {
+    type Target = Wallet<K>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl ReadOnlyWallet<DescriptorId> {
+    /// Load a [`ReadOnlyWallet`] from SQLite without writing to the database.
+    ///
+    /// Unlike [`Wallet::from_sqlite`] the tables are not created nor migrated, so this fails with
+    /// [`ReadOnlyLoadError::SchemaVersion`] if the database has not been migrated to the schema
+    /// versions used by this crate. Returns `Ok(None)` if the database holds no wallet.
+    pub fn from_sqlite(conn: &rusqlite::Connection) -> Result<Option<Self>, ReadOnlyLoadError> {
+        // The transaction is only read from and is rolled back when dropped.
+        let db_tx = conn.unchecked_transaction()?;
+
+        let found = schema_versions(&db_tx)?;
+        if found.is_empty() {
+            return Ok(None);
+        }
+        for (schema, expected) in expected_schema_versions()? {
+            let found = found.get(&schema).copied();
+            if found != Some(expected) {
+                return Err(ReadOnlyLoadError::SchemaVersion {
+                    schema,
+                    expected,
+                    found,
+                });
+            }
+        }
+
+        let changeset = ChangeSet::from_sqlite(&db_tx)?;
+        Ok(Wallet::from_changeset(changeset).map(Self))
+    }
+}
+
+/// Read the versions of the schemas present in the database.
+#[cfg(feature = "rusqlite")]
+fn schema_versions(db_tx: &rusqlite::Transaction) -> rusqlite::Result<BTreeMap<String, u32>> {
+    use bdk_chain::rusqlite_impl::SCHEMAS_TABLE_NAME;
+
+    let exists: bool = db_tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [SCHEMAS_TABLE_NAME],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(BTreeMap::new());
+    }
+
+    let mut stmt = db_tx.prepare(&format!("SELECT name, version FROM {SCHEMAS_TABLE_NAME}"))?;
+    let rows = stmt.query_map([], |row| Ok((row.get("name")?, row.get("version")?)))?;
+    rows.collect()
+}
+
+/// The schema versions a database is migrated to by [`ChangeSet::initialize`].
+#[cfg(feature = "rusqlite")]
+fn expected_schema_versions() -> rusqlite::Result<BTreeMap<String, u32>> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    let db_tx = conn.transaction()?;
+    ChangeSet::initialize(&db_tx)?;
+    schema_versions(&db_tx)
+}
+
+/// Error returned by [`ReadOnlyWallet::from_sqlite`].
+#[cfg(feature = "rusqlite")]
+#[derive(Debug)]
+pub enum ReadOnlyLoadError {
+    /// Reading from the database failed.
+    Sqlite(rusqlite::Error),
+    /// A schema of the database is not at the expected version.
+    SchemaVersion {
+        /// Name of the schema.
+        schema: String,
+        /// Version expected by this crate.
+        expected: u32,
+        /// Version found in the database, if any.
+        found: Option<u32>,
+    },
+}
+
+#[cfg(feature = "rusqlite")]
+impl From<rusqlite::Error> for ReadOnlyLoadError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl fmt::Display for ReadOnlyLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sqlite(e) => write!(f, "failed to read the wallet: {e}"),
+            Self::SchemaVersion {
+                schema,
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "schema `{schema}` is at version {found}, expected version {expected}"
+            ),
+            Self::SchemaVersion {
+                schema, expected, ..
+            } => write!(
+                f,
+                "schema `{schema}` is missing, expected version {expected}"
+            ),
+        }
+    }
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl std::error::Error for ReadOnlyLoadError {}
+
+#[cfg(all(test, feature = "rusqlite"))]
+mod test {
+    use bitcoin::Amount;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    #[test]
+    fn load_read_only() -> anyhow::Result<()> {
+        let db_file = NamedTempFile::new()?;
+        let mut conn = rusqlite::Connection::open(db_file.path())?;
+        assert!(ReadOnlyWallet::from_sqlite(&conn)?.is_none());
+
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        receive_confirmed(
+            &mut wallet,
+            descriptor_id(DESCRIPTORS[1]),
+            Amount::from_sat(10_000),
+        );
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let conn = rusqlite::Connection::open_with_flags(
+            db_file.path(),
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?;
+        let read_only = ReadOnlyWallet::from_sqlite(&conn)?.unwrap();
+        assert_eq!(read_only.balance(), wallet.balance());
+        assert_eq!(read_only.to_snapshot(), wallet.to_snapshot());
+
+        Ok(())
+    }
+
+    #[test]
+    fn outdated_schema_is_rejected() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+        new_wallet(&DESCRIPTORS[..1]).persist_to_sqlite(&mut conn)?;
+        conn.execute(
+            "UPDATE bdk_schemas SET version = version - 1 WHERE name = 'bdk_txgraph'",
+            (),
+        )?;
+
+        match ReadOnlyWallet::from_sqlite(&conn) {
+            Err(ReadOnlyLoadError::SchemaVersion {
+                schema,
+                expected,
+                found,
+            }) => {
+                assert_eq!(schema, "bdk_txgraph");
+                assert_eq!(found, Some(expected - 1));
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+//! Full wallet state [`Snapshot`]s.
+
+use core::fmt;
+
+use bdk_chain::{collections::BTreeSet, DescriptorExt, Merge};
+use bitcoin::constants::genesis_block;
+use serde::{Deserialize, Serialize};
+
+use crate::bdk_chain;
+use crate::multi_keychain::{ChangeSet, SpendingPriority, Wallet};
+
+/// Version of the [`Snapshot`] format produced by [`Wallet::to_snapshot`].
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A versioned dump of the entire state of a [`Wallet`].
+///
+/// The `changeset` is compacted, i.e. it is built from the current wallet state rather than from
+/// the history of changes applied to it, so it holds the least data needed to reconstruct the
+/// wallet with [`Wallet::from_snapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot<K: Ord> {
+    /// Format version, see [`SNAPSHOT_VERSION`].
+    pub version: u32,
+    /// Compacted wallet changeset.
+    pub changeset: ChangeSet<K>,
+}
+
+/// Error returned by [`Wallet::from_snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The snapshot was produced by an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The snapshot is missing the genesis block of the local chain.
+    MissingGenesis,
+    /// The snapshot is missing the network, the default keychain of the keyring or its
+    /// descriptor.
+    MissingKeyRing,
+    /// The genesis block of the snapshot does not belong to the network of its keyring.
+    NetworkMismatch,
+    /// A descriptor of the snapshot is multipath or cannot be derived.
+    InvalidDescriptor,
+    /// The same descriptor is assigned to several keychains of the snapshot.
+    DuplicateDescriptor,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported snapshot version {v}, expected {SNAPSHOT_VERSION}"
+            ),
+            Self::MissingGenesis => write!(f, "snapshot is missing the genesis block"),
+            Self::MissingKeyRing => write!(f, "snapshot is missing the keyring"),
+            Self::NetworkMismatch => {
+                write!(f, "snapshot genesis block does not match its network")
+            }
+            Self::InvalidDescriptor => write!(f, "snapshot has an invalid descriptor"),
+            Self::DuplicateDescriptor => {
+                write!(f, "snapshot assigns a descriptor to several keychains")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SnapshotError {}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Export the complete state of the wallet as a [`Snapshot`].
+    ///
+    /// This includes the keyring, local chain, transaction graph and indexer, regardless of
+    /// whether the changes have been persisted yet. The staged changes are left untouched.
+    pub fn to_snapshot(&self) -> Snapshot<K> {
+        let mut changeset = ChangeSet {
+            keyring: self.keyring_changeset(),
+            local_chain: self.local_chain().initial_changeset(),
+            marked_used: self.marked_used(),
+            utxo_locks: self.utxo_locks(),
+            payments: self
+                .payment_queue()
+                .iter()
+                .map(|(id, payment)| (id.clone(), Some(payment.clone())))
+                .collect(),
+            trusted_keychains: self.trusted_keychains().map(|k| (k, true)).collect(),
+            spending_priority: Some(self.spending_priority().clone())
+                .filter(|priority| *priority != SpendingPriority::default()),
+            ..Default::default()
+        };
+        changeset.merge(self.tx_graph().initial_changeset().into());
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            changeset,
+        }
+    }
+
+    /// Construct a [`Wallet`] from a [`Snapshot`] obtained with [`Wallet::to_snapshot`].
+    ///
+    /// The returned wallet has nothing staged. The snapshot is validated first, so that a corrupt
+    /// snapshot results in a [`SnapshotError`].
+    pub fn from_snapshot(snapshot: Snapshot<K>) -> Result<Self, SnapshotError> {
+        let Snapshot { version, changeset } = snapshot;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        validate(&changeset)?;
+        Self::from_changeset(changeset).ok_or(SnapshotError::MissingKeyRing)
+    }
+}
+
+/// Check that [`Wallet::from_changeset`] can load the `changeset` of a snapshot.
+fn validate<K: Ord>(changeset: &ChangeSet<K>) -> Result<(), SnapshotError> {
+    let genesis_hash = changeset
+        .local_chain
+        .blocks
+        .get(&0)
+        .copied()
+        .flatten()
+        .ok_or(SnapshotError::MissingGenesis)?;
+
+    let keyring = &changeset.keyring;
+    let network = keyring.network.ok_or(SnapshotError::MissingKeyRing)?;
+    if genesis_hash != genesis_block(network).block_hash() {
+        return Err(SnapshotError::NetworkMismatch);
+    }
+    let default_keychain = keyring
+        .default_keychain
+        .as_ref()
+        .ok_or(SnapshotError::MissingKeyRing)?;
+    if !keyring.descriptors.contains_key(default_keychain) {
+        return Err(SnapshotError::MissingKeyRing);
+    }
+
+    let mut descriptor_ids = BTreeSet::new();
+    for descriptor in keyring.descriptors.values() {
+        if descriptor.is_multipath() || descriptor.at_derivation_index(0).is_err() {
+            return Err(SnapshotError::InvalidDescriptor);
+        }
+        if !descriptor_ids.insert(descriptor.descriptor_id()) {
+            return Err(SnapshotError::DuplicateDescriptor);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use bitcoin::Amount;
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    #[test]
+    fn snapshot_round_trip() -> anyhow::Result<()> {
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k2 = descriptor_id(DESCRIPTORS[2]);
+        let op = receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        receive_unconfirmed(&mut wallet, k2, Amount::from_sat(5_000), 100);
+        let _ = wallet.reveal_next_address(k2);
+        wallet.lock_outpoint(op, Some(100));
+
+        let snapshot = wallet.to_snapshot();
+        let json = serde_json::to_string(&snapshot)?;
+        let restored = Wallet::from_snapshot(serde_json::from_str(&json)?)?;
+
+        assert!(restored.staged().is_none());
+        assert_eq!(restored.to_snapshot(), snapshot);
+        assert_eq!(restored.balance(), wallet.balance());
+        assert_eq!(restored.default_keychain(), k0);
+        assert_eq!(
+            restored.keychains().collect::<Vec<_>>(),
+            wallet.keychains().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            restored.txout_index().last_revealed_indices(),
+            wallet.txout_index().last_revealed_indices()
+        );
+        assert_eq!(restored.latest_checkpoint(), wallet.latest_checkpoint());
+        assert_eq!(
+            restored.locked_outpoints().collect::<Vec<_>>(),
+            wallet.locked_outpoints().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_is_compacted() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        for _ in 0..3 {
+            let _ = wallet.reveal_next_address(k0);
+        }
+
+        // Only the latest revealed index and the current chain are kept.
+        let snapshot = wallet.to_snapshot();
+        assert_eq!(snapshot.changeset.indexer.last_revealed.get(&k0), Some(&2));
+        assert_eq!(snapshot.changeset.local_chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn snapshot_version_is_checked() {
+        let wallet = new_wallet(&DESCRIPTORS[..1]);
+        let mut snapshot = wallet.to_snapshot();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            Wallet::from_snapshot(snapshot).unwrap_err(),
+            SnapshotError::UnsupportedVersion(SNAPSHOT_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn corrupt_snapshot_is_rejected() {
+        let wallet = new_wallet(&DESCRIPTORS[..2]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let snapshot = wallet.to_snapshot();
+
+        let mut duplicate = snapshot.clone();
+        let descriptor = duplicate.changeset.keyring.descriptors[&k0].clone();
+        duplicate
+            .changeset
+            .keyring
+            .descriptors
+            .insert(k1, descriptor);
+        assert_eq!(
+            Wallet::from_snapshot(duplicate).unwrap_err(),
+            SnapshotError::DuplicateDescriptor
+        );
+
+        let mut mainnet = snapshot.clone();
+        mainnet.changeset.keyring.network = Some(bitcoin::Network::Bitcoin);
+        assert_eq!(
+            Wallet::from_snapshot(mainnet).unwrap_err(),
+            SnapshotError::NetworkMismatch
+        );
+
+        let mut no_genesis = snapshot.clone();
+        no_genesis.changeset.local_chain.blocks.insert(0, None);
+        assert_eq!(
+            Wallet::from_snapshot(no_genesis).unwrap_err(),
+            SnapshotError::MissingGenesis
+        );
+
+        let mut no_default = snapshot;
+        no_default.changeset.keyring.descriptors.remove(&k0);
+        assert_eq!(
+            Wallet::from_snapshot(no_default).unwrap_err(),
+            SnapshotError::MissingKeyRing
+        );
+    }
+}
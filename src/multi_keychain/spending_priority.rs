@@ -0,0 +1,197 @@
+//! [`SpendingPriority`] of the keychains of a [`Wallet`](crate::multi_keychain::Wallet).
+
+use alloc::vec::Vec;
+
+use bitcoin::FeeRate;
+use miniscript::descriptor::DescriptorType;
+use serde::{Deserialize, Serialize};
+
+/// Names of the descriptor types, as they are persisted.
+const DESCRIPTOR_TYPE_NAMES: [(DescriptorType, &str); 11] = [
+    (DescriptorType::Bare, "bare"),
+    (DescriptorType::Sh, "sh"),
+    (DescriptorType::Pkh, "pkh"),
+    (DescriptorType::Wpkh, "wpkh"),
+    (DescriptorType::Wsh, "wsh"),
+    (DescriptorType::ShWsh, "sh-wsh"),
+    (DescriptorType::ShWpkh, "sh-wpkh"),
+    (DescriptorType::ShSortedMulti, "sh-sortedmulti"),
+    (DescriptorType::WshSortedMulti, "wsh-sortedmulti"),
+    (DescriptorType::ShWshSortedMulti, "sh-wsh-sortedmulti"),
+    (DescriptorType::Tr, "tr"),
+];
+
+/// The persisted name of `descriptor_type`.
+pub(crate) fn descriptor_type_name(descriptor_type: DescriptorType) -> &'static str {
+    DESCRIPTOR_TYPE_NAMES
+        .iter()
+        .find(|(t, _)| *t == descriptor_type)
+        .map(|(_, name)| *name)
+        .expect("every descriptor type is named")
+}
+
+/// The descriptor type persisted as `name`, if any.
+pub(crate) fn descriptor_type_from_name(name: &str) -> Option<DescriptorType> {
+    DESCRIPTOR_TYPE_NAMES
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(t, _)| *t)
+}
+
+/// (De)serialization of a [`DescriptorType`] by name.
+mod descriptor_type_serde {
+    use alloc::string::String;
+
+    use miniscript::descriptor::DescriptorType;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(t: &DescriptorType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(super::descriptor_type_name(*t))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DescriptorType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        super::descriptor_type_from_name(&name)
+            .ok_or_else(|| D::Error::custom(format_args!("unknown descriptor type `{name}`")))
+    }
+}
+
+/// Keychains preferred by a [`SpendingPriority`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Preference<K> {
+    /// The given keychain.
+    Keychain(K),
+    /// Every keychain whose descriptor is of the given type, e.g. [`DescriptorType::Tr`].
+    DescriptorType(#[serde(with = "descriptor_type_serde")] DescriptorType),
+}
+
+impl<K: PartialEq> Preference<K> {
+    fn matches(&self, keychain: &K, descriptor_type: DescriptorType) -> bool {
+        match self {
+            Self::Keychain(k) => k == keychain,
+            Self::DescriptorType(t) => *t == descriptor_type,
+        }
+    }
+}
+
+/// Order in which the keychains of a wallet are spent from, depending on the fee rate.
+///
+/// Each [`Preference`] of the applicable order makes a tier. When building a transaction the
+/// outputs of the first tier are selected from first, and those of the next tiers are only added
+/// if the previous ones can not fund it. Keychains matching no preference make the last tier.
+///
+/// The applicable order is the one of the rule with the lowest maximum fee rate at least the fee
+/// rate of the transaction, or the default order if there is none. For example, a legacy
+/// keychain can be drained while fees are low by preferring [`DescriptorType::Pkh`] up to a low
+/// fee rate, and taproot be preferred otherwise with a default order starting with
+/// [`DescriptorType::Tr`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpendingPriority<K> {
+    default: Vec<Preference<K>>,
+    /// Orders by maximum fee rate, in ascending order.
+    rules: Vec<(FeeRate, Vec<Preference<K>>)>,
+}
+
+impl<K> Default for SpendingPriority<K> {
+    fn default() -> Self {
+        Self {
+            default: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl<K: PartialEq> SpendingPriority<K> {
+    /// Spend in the given `order` by default.
+    pub fn new(order: impl IntoIterator<Item = Preference<K>>) -> Self {
+        Self {
+            default: order.into_iter().collect(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Spend in the given `order` when the fee rate is at most `max_fee_rate`, replacing any rule
+    /// with the same maximum fee rate.
+    pub fn up_to_fee_rate(
+        mut self,
+        max_fee_rate: FeeRate,
+        order: impl IntoIterator<Item = Preference<K>>,
+    ) -> Self {
+        let order = order.into_iter().collect();
+        match self
+            .rules
+            .binary_search_by_key(&max_fee_rate, |(rate, _)| *rate)
+        {
+            Ok(i) => self.rules[i].1 = order,
+            Err(i) => self.rules.insert(i, (max_fee_rate, order)),
+        }
+        self
+    }
+
+    /// The order applying at `fee_rate`, or the default order if the fee rate is not known.
+    pub fn order(&self, fee_rate: Option<FeeRate>) -> &[Preference<K>] {
+        fee_rate
+            .and_then(|fee_rate| {
+                self.rules
+                    .iter()
+                    .find(|(max_fee_rate, _)| fee_rate <= *max_fee_rate)
+            })
+            .map_or(&self.default, |(_, order)| order)
+    }
+
+    /// The rules of the priority as `(max_fee_rate, order)`, by ascending maximum fee rate.
+    pub fn rules(&self) -> impl Iterator<Item = (FeeRate, &[Preference<K>])> {
+        self.rules
+            .iter()
+            .map(|(max_fee_rate, order)| (*max_fee_rate, order.as_slice()))
+    }
+
+    /// The tier of `keychain` at `fee_rate`, lower tiers being spent first.
+    pub(crate) fn tier(
+        &self,
+        fee_rate: Option<FeeRate>,
+        keychain: &K,
+        descriptor_type: DescriptorType,
+    ) -> usize {
+        let order = self.order(fee_rate);
+        order
+            .iter()
+            .position(|p| p.matches(keychain, descriptor_type))
+            .unwrap_or(order.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order_by_fee_rate() {
+        let low = FeeRate::from_sat_per_vb_u32(5);
+        let high = FeeRate::from_sat_per_vb_u32(50);
+        let priority = SpendingPriority::new([Preference::DescriptorType(DescriptorType::Tr)])
+            .up_to_fee_rate(high, [Preference::Keychain(1)])
+            .up_to_fee_rate(low, [Preference::DescriptorType(DescriptorType::Pkh)]);
+
+        assert_eq!(
+            priority.order(Some(FeeRate::from_sat_per_vb_u32(1))),
+            [Preference::DescriptorType(DescriptorType::Pkh)]
+        );
+        assert_eq!(
+            priority.order(Some(low)),
+            priority.order(Some(FeeRate::BROADCAST_MIN))
+        );
+        assert_eq!(priority.order(Some(high)), [Preference::Keychain(1)]);
+        assert_eq!(
+            priority.order(Some(FeeRate::from_sat_per_vb_u32(51))),
+            [Preference::DescriptorType(DescriptorType::Tr)]
+        );
+        assert_eq!(priority.order(None), priority.order(Some(FeeRate::MAX)));
+
+        assert_eq!(priority.tier(None, &0, DescriptorType::Tr), 0);
+        assert_eq!(priority.tier(None, &0, DescriptorType::Wpkh), 1);
+        assert_eq!(priority.tier(Some(high), &1, DescriptorType::Wpkh), 0);
+    }
+}
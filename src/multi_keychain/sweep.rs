@@ -0,0 +1,367 @@
+//! Sweeping outputs locked by external private keys into the [`Wallet`], see [`Wallet::sweep`].
+
+use alloc::{format, vec, vec::Vec};
+use core::fmt;
+
+use bdk_chain::collections::BTreeMap;
+use bdk_wallet::descriptor::{DescriptorError, IntoWalletDescriptor};
+use bitcoin::{
+    absolute, key::FromWifError, psbt::SignError, transaction, Amount, FeeRate, OutPoint,
+    PrivateKey, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+};
+use miniscript::{
+    descriptor::{KeyMap, KeyMapWrapper},
+    psbt::{PsbtExt, PsbtInputExt},
+    Descriptor, DescriptorPublicKey,
+};
+
+use crate::bdk_chain;
+use crate::multi_keychain::{
+    fee_for_weight, AntiFeeSniping, CreateTxError, DrainTx, FeePolicy, Wallet,
+    SEGWIT_MARKER_WEIGHT, TXIN_BASE_WEIGHT,
+};
+
+/// Number of derivation indices of a ranged descriptor searched for the outputs to sweep.
+pub const SWEEP_DERIVATION_LIMIT: u32 = 1_000;
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Build and sign a transaction spending the `utxos`, locked by the private `descriptor`, to
+    /// a newly revealed address of the default keychain, paying `fee_rate`.
+    ///
+    /// The outputs of a ranged descriptor are looked up among its first
+    /// [`SWEEP_DERIVATION_LIMIT`] derivation indices. The descriptor and its keys are only used to
+    /// sign the transaction, they are not added to the wallet.
+    ///
+    /// Discourage fee sniping with `anti_fee_sniping`, see
+    /// [`TxBuilder::anti_fee_sniping`](crate::multi_keychain::TxBuilder::anti_fee_sniping).
+    pub fn sweep(
+        &mut self,
+        descriptor: impl IntoWalletDescriptor,
+        utxos: impl IntoIterator<Item = (OutPoint, TxOut)>,
+        fee_rate: FeeRate,
+        anti_fee_sniping: AntiFeeSniping,
+    ) -> Result<DrainTx, SweepError<K>> {
+        let descriptor = descriptor
+            .into_wallet_descriptor(self.secp_ctx(), self.network())
+            .map_err(SweepError::Descriptor)?;
+        self.sweep_descriptors(vec![descriptor], utxos, fee_rate, anti_fee_sniping)
+    }
+
+    /// Like [`Wallet::sweep`] with the private key `wif`, whose outputs may be of any standard
+    /// single key script type.
+    pub fn sweep_wif(
+        &mut self,
+        wif: &str,
+        utxos: impl IntoIterator<Item = (OutPoint, TxOut)>,
+        fee_rate: FeeRate,
+        anti_fee_sniping: AntiFeeSniping,
+    ) -> Result<DrainTx, SweepError<K>> {
+        let key = PrivateKey::from_wif(wif).map_err(SweepError::Wif)?;
+        let strings = if key.compressed {
+            vec![
+                format!("pkh({wif})"),
+                format!("wpkh({wif})"),
+                format!("sh(wpkh({wif}))"),
+                format!("tr({wif})"),
+            ]
+        } else {
+            vec![format!("pkh({wif})")]
+        };
+        let mut descriptors = Vec::new();
+        for string in strings {
+            let descriptor = string
+                .as_str()
+                .into_wallet_descriptor(self.secp_ctx(), self.network())
+                .map_err(SweepError::Descriptor)?;
+            descriptors.push(descriptor);
+        }
+        self.sweep_descriptors(descriptors, utxos, fee_rate, anti_fee_sniping)
+    }
+
+    fn sweep_descriptors(
+        &mut self,
+        descriptors: Vec<(Descriptor<DescriptorPublicKey>, KeyMap)>,
+        utxos: impl IntoIterator<Item = (OutPoint, TxOut)>,
+        fee_rate: FeeRate,
+        anti_fee_sniping: AntiFeeSniping,
+    ) -> Result<DrainTx, SweepError<K>> {
+        FeePolicy::FeeRate(fee_rate).check()?;
+        if descriptors.iter().any(|(_, keymap)| keymap.is_empty()) {
+            return Err(SweepError::MissingPrivateKey);
+        }
+        let utxos = utxos.into_iter().collect::<Vec<_>>();
+        if utxos.is_empty() {
+            return Err(SweepError::NoUtxos);
+        }
+
+        // The descriptor and derivation index of each script pubkey.
+        let mut spks = BTreeMap::<ScriptBuf, (usize, u32)>::new();
+        for (i, (descriptor, _)) in descriptors.iter().enumerate() {
+            let end = if descriptor.has_wildcard() {
+                SWEEP_DERIVATION_LIMIT
+            } else {
+                1
+            };
+            for index in 0..end {
+                let spk = descriptor
+                    .at_derivation_index(index)
+                    .expect("derivation index is not hardened")
+                    .script_pubkey();
+                spks.entry(spk).or_insert((i, index));
+            }
+        }
+
+        let keychain = self.default_keychain();
+        let (change_index, script_pubkey) = self
+            .peek_next_change(&keychain)
+            .ok_or_else(|| CreateTxError::UnknownKeychain(keychain.clone()))?;
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey,
+            }],
+        };
+        let mut weight = tx.weight() + SEGWIT_MARKER_WEIGHT;
+        let mut definite_descriptors = Vec::new();
+        for (outpoint, txout) in &utxos {
+            let (i, index) = *spks
+                .get(&txout.script_pubkey)
+                .ok_or(SweepError::UnknownUtxo(*outpoint))?;
+            let descriptor = descriptors[i]
+                .0
+                .at_derivation_index(index)
+                .expect("derivation index is not hardened");
+            weight += TXIN_BASE_WEIGHT
+                + descriptor
+                    .max_weight_to_satisfy()
+                    .map_err(|_| SweepError::UnsatisfiableDescriptor)?;
+            definite_descriptors.push(descriptor);
+            tx.input.push(TxIn {
+                previous_output: *outpoint,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            });
+        }
+
+        let available = utxos.iter().map(|(_, txout)| txout.value).sum::<Amount>();
+        let fee = fee_for_weight(fee_rate, weight);
+        let needed = fee + tx.output[0].script_pubkey.minimal_non_dust();
+        if available < needed {
+            return Err(CreateTxError::InsufficientFunds { needed, available }.into());
+        }
+        let amount = available - fee;
+        tx.output[0].value = amount;
+        // The confirmation heights of the swept outputs are unknown.
+        let inputs = definite_descriptors
+            .iter()
+            .map(|descriptor| (descriptor.desc_type(), None))
+            .collect::<Vec<_>>();
+        self.discourage_fee_sniping(&mut tx, &inputs, anti_fee_sniping);
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect("transaction is unsigned");
+        for ((psbt_input, (_, txout)), descriptor) in psbt
+            .inputs
+            .iter_mut()
+            .zip(&utxos)
+            .zip(&definite_descriptors)
+        {
+            psbt_input
+                .update_with_descriptor_unchecked(descriptor)
+                .expect("derivation index is not hardened");
+            // Signing only needs the spent output, even for legacy inputs.
+            psbt_input.witness_utxo = Some(txout.clone());
+        }
+        self.update_psbt(&mut psbt, &[]);
+
+        let secp = self.secp_ctx();
+        let keymap = descriptors
+            .into_iter()
+            .flat_map(|(_, keymap)| keymap)
+            .collect::<KeyMap>();
+        psbt.sign(&KeyMapWrapper::from(keymap), secp)
+            .map_err(|(_, errors)| match errors.into_iter().next() {
+                Some((_, e)) => SweepError::Sign(e),
+                None => SweepError::SigningFailed,
+            })?;
+        psbt.finalize_mut(secp)
+            .map_err(|_| SweepError::UnsatisfiableDescriptor)?;
+
+        self.reveal_change(keychain, change_index);
+
+        Ok(DrainTx { psbt, amount, fee })
+    }
+}
+
+/// Error returned by [`Wallet::sweep`] and [`Wallet::sweep_wif`].
+#[derive(Debug, PartialEq)]
+pub enum SweepError<K> {
+    /// The descriptor is invalid.
+    Descriptor(DescriptorError),
+    /// The WIF private key is invalid.
+    Wif(FromWifError),
+    /// The descriptor has no private key.
+    MissingPrivateKey,
+    /// No output to sweep was given.
+    NoUtxos,
+    /// The output is not locked by the descriptor.
+    UnknownUtxo(OutPoint),
+    /// The descriptor can not be satisfied with its private keys.
+    UnsatisfiableDescriptor,
+    /// Signing an input of the transaction failed.
+    Sign(SignError),
+    /// Signing the transaction failed without an error for any input.
+    SigningFailed,
+    /// Building the transaction failed.
+    CreateTx(CreateTxError<K>),
+}
+
+impl<K> From<CreateTxError<K>> for SweepError<K> {
+    fn from(e: CreateTxError<K>) -> Self {
+        Self::CreateTx(e)
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for SweepError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Descriptor(e) => write!(f, "invalid descriptor: {e}"),
+            Self::Wif(e) => write!(f, "invalid WIF private key: {e}"),
+            Self::MissingPrivateKey => write!(f, "the descriptor has no private key"),
+            Self::NoUtxos => write!(f, "no output to sweep"),
+            Self::UnknownUtxo(outpoint) => {
+                write!(f, "output {outpoint} is not locked by the descriptor")
+            }
+            Self::UnsatisfiableDescriptor => {
+                write!(
+                    f,
+                    "the descriptor can not be satisfied with its private keys"
+                )
+            }
+            Self::Sign(e) => write!(f, "failed to sign: {e}"),
+            Self::SigningFailed => write!(f, "failed to sign"),
+            Self::CreateTx(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug> std::error::Error for SweepError<K> {}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, secp256k1::Secp256k1, Txid};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    const WIF: &str = "cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW";
+    const TPRV: &str = "tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS";
+
+    /// An output of `value` locked by `descriptor` at derivation `index`.
+    fn utxo(descriptor: &str, index: u32, value: u64) -> (OutPoint, TxOut) {
+        let (descriptor, _) = descriptor
+            .into_wallet_descriptor(&Secp256k1::new(), bitcoin::Network::Signet)
+            .unwrap();
+        let script_pubkey = descriptor
+            .at_derivation_index(index)
+            .unwrap()
+            .script_pubkey();
+        let outpoint = OutPoint::new(
+            Txid::from_byte_array([index as u8 + 1; 32]),
+            value as u32 % 7,
+        );
+        (
+            outpoint,
+            TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            },
+        )
+    }
+
+    #[test]
+    fn sweep_wif() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let keychains = wallet.keychains().count();
+        let utxos = [
+            utxo(&format!("pkh({WIF})"), 0, 20_000),
+            utxo(&format!("tr({WIF})"), 0, 30_000),
+            utxo(&format!("sh(wpkh({WIF}))"), 0, 40_000),
+        ];
+        let fee_rate = FeeRate::from_sat_per_vb_u32(2);
+
+        let DrainTx { psbt, amount, fee } = wallet
+            .sweep_wif(WIF, utxos.clone(), fee_rate, AntiFeeSniping::Disabled)
+            .unwrap();
+        assert_eq!(amount + fee, Amount::from_sat(90_000));
+        let tx = psbt.extract_tx().unwrap();
+        assert_eq!(tx.lock_time, absolute::LockTime::ZERO);
+        assert!(fee >= fee_for_weight(fee_rate, tx.weight()));
+        assert!(tx
+            .input
+            .iter()
+            .all(|txin| !txin.script_sig.is_empty() || !txin.witness.is_empty()));
+        assert_eq!(
+            wallet
+                .txout_index()
+                .index_of_spk(tx.output[0].script_pubkey.clone()),
+            Some(&(descriptor_id(DESCRIPTORS[0]), 0))
+        );
+        // The foreign key is not added.
+        assert_eq!(wallet.keychains().count(), keychains);
+    }
+
+    #[test]
+    fn sweep_descriptor() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let descriptor = format!("wpkh({TPRV}/84'/1'/0'/0/*)");
+        let utxos = [utxo(&descriptor, 3, 50_000), utxo(&descriptor, 7, 10_000)];
+
+        let drain = wallet
+            .sweep(
+                descriptor.as_str(),
+                utxos.clone(),
+                FeeRate::BROADCAST_MIN,
+                AntiFeeSniping::default(),
+            )
+            .unwrap();
+        let tx = drain.psbt.extract_tx().unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx.input.iter().all(|txin| txin.witness.len() == 2));
+
+        let foreign = utxo(&format!("wpkh({WIF})"), 0, 10_000);
+        assert_eq!(
+            wallet.sweep(
+                descriptor.as_str(),
+                [foreign.clone()],
+                FeeRate::BROADCAST_MIN,
+                AntiFeeSniping::default(),
+            ),
+            Err(SweepError::UnknownUtxo(foreign.0))
+        );
+        assert_eq!(
+            wallet.sweep(
+                DESCRIPTORS[1],
+                utxos,
+                FeeRate::BROADCAST_MIN,
+                AntiFeeSniping::default(),
+            ),
+            Err(SweepError::MissingPrivateKey)
+        );
+        assert!(matches!(
+            wallet.sweep_wif(
+                "not a key",
+                [foreign],
+                FeeRate::BROADCAST_MIN,
+                AntiFeeSniping::default(),
+            ),
+            Err(SweepError::Wif(_))
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+//! Helpers shared by the unit tests of this module.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use bitcoin::{
+    absolute, block,
+    hashes::{sha256d, Hash},
+    transaction, Amount, Block, CompactTarget, OutPoint, Transaction, TxIn, TxMerkleNode, TxOut,
+    Txid,
+};
+use miniscript::Descriptor;
+
+use crate::bdk_chain::{DescriptorExt, DescriptorId};
+use crate::multi_keychain::{KeyRing, Wallet};
+
+pub(crate) const DESCRIPTORS: [&str; 6] = ["wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/0/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/1/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/2/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/3/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/4/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/5/*)"];
+
+/// A taproot descriptor, to mix script types in a wallet.
+pub(crate) const TR_DESCRIPTOR: &str = "tr(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/6/*)";
+
+/// A legacy descriptor, to mix script types in a wallet.
+pub(crate) const PKH_DESCRIPTOR: &str = "pkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/7/*)";
+
+pub(crate) fn descriptor_id(s: &str) -> DescriptorId {
+    let desc = Descriptor::parse_descriptor(&bitcoin::secp256k1::Secp256k1::new(), s)
+        .expect("failed to parse descriptor")
+        .0;
+    desc.descriptor_id()
+}
+
+/// Create a signet wallet with one keychain per descriptor, the first one being the default.
+pub(crate) fn new_wallet(descriptors: &[&str]) -> Wallet<DescriptorId> {
+    let mut keyring = KeyRing::new(
+        bitcoin::Network::Signet,
+        descriptor_id(descriptors[0]),
+        descriptors[0],
+    );
+    for desc in &descriptors[1..] {
+        keyring.add_descriptor(descriptor_id(desc), *desc, false);
+    }
+    Wallet::new(keyring)
+}
+
+/// Build a transaction spending a made-up (but unique) outpoint and paying `value` to the next address revealed
+/// on `keychain`.
+pub(crate) fn receive_tx(
+    wallet: &mut Wallet<DescriptorId>,
+    keychain: DescriptorId,
+    value: Amount,
+) -> Transaction {
+    let addr = wallet
+        .reveal_next_address(keychain)
+        .expect("keychain must exist");
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(
+                Txid::from_raw_hash(sha256d::Hash::hash(addr.script_pubkey().as_bytes())),
+                0,
+            ),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: addr.script_pubkey(),
+        }],
+    }
+}
+
+/// Connect a block containing `txdata` on top of the wallet's tip, its time being its height.
+/// Returns the new height.
+pub(crate) fn add_block(wallet: &mut Wallet<DescriptorId>, txdata: Vec<Transaction>) -> u32 {
+    let height = wallet.latest_checkpoint().height() + 1;
+    add_block_at(wallet, txdata, height)
+}
+
+/// Like [`add_block`] with a block of the given `time`.
+pub(crate) fn add_block_at(
+    wallet: &mut Wallet<DescriptorId>,
+    txdata: Vec<Transaction>,
+    time: u32,
+) -> u32 {
+    let tip = wallet.latest_checkpoint();
+    let height = tip.height() + 1;
+    let block = Block {
+        header: block::Header {
+            version: block::Version::ONE,
+            prev_blockhash: tip.hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 0,
+        },
+        txdata,
+    };
+    wallet
+        .apply_block(&block, height)
+        .expect("block must connect");
+    height
+}
+
+/// Receive `value` on `keychain` in a newly mined block.
+pub(crate) fn receive_confirmed(
+    wallet: &mut Wallet<DescriptorId>,
+    keychain: DescriptorId,
+    value: Amount,
+) -> OutPoint {
+    let tx = receive_tx(wallet, keychain, value);
+    let outpoint = OutPoint::new(tx.compute_txid(), 0);
+    add_block(wallet, vec![tx]);
+    outpoint
+}
+
+/// Receive `value` on `keychain` in an unconfirmed transaction last seen at `last_seen`.
+pub(crate) fn receive_unconfirmed(
+    wallet: &mut Wallet<DescriptorId>,
+    keychain: DescriptorId,
+    value: Amount,
+    last_seen: u64,
+) -> OutPoint {
+    let tx = receive_tx(wallet, keychain, value);
+    let outpoint = OutPoint::new(tx.compute_txid(), 0);
+    wallet.apply_unconfirmed_txs([(Arc::new(tx), last_seen)]);
+    outpoint
+}
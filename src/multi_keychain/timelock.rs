@@ -0,0 +1,509 @@
+//! Timelocks of the spending paths of the outputs of the [`Wallet`], see
+//! [`Wallet::set_keychain_assets`].
+
+use alloc::vec;
+use core::fmt;
+
+use bdk_chain::{ChainPosition, ConfirmationBlockTime};
+use bitcoin::{absolute, relative, taproot::TapLeafHash, Amount};
+use miniscript::{
+    descriptor::DescriptorType,
+    plan::{AssetProvider, Plan},
+    policy::Liftable,
+    DefiniteDescriptorKey,
+};
+
+use crate::bdk_chain;
+use crate::multi_keychain::Wallet;
+
+/// Size of a Schnorr signature with the default sighash type.
+const SCHNORR_SIG_SIZE: usize = 64;
+
+/// The earliest point at which an output of the [`Wallet`] can be spent through its spending
+/// path, see [`LocalOutput::spendable_from`](crate::multi_keychain::LocalOutput::spendable_from).
+///
+/// Relative timelocks of unconfirmed outputs are counted as if they confirmed in the next block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpendableFrom {
+    /// Height of the first block that can include a transaction spending the output, if
+    /// timelocked in blocks.
+    pub height: Option<u32>,
+    /// Earliest block time at which a transaction spending the output can be mined, if
+    /// timelocked in time.
+    pub time: Option<u64>,
+}
+
+/// Balance of the [`Wallet`] split by whether the spending paths of its outputs are timelocked,
+/// see [`Wallet::timelocked_balance`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimelockedBalance {
+    /// Confirmed and trusted pending amount that can be spent now.
+    pub spendable: Amount,
+    /// Confirmed and trusted pending amount whose spending path is still timelocked.
+    pub timelocked: Amount,
+}
+
+/// Expected number of seconds between blocks, to compare timelocks in blocks and in time.
+const BLOCK_INTERVAL: u64 = 600;
+
+/// The earliest block a transaction built at the tip of the local chain can be mined in, against
+/// which the timelocks of the spending paths are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NextBlock {
+    /// Height of the block, one above the tip.
+    pub height: u32,
+    /// Time timelocks in time are checked against.
+    ///
+    /// This is an approximation: consensus checks them against the median time past of the tip,
+    /// which the local chain does not hold, so the time of the latest block of the local chain
+    /// confirming a transaction of the wallet is used instead, see
+    /// [`Wallet::latest_block_time`]. Timelocks in time may be found reached a few blocks early
+    /// or late.
+    pub time: u64,
+}
+
+impl NextBlock {
+    /// Whether a transaction spending an output at `from` can be mined in this block.
+    pub fn is_reached(&self, from: SpendableFrom) -> bool {
+        from.height.map_or(true, |height| self.height >= height)
+            && from.time.map_or(true, |time| self.time >= time)
+    }
+}
+
+/// Assumes every key signs, and that the timelocks are checked in a block at `height` and
+/// `time`, to find the cheapest spending path of a descriptor available in that block.
+struct AllKeysAt {
+    height: u32,
+    time: u64,
+    /// Height and time from which relative timelocks count.
+    created: (u32, u64),
+}
+
+impl AssetProvider<DefiniteDescriptorKey> for AllKeysAt {
+    fn provider_lookup_ecdsa_sig(&self, _: &DefiniteDescriptorKey) -> bool {
+        true
+    }
+
+    fn provider_lookup_tap_key_spend_sig(&self, _: &DefiniteDescriptorKey) -> Option<usize> {
+        Some(SCHNORR_SIG_SIZE)
+    }
+
+    fn provider_lookup_tap_leaf_script_sig(
+        &self,
+        _: &DefiniteDescriptorKey,
+        _: &TapLeafHash,
+    ) -> Option<usize> {
+        Some(SCHNORR_SIG_SIZE)
+    }
+
+    fn check_older(&self, older: relative::LockTime) -> bool {
+        let (height, time) = self.created;
+        match older {
+            relative::LockTime::Blocks(blocks) => height + u32::from(blocks.value()) <= self.height,
+            relative::LockTime::Time(intervals) => {
+                time + u64::from(intervals.value()) * 512 <= self.time
+            }
+        }
+    }
+
+    fn check_after(&self, after: absolute::LockTime) -> bool {
+        match after {
+            absolute::LockTime::Blocks(height) => height.to_consensus_u32() < self.height,
+            absolute::LockTime::Seconds(time) => u64::from(time.to_consensus_u32()) < self.time,
+        }
+    }
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Compute the confirmed and trusted pending balance, split by whether the spending paths of
+    /// the outputs are timelocked at the current tip, see [`Wallet::set_keychain_assets`].
+    ///
+    /// Locked outputs and immature coinbase outputs are not counted.
+    pub fn timelocked_balance(&self) -> TimelockedBalance {
+        let mut balance = TimelockedBalance::default();
+        let utxos = self.list_unspent().filter(|utxo| {
+            utxo.lock.is_none()
+                && (utxo.chain_position.is_confirmed() || self.is_keychain_trusted(&utxo.keychain))
+        });
+        for utxo in utxos {
+            if utxo.is_spendable {
+                balance.spendable += utxo.txout.value;
+            } else if utxo.spendable_from.is_some() {
+                balance.timelocked += utxo.txout.value;
+            }
+        }
+        balance
+    }
+
+    /// The time of the latest block of the local chain confirming a transaction of the wallet.
+    ///
+    /// This scans every anchor of the graph, see [`Wallet::next_block`] to compute it once.
+    pub(crate) fn latest_block_time(&self) -> u64 {
+        let chain = self.local_chain();
+        self.tx_graph()
+            .graph()
+            .all_anchors()
+            .values()
+            .flatten()
+            .filter(|anchor| {
+                chain
+                    .get(anchor.block_id.height)
+                    .map_or(false, |cp| cp.hash() == anchor.block_id.hash)
+            })
+            .map(|anchor| anchor.confirmation_time)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The block on top of the tip of the local chain.
+    pub(crate) fn next_block(&self) -> NextBlock {
+        NextBlock {
+            height: self.local_chain().tip().height() + 1,
+            time: self.latest_block_time(),
+        }
+    }
+
+    /// When the output of `keychain` at `derivation_index` created at `chain_position` becomes
+    /// spendable, or `None` if its spending path is not timelocked or can not be satisfied.
+    ///
+    /// Without assets set for the keychain, this is the cheapest spending path available in
+    /// `next`, or else the one whose timelocks are reached first.
+    pub(crate) fn spendable_from(
+        &self,
+        keychain: &K,
+        derivation_index: u32,
+        chain_position: &ChainPosition<ConfirmationBlockTime>,
+        next: NextBlock,
+    ) -> Option<SpendableFrom> {
+        let descriptor = self.txout_index().get_descriptor(keychain.clone())?;
+        if matches!(
+            descriptor.desc_type(),
+            DescriptorType::Pkh | DescriptorType::Wpkh | DescriptorType::ShWpkh
+        ) {
+            return None;
+        }
+        let policy = descriptor.lift().ok()?;
+        let descriptor = descriptor.at_derivation_index(derivation_index).ok()?;
+        if let Some(assets) = self.keychain_assets(keychain) {
+            let plan = descriptor.plan(assets).ok()?;
+            return self.plan_spendable_from(&plan, chain_position, next);
+        }
+
+        let created = match chain_position {
+            ChainPosition::Confirmed { anchor, .. } => {
+                (anchor.block_id.height, anchor.confirmation_time)
+            }
+            ChainPosition::Unconfirmed { .. } => (next.height, next.time),
+        };
+        // The blocks at which the timelocks of the spending paths are reached.
+        let mut heights = vec![next.height];
+        let mut times = vec![next.time];
+        for after in policy.absolute_timelocks() {
+            match absolute::LockTime::from_consensus(after) {
+                absolute::LockTime::Blocks(height) => heights.push(height.to_consensus_u32() + 1),
+                absolute::LockTime::Seconds(time) => {
+                    times.push(u64::from(time.to_consensus_u32()) + 1)
+                }
+            }
+        }
+        for older in policy.relative_timelocks() {
+            match relative::LockTime::from_consensus(older) {
+                Ok(relative::LockTime::Blocks(blocks)) => {
+                    heights.push(created.0 + u32::from(blocks.value()))
+                }
+                Ok(relative::LockTime::Time(intervals)) => {
+                    times.push(created.1 + u64::from(intervals.value()) * 512)
+                }
+                Err(_) => {}
+            }
+        }
+        heights.retain(|&height| height >= next.height);
+        times.retain(|&time| time >= next.time);
+        heights.sort_unstable();
+        heights.dedup();
+        times.sort_unstable();
+        times.dedup();
+
+        // The cheapest path available in the next block, or else the path available the soonest.
+        let mut best = None::<(u64, Plan)>;
+        for &height in &heights {
+            for &time in &times {
+                let wait = (u64::from(height - next.height) * BLOCK_INTERVAL).max(time - next.time);
+                if best
+                    .as_ref()
+                    .map_or(false, |(best_wait, _)| *best_wait <= wait)
+                {
+                    continue;
+                }
+                let assets = AllKeysAt {
+                    height,
+                    time,
+                    created,
+                };
+                if let Ok(plan) = descriptor.clone().plan(&assets) {
+                    best = Some((wait, plan));
+                }
+            }
+        }
+        let (_, plan) = best?;
+        self.plan_spendable_from(&plan, chain_position, next)
+    }
+
+    /// When an output created at `chain_position` becomes spendable through the spending path
+    /// `plan`, or `None` if the path is not timelocked.
+    ///
+    /// An unconfirmed output is counted as confirming in `next`.
+    pub(crate) fn plan_spendable_from(
+        &self,
+        plan: &Plan,
+        chain_position: &ChainPosition<ConfirmationBlockTime>,
+        next: NextBlock,
+    ) -> Option<SpendableFrom> {
+        let mut from = SpendableFrom::default();
+        match plan.absolute_timelock {
+            Some(absolute::LockTime::Blocks(height)) => {
+                from.height = Some(height.to_consensus_u32() + 1);
+            }
+            Some(absolute::LockTime::Seconds(time)) => {
+                from.time = Some(u64::from(time.to_consensus_u32()) + 1);
+            }
+            None => {}
+        }
+        if let Some(older) = plan.relative_timelock {
+            let (height, time) = match chain_position {
+                ChainPosition::Confirmed { anchor, .. } => {
+                    (anchor.block_id.height, anchor.confirmation_time)
+                }
+                ChainPosition::Unconfirmed { .. } => (next.height, next.time),
+            };
+            match older {
+                relative::LockTime::Blocks(blocks) => {
+                    let height = height + u32::from(blocks.value());
+                    from.height = Some(from.height.map_or(height, |h| h.max(height)));
+                }
+                relative::LockTime::Time(intervals) => {
+                    let time = time + u64::from(intervals.value()) * 512;
+                    from.time = Some(from.time.map_or(time, |t| t.max(time)));
+                }
+            }
+        }
+        if from == SpendableFrom::default() {
+            None
+        } else {
+            Some(from)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, string::String};
+    use core::str::FromStr;
+
+    use bitcoin::{hashes::Hash, FeeRate, ScriptBuf, Sequence, WPubkeyHash};
+    use miniscript::{plan::Assets, DescriptorPublicKey};
+
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    const XPUB: &str = "tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7";
+
+    fn key(i: u32) -> String {
+        format!("{XPUB}/{i}/*")
+    }
+
+    #[test]
+    fn relative_timelock() {
+        let recovery = format!("wsh(or_d(pk({}),and_v(v:pk({}),older(6))))", key(8), key(9));
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], &recovery]);
+        let k = descriptor_id(&recovery);
+        let op = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+        let height = wallet.latest_checkpoint().height();
+
+        // The cheapest path, through the primary key, is not timelocked.
+        let utxo = wallet.list_unspent().next().unwrap();
+        assert_eq!(utxo.spendable_from, None);
+        assert!(utxo.is_spendable);
+
+        let recovery_key = DescriptorPublicKey::from_str(&key(9)).unwrap();
+        let assets = || {
+            Assets::new()
+                .add(recovery_key.clone())
+                .older(relative::LockTime::from_height(6))
+        };
+        wallet.set_keychain_assets(k, Some(assets()));
+        let utxo = wallet.list_unspent().next().unwrap();
+        assert_eq!(
+            utxo.spendable_from,
+            Some(SpendableFrom {
+                height: Some(height + 6),
+                time: None
+            })
+        );
+        assert!(!utxo.is_spendable);
+        assert_eq!(wallet.list_spendable().count(), 0);
+        assert_eq!(
+            wallet.timelocked_balance(),
+            TimelockedBalance {
+                spendable: Amount::ZERO,
+                timelocked: Amount::from_sat(50_000)
+            }
+        );
+
+        for _ in 0..5 {
+            add_block(&mut wallet, vec![]);
+        }
+        assert_eq!(
+            wallet.timelocked_balance(),
+            TimelockedBalance {
+                spendable: Amount::from_sat(50_000),
+                timelocked: Amount::ZERO
+            }
+        );
+        // The builder spends through the recovery path.
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+                Amount::from_sat(20_000),
+            )
+            .fee_rate(FeeRate::BROADCAST_MIN)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, op);
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, Sequence::from_height(6));
+
+        // An unconfirmed output is counted as confirming in the next block.
+        wallet.set_keychain_trusted(k, true);
+        receive_unconfirmed(&mut wallet, k, Amount::from_sat(10_000), 1);
+        let tip = wallet.latest_checkpoint().height();
+        let utxo = wallet
+            .list_unspent()
+            .find(|utxo| !utxo.chain_position.is_confirmed())
+            .unwrap();
+        assert_eq!(utxo.spendable_from.unwrap().height, Some(tip + 1 + 6));
+        assert_eq!(
+            wallet.timelocked_balance().timelocked,
+            Amount::from_sat(10_000)
+        );
+
+        wallet.set_keychain_assets(k, None);
+        assert_eq!(wallet.timelocked_balance().timelocked, Amount::ZERO);
+    }
+
+    #[test]
+    fn absolute_and_time_timelocks() {
+        let tip = 10;
+        let vault = format!("wsh(and_v(v:pk({}),after({})))", key(8), tip + 3);
+        // Relative timelock of one 512 seconds interval.
+        let delayed = format!("wsh(and_v(v:pk({}),older(4194305)))", key(9));
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], &vault, &delayed]);
+        let k_vault = descriptor_id(&vault);
+        let k_delayed = descriptor_id(&delayed);
+        while wallet.latest_checkpoint().height() < tip - 1 {
+            add_block(&mut wallet, vec![]);
+        }
+        receive_confirmed(&mut wallet, k_vault, Amount::from_sat(20_000));
+        receive_confirmed(&mut wallet, k_delayed, Amount::from_sat(30_000));
+        let time = wallet.latest_block_time();
+
+        let spendable_from = |wallet: &Wallet<_>, keychain| {
+            let utxo = wallet
+                .list_unspent()
+                .find(|utxo| utxo.keychain == keychain)
+                .unwrap();
+            (utxo.spendable_from.unwrap(), utxo.is_spendable)
+        };
+        assert_eq!(
+            spendable_from(&wallet, k_vault),
+            (
+                SpendableFrom {
+                    height: Some(tip + 4),
+                    time: None
+                },
+                false
+            )
+        );
+        assert_eq!(
+            spendable_from(&wallet, k_delayed),
+            (
+                SpendableFrom {
+                    height: None,
+                    time: Some(time + 512)
+                },
+                false
+            )
+        );
+
+        add_block(&mut wallet, vec![]);
+        assert!(!spendable_from(&wallet, k_vault).1);
+        add_block(&mut wallet, vec![]);
+        assert!(spendable_from(&wallet, k_vault).1);
+        assert_eq!(
+            wallet.timelocked_balance(),
+            TimelockedBalance {
+                spendable: Amount::from_sat(20_000),
+                timelocked: Amount::from_sat(30_000)
+            }
+        );
+    }
+
+    #[test]
+    fn earliest_spending_path() {
+        // The cheapest path is timelocked, but the multisig path is available now.
+        let multi = format!(
+            "wsh(or_d(multi(2,{},{},{}),and_v(v:pk({}),older(144))))",
+            key(8),
+            key(9),
+            key(10),
+            key(11)
+        );
+        // The cheapest path is reached after the other one.
+        let recovery = format!(
+            "wsh(or_i(and_v(v:pk({}),older(144)),and_v(v:multi(2,{},{}),older(6))))",
+            key(12),
+            key(13),
+            key(14)
+        );
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], &multi, &recovery]);
+        let k_multi = descriptor_id(&multi);
+        let k_recovery = descriptor_id(&recovery);
+        receive_confirmed(&mut wallet, k_multi, Amount::from_sat(20_000));
+        receive_confirmed(&mut wallet, k_recovery, Amount::from_sat(30_000));
+        let height = wallet.latest_checkpoint().height();
+
+        let utxo = |wallet: &Wallet<_>, keychain| {
+            wallet
+                .list_unspent()
+                .find(|utxo| utxo.keychain == keychain)
+                .unwrap()
+        };
+        let multi_utxo = utxo(&wallet, k_multi);
+        assert_eq!(multi_utxo.spendable_from, None);
+        assert!(multi_utxo.is_spendable);
+        let recovery_utxo = utxo(&wallet, k_recovery);
+        assert_eq!(
+            recovery_utxo.spendable_from,
+            Some(SpendableFrom {
+                height: Some(height + 6),
+                time: None
+            })
+        );
+        assert!(!recovery_utxo.is_spendable);
+
+        for _ in 0..5 {
+            add_block(&mut wallet, vec![]);
+        }
+        assert!(utxo(&wallet, k_recovery).is_spendable);
+        assert_eq!(
+            wallet.timelocked_balance(),
+            TimelockedBalance {
+                spendable: Amount::from_sat(50_000),
+                timelocked: Amount::ZERO
+            }
+        );
+    }
+}
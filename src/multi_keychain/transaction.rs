@@ -0,0 +1,239 @@
+//! Atomic [`WalletTransaction`]s.
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(feature = "rusqlite")]
+use bdk_chain::{rusqlite, DescriptorId};
+
+use bdk_chain::Merge;
+
+use crate::bdk_chain;
+use crate::multi_keychain::{ChangeSet, Wallet};
+
+/// A set of mutations to a [`Wallet`] that are committed to persistence and to memory together.
+///
+/// Obtained with [`Wallet::begin`]. The wallet is mutated through the transaction, which
+/// dereferences to the [`Wallet`]. [`commit`](Self::commit) persists everything that is staged.
+/// If persisting fails, or if the transaction is [rolled back](Self::rollback) or dropped without
+/// being committed, the wallet is restored to the state it was in when the transaction began, so
+/// the in-memory state never diverges from what is persisted.
+///
+/// Beginning a transaction does not copy the transaction graph of the wallet: it is only copied
+/// once the transaction mutates it, e.g. by revealing addresses or applying an update.
+#[derive(Debug)]
+#[must_use = "the changes are rolled back unless the transaction is committed"]
+pub struct WalletTransaction<'a, K: Ord> {
+    wallet: &'a mut Wallet<K>,
+    /// The wallet when the transaction began, with nothing staged.
+    checkpoint: Option<Wallet<K>>,
+    /// The changes staged before the transaction began.
+    staged: Option<ChangeSet<K>>,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Begin a [`WalletTransaction`].
+    pub fn begin(&mut self) -> WalletTransaction<'_, K> {
+        // The wallet only stages the changes made during the transaction.
+        let staged = self.take_staged();
+        WalletTransaction {
+            checkpoint: Some(self.checkpoint()),
+            staged,
+            wallet: self,
+        }
+    }
+}
+
+impl<K> WalletTransaction<'_, K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Persist the staged changes with `persist` and keep the mutations made in memory.
+    ///
+    /// This persists everything that is staged, including changes staged before the transaction
+    /// began. Returns the persisted changeset, or `None` if nothing is staged. If `persist` fails,
+    /// the wallet is rolled back and the error is returned.
+    pub fn commit<E>(
+        mut self,
+        persist: impl FnOnce(&ChangeSet<K>) -> Result<(), E>,
+    ) -> Result<Option<ChangeSet<K>>, E> {
+        // The changes staged before are kept if persisting fails.
+        let mut changeset = self.staged.clone().unwrap_or_default();
+        if let Some(changes) = self.wallet.take_staged() {
+            changeset.merge(changes);
+        }
+        if changeset.is_empty() {
+            self.checkpoint = None;
+            self.staged = None;
+            return Ok(None);
+        }
+        persist(&changeset)?;
+        self.checkpoint = None;
+        self.staged = None;
+        Ok(Some(changeset))
+    }
+
+    /// Discard the mutations made since the transaction began.
+    pub fn rollback(self) {}
+}
+
+#[cfg(feature = "rusqlite")]
+impl WalletTransaction<'_, DescriptorId> {
+    /// Commit the transaction to SQLite, see [`commit`](Self::commit).
+    pub fn commit_to_sqlite(
+        self,
+        conn: &mut rusqlite::Connection,
+    ) -> rusqlite::Result<Option<ChangeSet<DescriptorId>>> {
+        self.commit(|changeset| {
+            let db_tx = conn.transaction()?;
+            changeset.persist_to_sqlite(&db_tx)?;
+            db_tx.commit()
+        })
+    }
+}
+
+impl<K: Ord> Deref for WalletTransaction<'_, K> {
+    type Target = Wallet<K>;
+
+    fn deref(&self) -> &Self::Target {
+        self.wallet
+    }
+}
+
+impl<K: Ord> DerefMut for WalletTransaction<'_, K> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.wallet
+    }
+}
+
+impl<K: Ord> Drop for WalletTransaction<'_, K> {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            *self.wallet = checkpoint;
+        }
+        if let Some(staged) = self.staged.take() {
+            self.wallet.restage(staged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Amount;
+
+    use crate::multi_keychain::test_utils::*;
+    #[cfg(feature = "rusqlite")]
+    use crate::multi_keychain::Wallet;
+
+    #[test]
+    fn failed_commit_rolls_back() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let snapshot = wallet.to_snapshot();
+        let staged = wallet.staged().cloned();
+
+        let mut tx = wallet.begin();
+        let _ = tx.reveal_next_address(k0);
+        assert!(tx.add_descriptor(k1, DESCRIPTORS[1]).unwrap());
+        receive_confirmed(&mut tx, k1, Amount::from_sat(10_000));
+        assert_eq!(tx.keychains().count(), 2);
+        assert!(tx.commit(|_| Err("disk full")).is_err());
+
+        assert_eq!(wallet.to_snapshot(), snapshot);
+        assert_eq!(wallet.staged().cloned(), staged);
+    }
+
+    #[test]
+    fn dropped_transaction_rolls_back() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let snapshot = wallet.to_snapshot();
+
+        {
+            let mut tx = wallet.begin();
+            let _ = tx.reveal_next_address(k0);
+        }
+        assert_eq!(wallet.to_snapshot(), snapshot);
+
+        let mut tx = wallet.begin();
+        let _ = tx.reveal_next_address(k0);
+        tx.rollback();
+        assert_eq!(wallet.to_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+
+        let mut tx = wallet.begin();
+        assert!(tx.add_descriptor(k1, DESCRIPTORS[1]).unwrap());
+        receive_confirmed(&mut tx, k1, Amount::from_sat(10_000));
+        let persisted = tx.commit(|_| Ok::<_, ()>(())).unwrap().unwrap();
+
+        assert!(persisted.keyring.descriptors.contains_key(&k1));
+        assert!(wallet.staged().is_none());
+        assert_eq!(wallet.balance().confirmed, Amount::from_sat(10_000));
+    }
+
+    #[test]
+    fn changes_staged_before_are_kept() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let _ = wallet.take_staged();
+        let _ = wallet.reveal_next_address(k0);
+        let staged = wallet.staged().cloned().unwrap();
+
+        let mut tx = wallet.begin();
+        let _ = tx.reveal_next_address(k0);
+        tx.rollback();
+        assert_eq!(wallet.staged(), Some(&staged));
+
+        let mut tx = wallet.begin();
+        let _ = tx.reveal_next_address(k0);
+        let persisted = tx.commit(|_| Ok::<_, ()>(())).unwrap().unwrap();
+        assert_eq!(
+            persisted.indexer.last_revealed.values().copied().max(),
+            Some(1)
+        );
+        assert!(wallet.staged().is_none());
+
+        // Nothing to persist.
+        let tx = wallet.begin();
+        assert_eq!(tx.commit(|_| Err(())), Ok(None));
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn commit_to_sqlite() -> anyhow::Result<()> {
+        use crate::bdk_chain::rusqlite;
+
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+
+        // Tables are missing, so persisting fails.
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let snapshot = wallet.to_snapshot();
+        let mut tx = wallet.begin();
+        tx.add_descriptor(k1, DESCRIPTORS[1])?;
+        assert!(tx.commit_to_sqlite(&mut conn).is_err());
+        assert_eq!(wallet.to_snapshot(), snapshot);
+
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+        let mut tx = wallet.begin();
+        tx.add_descriptor(k1, DESCRIPTORS[1])?;
+        receive_confirmed(&mut tx, k1, Amount::from_sat(10_000));
+        assert!(tx.commit_to_sqlite(&mut conn)?.is_some());
+
+        let loaded = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert_eq!(loaded.to_snapshot(), wallet.to_snapshot());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1115 @@
+//! [`TxBuilder`] to spend from the [`Wallet`].
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bdk_chain::collections::{BTreeMap, BTreeSet};
+use bitcoin::{
+    absolute, transaction, Amount, FeeRate, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Weight,
+};
+use miniscript::{
+    descriptor::{DescriptorPublicKey, DescriptorType},
+    plan::{Assets, Plan},
+    policy::{semantic::Policy, Liftable},
+    psbt::{PsbtInputExt, PsbtOutputExt},
+};
+
+use crate::bdk_chain;
+use crate::multi_keychain::{
+    AntiFeeSniping, BranchAndBound, CoinSelectionAlgorithm, CoinSelectionResult, InsufficientFunds,
+    LocalOutput, SelectionParams, Wallet, WeightedUtxo,
+};
+
+/// Weight of a transaction input without its `script_sig` nor witness: the previous outpoint
+/// (36 bytes), the script length (1 byte) and the sequence (4 bytes).
+pub(crate) const TXIN_BASE_WEIGHT: Weight = Weight::from_wu(41 * 4);
+
+/// Weight of the segwit marker and flag, counted for every transaction as an upper bound.
+pub(crate) const SEGWIT_MARKER_WEIGHT: Weight = Weight::from_wu(2);
+
+/// How the fee of a transaction is determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePolicy {
+    /// Pay at least this fee rate.
+    FeeRate(FeeRate),
+    /// Pay exactly this fee.
+    FeeAmount(Amount),
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self::FeeRate(FeeRate::BROADCAST_MIN)
+    }
+}
+
+impl FeePolicy {
+    /// Check that the fee of a transaction as heavy as a block does not exceed
+    /// [`Amount::MAX_MONEY`], so that computing fees does not overflow.
+    pub(crate) fn check<K>(&self) -> Result<(), CreateTxError<K>> {
+        let fee = match *self {
+            Self::FeeRate(rate) => checked_fee_for_weight(rate, Weight::MAX_BLOCK),
+            Self::FeeAmount(fee) => Some(fee),
+        };
+        match fee {
+            Some(fee) if fee <= Amount::MAX_MONEY => Ok(()),
+            _ => Err(CreateTxError::FeeTooHigh),
+        }
+    }
+
+    /// The fee to pay for a transaction of the given `weight`.
+    pub(crate) fn fee(&self, weight: Weight) -> Amount {
+        match *self {
+            Self::FeeRate(rate) => fee_for_weight(rate, weight),
+            Self::FeeAmount(fee) => fee,
+        }
+    }
+
+    /// The fee added by `weight` to a transaction, which is nothing for an absolute fee.
+    pub(crate) fn marginal_fee(&self, weight: Weight) -> Amount {
+        match *self {
+            Self::FeeRate(rate) => fee_for_weight(rate, weight),
+            Self::FeeAmount(_) => Amount::ZERO,
+        }
+    }
+}
+
+/// The fee paid by a transaction of `weight` at `rate`, rounded up, or `None` on overflow.
+pub(crate) fn checked_fee_for_weight(rate: FeeRate, weight: Weight) -> Option<Amount> {
+    let fee = rate
+        .to_sat_per_kwu()
+        .checked_mul(weight.to_wu())?
+        .checked_add(999)?;
+    Some(Amount::from_sat(fee / 1000))
+}
+
+/// The fee paid by a transaction of `weight` at `rate`, rounded up and capped to
+/// [`Amount::MAX_MONEY`]. Caller supplied fee rates are validated with [`FeePolicy::check`].
+pub(crate) fn fee_for_weight(rate: FeeRate, weight: Weight) -> Amount {
+    checked_fee_for_weight(rate, weight).map_or(Amount::MAX_MONEY, |fee| fee.min(Amount::MAX_MONEY))
+}
+
+/// Builds a transaction spending from the [`Wallet`], see [`Wallet::build_tx`].
+///
+/// The transaction is returned as an unsigned [`Psbt`] carrying the BIP32 derivation paths and
+/// previous outputs of every input, whatever the script type of the keychain it spends from.
+#[derive(Debug)]
+#[must_use]
+pub struct TxBuilder<'a, K: Ord, Cs = BranchAndBound> {
+    wallet: &'a mut Wallet<K>,
+    recipients: Vec<(ScriptBuf, Amount)>,
+    spend_from: Option<BTreeSet<K>>,
+    change_keychain: Option<K>,
+    fee_policy: FeePolicy,
+    assets: BTreeMap<K, Assets>,
+    anti_fee_sniping: AntiFeeSniping,
+    rbf: bool,
+    coin_selection: Cs,
+}
+
+impl<K> Wallet<K>
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    /// Start building a transaction spending from the wallet.
+    pub fn build_tx(&mut self) -> TxBuilder<'_, K> {
+        TxBuilder {
+            wallet: self,
+            recipients: Vec::new(),
+            spend_from: None,
+            change_keychain: None,
+            fee_policy: FeePolicy::default(),
+            assets: BTreeMap::new(),
+            anti_fee_sniping: AntiFeeSniping::default(),
+            rbf: true,
+            coin_selection: BranchAndBound::default(),
+        }
+    }
+
+    /// The weight of an input spending an output of `keychain`, once satisfied.
+    pub(crate) fn input_weight(&self, keychain: &K) -> Option<Weight> {
+        let descriptor = self.txout_index().get_descriptor(keychain.clone())?;
+        let satisfaction_weight = descriptor.max_weight_to_satisfy().ok()?;
+        Some(TXIN_BASE_WEIGHT + satisfaction_weight)
+    }
+
+    /// The spending policy of the descriptor of `keychain`, to pick the spending path to use with
+    /// [`TxBuilder::spending_assets`].
+    ///
+    /// Returns `None` if the keychain does not exist or its descriptor can not be lifted.
+    pub fn policy(&self, keychain: &K) -> Option<Policy<DescriptorPublicKey>> {
+        let descriptor = self.txout_index().get_descriptor(keychain.clone())?;
+        descriptor.lift().ok()
+    }
+
+    /// The cheapest spending path of `utxo` available with `assets`, if any.
+    pub(crate) fn plan(&self, utxo: &LocalOutput<K>, assets: &Assets) -> Option<Plan> {
+        self.txout_index()
+            .get_descriptor(utxo.keychain.clone())?
+            .at_derivation_index(utxo.derivation_index)
+            .ok()?
+            .plan(assets)
+            .ok()
+    }
+
+    /// The index and script pubkey of the address [`Wallet::reveal_next_address`] would return
+    /// for `keychain`, without revealing it.
+    ///
+    /// Change is sent to this address rather than to the next unused one, which may have been
+    /// handed out already to receive a payment.
+    pub(crate) fn peek_next_change(&self, keychain: &K) -> Option<(u32, ScriptBuf)> {
+        let (next_index, _) = self.txout_index().next_index(keychain.clone())?;
+        let address = self.peek_address(keychain.clone(), next_index)?;
+        Some((next_index, address.script_pubkey()))
+    }
+
+    /// Reveal the change address of `keychain` at `index`, as returned by
+    /// [`Wallet::peek_next_change`], and mark it as used.
+    pub(crate) fn reveal_change(&mut self, keychain: K, index: u32) {
+        let _ = self.reveal_addresses_to(keychain.clone(), index);
+        self.mark_used(keychain, index);
+    }
+
+    /// The spendable outputs of `keychains` accepted by `filter`, weighted for coin selection and
+    /// grouped by their tier in the wallet's [`SpendingPriority`](crate::multi_keychain::SpendingPriority)
+    /// at the fee rate of `fee_policy`.
+    ///
+    /// The outputs of the keychains with `assets`, or with assets set with
+    /// [`Wallet::set_keychain_assets`], are weighted for their cheapest spending path with these
+    /// assets, and left out if there is none or if its timelocks are not reached at the tip.
+    /// If every output accepted by `filter` is left out, this fails with
+    /// [`CreateTxError::NoSpendingPath`].
+    pub(crate) fn candidates_by_tier(
+        &self,
+        keychains: &BTreeSet<K>,
+        fee_policy: &FeePolicy,
+        assets: &BTreeMap<K, Assets>,
+        filter: impl Fn(&LocalOutput<K>) -> bool,
+    ) -> Result<BTreeMap<usize, Vec<WeightedUtxo<K>>>, CreateTxError<K>> {
+        let fee_rate = match *fee_policy {
+            FeePolicy::FeeRate(rate) => Some(rate),
+            FeePolicy::FeeAmount(_) => None,
+        };
+        let mut tiers = BTreeMap::<usize, Vec<WeightedUtxo<K>>>::new();
+        let mut left_out = 0;
+        let next = self.next_block();
+        for keychain in keychains {
+            let descriptor = self
+                .txout_index()
+                .get_descriptor(keychain.clone())
+                .ok_or_else(|| CreateTxError::UnknownKeychain(keychain.clone()))?;
+            let tier = self
+                .spending_priority()
+                .tier(fee_rate, keychain, descriptor.desc_type());
+            let weight = self
+                .input_weight(keychain)
+                .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(keychain.clone()))?;
+            let utxos = self
+                .list_spendable()
+                .filter(|utxo| &utxo.keychain == keychain && filter(utxo));
+            let candidates = tiers.entry(tier).or_default();
+            match assets
+                .get(keychain)
+                .or_else(|| self.keychain_assets(keychain))
+            {
+                Some(assets) => {
+                    for utxo in utxos {
+                        let plan = self.plan(&utxo, assets).filter(|plan| {
+                            self.plan_spendable_from(plan, &utxo.chain_position, next)
+                                .map_or(true, |from| next.is_reached(from))
+                        });
+                        match plan {
+                            Some(plan) => {
+                                let weight = TXIN_BASE_WEIGHT
+                                    + Weight::from_wu(plan.satisfaction_weight() as u64);
+                                candidates.push(WeightedUtxo { utxo, weight });
+                            }
+                            None => left_out += 1,
+                        }
+                    }
+                }
+                None => candidates.extend(utxos.map(|utxo| WeightedUtxo { utxo, weight })),
+            }
+        }
+        if left_out > 0 && tiers.values().all(Vec::is_empty) {
+            return Err(CreateTxError::NoSpendingPath);
+        }
+        Ok(tiers)
+    }
+
+    /// Fill in the derivation paths and previous outputs of the inputs of `psbt`, spending
+    /// `utxos` in order, and the derivation paths of the outputs paying to the wallet.
+    pub(crate) fn update_psbt(&self, psbt: &mut Psbt, utxos: &[LocalOutput<K>]) {
+        let index = self.txout_index();
+        let graph = self.tx_graph().graph();
+        for (psbt_input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+            let descriptor = index
+                .get_descriptor(utxo.keychain.clone())
+                .expect("utxo keychain must exist")
+                .at_derivation_index(utxo.derivation_index)
+                .expect("derivation index is not hardened");
+            psbt_input
+                .update_with_descriptor_unchecked(&descriptor)
+                .expect("derivation index is not hardened");
+            let desc_type = descriptor.desc_type();
+            if desc_type.segwit_version().is_some() {
+                psbt_input.witness_utxo = Some(utxo.txout.clone());
+            }
+            // Only taproot signatures commit to the amounts of all inputs.
+            if desc_type != DescriptorType::Tr {
+                psbt_input.non_witness_utxo = graph
+                    .get_tx(utxo.outpoint.txid)
+                    .map(|tx| tx.as_ref().clone());
+            }
+        }
+        for (psbt_output, txout) in psbt.outputs.iter_mut().zip(&psbt.unsigned_tx.output) {
+            if let Some((keychain, i)) = index.index_of_spk(txout.script_pubkey.clone()) {
+                let descriptor = index
+                    .get_descriptor(keychain.clone())
+                    .expect("keychain must exist")
+                    .at_derivation_index(*i)
+                    .expect("derivation index is not hardened");
+                psbt_output
+                    .update_with_descriptor_unchecked(&descriptor)
+                    .expect("derivation index is not hardened");
+            }
+        }
+    }
+}
+
+/// The absolute timelock of a transaction whose inputs are spent through `plans`, the latest of
+/// their absolute timelocks.
+pub(crate) fn plans_lock_time<K>(
+    plans: &[Option<Plan>],
+) -> Result<absolute::LockTime, CreateTxError<K>> {
+    let mut lock_time = None::<absolute::LockTime>;
+    for after in plans
+        .iter()
+        .flatten()
+        .filter_map(|plan| plan.absolute_timelock)
+    {
+        lock_time = match lock_time {
+            None => Some(after),
+            Some(current) if current.is_same_unit(after) => {
+                Some(if after.to_consensus_u32() > current.to_consensus_u32() {
+                    after
+                } else {
+                    current
+                })
+            }
+            Some(_) => return Err(CreateTxError::IncompatibleLockTimes),
+        };
+    }
+    Ok(lock_time.unwrap_or(absolute::LockTime::ZERO))
+}
+
+/// Restrict the inputs of `psbt` with a spending path in `plans` to the keys of that path.
+pub(crate) fn update_psbt_plans(psbt: &mut Psbt, plans: &[Option<Plan>]) {
+    for (psbt_input, plan) in psbt.inputs.iter_mut().zip(plans) {
+        if let Some(plan) = plan {
+            // Only keep the keys of the spending path.
+            psbt_input.bip32_derivation.clear();
+            psbt_input.tap_key_origins.clear();
+            psbt_input.tap_scripts.clear();
+            plan.update_psbt_input(psbt_input);
+        }
+    }
+}
+
+/// Select inputs on top of the `required` ones, only adding the candidates of a tier if the
+/// previous ones can not fund the transaction.
+///
+/// Selection runs over one tier at a time. A tier that can not fund the transaction is spent
+/// entirely, and selection moves on to the next tier, so that a preferred tier is never replaced
+/// by the coins of a later one.
+pub(crate) fn select_by_tier<K, Cs>(
+    coin_selection: &Cs,
+    mut required: Vec<WeightedUtxo<K>>,
+    tiers: BTreeMap<usize, Vec<WeightedUtxo<K>>>,
+    params: &SelectionParams,
+) -> Result<CoinSelectionResult<K>, CreateTxError<K>>
+where
+    K: Clone,
+    Cs: CoinSelectionAlgorithm<K>,
+{
+    let mut tiers = tiers.into_values();
+    let mut optional = tiers.next().unwrap_or_default();
+    loop {
+        let result = coin_selection.coin_select(required.clone(), optional.clone(), params);
+        match tiers.next() {
+            Some(tier) if result.is_err() => {
+                required.append(&mut optional);
+                optional = tier;
+            }
+            _ => {
+                return result.map_err(|InsufficientFunds { needed, available }| {
+                    CreateTxError::InsufficientFunds { needed, available }
+                })
+            }
+        }
+    }
+}
+
+impl<'a, K, Cs> TxBuilder<'a, K, Cs>
+where
+    K: fmt::Debug + Clone + Ord,
+    Cs: CoinSelectionAlgorithm<K>,
+{
+    /// Pay `amount` to `script_pubkey`.
+    pub fn add_recipient(mut self, script_pubkey: impl Into<ScriptBuf>, amount: Amount) -> Self {
+        self.recipients.push((script_pubkey.into(), amount));
+        self
+    }
+
+    /// Replace the recipients with `recipients`.
+    pub fn set_recipients(mut self, recipients: Vec<(ScriptBuf, Amount)>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Only spend outputs of the given `keychains`. By default all keychains are spent from.
+    pub fn spend_from(mut self, keychains: impl IntoIterator<Item = K>) -> Self {
+        self.spend_from = Some(keychains.into_iter().collect());
+        self
+    }
+
+    /// Send the change to `keychain`.
+    ///
+    /// By default the change goes to the keychain paired with the smallest keychain spent from, see
+    /// [`Wallet::set_change_keychain`]. If no keychain is chosen with
+    /// [`spend_from`](Self::spend_from) the default keychain is used for the pairing.
+    pub fn change_keychain(mut self, keychain: K) -> Self {
+        self.change_keychain = Some(keychain);
+        self
+    }
+
+    /// Pay at least `fee_rate`. This is the default, at 1 sat/vB.
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.fee_policy = FeePolicy::FeeRate(fee_rate);
+        self
+    }
+
+    /// Pay exactly `fee`, regardless of the size of the transaction.
+    pub fn fee_absolute(mut self, fee: Amount) -> Self {
+        self.fee_policy = FeePolicy::FeeAmount(fee);
+        self
+    }
+
+    /// Spend the outputs of `keychain` with their cheapest spending path available with
+    /// `assets`, i.e. the keys that will sign and the timelocks that can be satisfied, see
+    /// [`Wallet::policy`]. This overrides the assets set with [`Wallet::set_keychain_assets`],
+    /// without which the outputs are weighted for their most expensive path.
+    ///
+    /// Outputs with no path available, or whose path is timelocked at the tip of the local
+    /// chain, are not spent. The `nSequence` of the inputs and the
+    /// `nLockTime` of the transaction are set to satisfy the timelocks of the paths, and only the
+    /// keys of the paths are added to the PSBT inputs.
+    pub fn spending_assets(mut self, keychain: K, assets: Assets) -> Self {
+        self.assets.insert(keychain, assets);
+        self
+    }
+
+    /// Discourage fee sniping with `anti_fee_sniping`. The default is
+    /// [`AntiFeeSniping::Randomized`] if the `std` feature is enabled.
+    pub fn anti_fee_sniping(mut self, anti_fee_sniping: AntiFeeSniping) -> Self {
+        self.anti_fee_sniping = anti_fee_sniping;
+        self
+    }
+
+    /// Whether the transaction signals that it can be replaced, as in BIP125. This is the
+    /// default.
+    ///
+    /// The `nSequence` of the inputs whose spending path has a relative timelock always signal
+    /// replaceability.
+    pub fn rbf(mut self, rbf: bool) -> Self {
+        self.rbf = rbf;
+        self
+    }
+
+    /// Choose the inputs with `coin_selection`. The default is [`BranchAndBound`], falling back
+    /// to [`LargestFirst`](crate::multi_keychain::LargestFirst).
+    pub fn coin_selection<P>(self, coin_selection: P) -> TxBuilder<'a, K, P>
+    where
+        P: CoinSelectionAlgorithm<K>,
+    {
+        TxBuilder {
+            wallet: self.wallet,
+            recipients: self.recipients,
+            spend_from: self.spend_from,
+            change_keychain: self.change_keychain,
+            fee_policy: self.fee_policy,
+            assets: self.assets,
+            anti_fee_sniping: self.anti_fee_sniping,
+            rbf: self.rbf,
+            coin_selection,
+        }
+    }
+
+    /// Select the inputs and build the unsigned transaction.
+    ///
+    /// The keychains are spent from in the order of the wallet's
+    /// [`SpendingPriority`](crate::multi_keychain::SpendingPriority), see
+    /// [`Wallet::set_spending_priority`]. If the transaction has a change output, its address is
+    /// revealed and marked as used on the change keychain, which is staged in the wallet.
+    pub fn finish(self) -> Result<Psbt, CreateTxError<K>> {
+        let CreatedTx { psbt, change, .. } = self.create()?;
+        if let Some((change_keychain, change_index)) = change {
+            self.wallet.reveal_change(change_keychain, change_index);
+        }
+        Ok(psbt)
+    }
+
+    /// Select the inputs and build the unsigned transaction without revealing the address of
+    /// the change output.
+    pub(crate) fn create(&self) -> Result<CreatedTx<K>, CreateTxError<K>> {
+        let Self {
+            wallet,
+            recipients,
+            spend_from,
+            change_keychain,
+            fee_policy,
+            assets,
+            anti_fee_sniping,
+            rbf,
+            coin_selection,
+        } = self;
+        let wallet = &**wallet;
+        let fee_policy = *fee_policy;
+        fee_policy.check()?;
+
+        if recipients.is_empty() {
+            return Err(CreateTxError::NoRecipients);
+        }
+        for (i, (script_pubkey, amount)) in recipients.iter().enumerate() {
+            if *amount < script_pubkey.minimal_non_dust() {
+                return Err(CreateTxError::OutputBelowDustLimit(i));
+            }
+        }
+
+        let change_keychain = change_keychain.clone().unwrap_or_else(|| {
+            let keychain = spend_from
+                .as_ref()
+                .and_then(|keychains| keychains.iter().next().cloned())
+                .unwrap_or_else(|| wallet.default_keychain());
+            wallet.change_keychain(&keychain)
+        });
+        let spend_from = match spend_from {
+            Some(keychains) => keychains.clone(),
+            None => wallet.keychains().map(|(keychain, _)| keychain).collect(),
+        };
+
+        let tiers = wallet.candidates_by_tier(&spend_from, &fee_policy, assets, |_| true)?;
+        let (change_index, change_spk) = wallet
+            .peek_next_change(&change_keychain)
+            .ok_or_else(|| CreateTxError::UnknownKeychain(change_keychain.clone()))?;
+        let change_spend_weight = wallet
+            .input_weight(&change_keychain)
+            .ok_or_else(|| CreateTxError::UnsatisfiableKeychain(change_keychain.clone()))?;
+
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: recipients
+                .iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: *value,
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        };
+        let change_txout = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: change_spk,
+        };
+        let params = SelectionParams {
+            target: recipients.iter().map(|(_, amount)| *amount).sum(),
+            base_weight: tx.weight() + SEGWIT_MARKER_WEIGHT,
+            fee_policy,
+            change_weight: change_txout.weight(),
+            change_spend_weight,
+            min_change: change_txout.script_pubkey.minimal_non_dust(),
+            min_fee: Amount::ZERO,
+        };
+
+        let CoinSelectionResult {
+            selected, change, ..
+        } = select_by_tier(coin_selection, Vec::new(), tiers, &params)?;
+        let mut weight = params.base_weight;
+        for w in &selected {
+            weight += w.weight;
+        }
+        let selected = selected.into_iter().map(|w| w.utxo).collect::<Vec<_>>();
+        if let Some(value) = change {
+            weight += params.change_weight;
+            tx.output.push(TxOut {
+                value,
+                ..change_txout
+            });
+        }
+
+        // The spending paths of the inputs of keychains with assets, and their timelocks.
+        let plans = selected
+            .iter()
+            .map(|utxo| {
+                let assets = assets
+                    .get(&utxo.keychain)
+                    .or_else(|| wallet.keychain_assets(&utxo.keychain))?;
+                Some(wallet.plan(utxo, assets).expect("candidates have a plan"))
+            })
+            .collect::<Vec<_>>();
+        tx.lock_time = plans_lock_time(&plans)?;
+        let sequence = if *rbf {
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        } else {
+            Sequence::ENABLE_LOCKTIME_NO_RBF
+        };
+        tx.input = selected
+            .iter()
+            .zip(&plans)
+            .map(|(utxo, plan)| TxIn {
+                previous_output: utxo.outpoint,
+                sequence: plan
+                    .as_ref()
+                    .and_then(|plan| plan.relative_timelock)
+                    .map_or(sequence, |older| older.to_sequence()),
+                ..Default::default()
+            })
+            .collect();
+        let inputs = selected
+            .iter()
+            .map(|utxo| wallet.anti_fee_sniping_input(utxo))
+            .collect::<Vec<_>>();
+        wallet.discourage_fee_sniping(&mut tx, &inputs, *anti_fee_sniping);
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect("transaction is unsigned");
+        wallet.update_psbt(&mut psbt, &selected);
+        update_psbt_plans(&mut psbt, &plans);
+
+        Ok(CreatedTx {
+            psbt,
+            weight,
+            change: change.map(|_| (change_keychain, change_index)),
+        })
+    }
+}
+
+/// A transaction built by a [`TxBuilder`], whose change address is not revealed yet.
+pub(crate) struct CreatedTx<K> {
+    /// The unsigned transaction.
+    pub psbt: Psbt,
+    /// Weight of the transaction once satisfied.
+    pub weight: Weight,
+    /// Keychain and derivation index of the change output, if any.
+    pub change: Option<(K, u32)>,
+}
+
+/// Error returned by [`TxBuilder::finish`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CreateTxError<K> {
+    /// No recipient was added.
+    NoRecipients,
+    /// The amount paid to the recipient at this index is below the dust limit.
+    OutputBelowDustLimit(usize),
+    /// The wallet has no such keychain.
+    UnknownKeychain(K),
+    /// The maximum weight to satisfy the descriptor of the keychain can not be computed.
+    UnsatisfiableKeychain(K),
+    /// The spendable outputs do not cover the recipients and the fee.
+    InsufficientFunds {
+        /// Amount needed, including the fee.
+        needed: Amount,
+        /// Amount available.
+        available: Amount,
+    },
+    /// The spending paths of the inputs require absolute timelocks in both blocks and time.
+    IncompatibleLockTimes,
+    /// No spendable output has a spending path available with the assets of its keychain whose
+    /// timelocks are reached, see [`TxBuilder::spending_assets`].
+    NoSpendingPath,
+    /// The destination keychain of a drain is one of the keychains drained, see
+    /// [`Wallet::drain_keychains`].
+    InvalidDestination(K),
+    /// The fee rate or the absolute fee is so high that the fee could exceed the maximum amount
+    /// of bitcoin.
+    FeeTooHigh,
+}
+
+impl<K: fmt::Debug> fmt::Display for CreateTxError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRecipients => write!(f, "the transaction has no recipient"),
+            Self::OutputBelowDustLimit(i) => {
+                write!(f, "the output of recipient {i} is below the dust limit")
+            }
+            Self::UnknownKeychain(keychain) => write!(f, "unknown keychain {keychain:?}"),
+            Self::UnsatisfiableKeychain(keychain) => {
+                write!(
+                    f,
+                    "the descriptor of keychain {keychain:?} can not be satisfied"
+                )
+            }
+            Self::InsufficientFunds { needed, available } => write!(
+                f,
+                "insufficient funds: {available} available of {needed} needed"
+            ),
+            Self::IncompatibleLockTimes => write!(
+                f,
+                "the spending paths require timelocks in both blocks and time"
+            ),
+            Self::NoSpendingPath => write!(f, "no output has a spending path available"),
+            Self::InvalidDestination(keychain) => {
+                write!(f, "keychain {keychain:?} can not be drained into itself")
+            }
+            Self::FeeTooHigh => write!(f, "the fee is too high"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: fmt::Debug> std::error::Error for CreateTxError<K> {}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, Amount, FeeRate, ScriptBuf, WPubkeyHash};
+
+    use miniscript::descriptor::DescriptorType;
+
+    use super::*;
+    use crate::bdk_chain::DescriptorId;
+    use crate::multi_keychain::test_utils::*;
+    use crate::multi_keychain::{LargestFirst, OldestFirst, Preference, SpendingPriority};
+
+    fn recipient() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]))
+    }
+
+    #[test]
+    fn spend_across_script_types() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], TR_DESCRIPTOR, PKH_DESCRIPTOR]);
+        let k_wpkh = descriptor_id(DESCRIPTORS[0]);
+        let k_tr = descriptor_id(TR_DESCRIPTOR);
+        let k_pkh = descriptor_id(PKH_DESCRIPTOR);
+        let op_wpkh = receive_confirmed(&mut wallet, k_wpkh, Amount::from_sat(40_000));
+        let op_tr = receive_confirmed(&mut wallet, k_tr, Amount::from_sat(30_000));
+        let op_pkh = receive_confirmed(&mut wallet, k_pkh, Amount::from_sat(20_000));
+        wallet.set_change_keychain(k_wpkh, k_tr);
+
+        let fee_rate = FeeRate::from_sat_per_vb(5).unwrap();
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(80_000))
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            [op_wpkh, op_tr, op_pkh]
+        );
+        let fee = psbt.fee().unwrap();
+        assert!(fee >= fee_for_weight(fee_rate, tx.weight()));
+
+        // Every input carries its previous output and derivation.
+        let (wpkh_input, tr_input, pkh_input) = (&psbt.inputs[0], &psbt.inputs[1], &psbt.inputs[2]);
+        assert!(wpkh_input.witness_utxo.is_some() && wpkh_input.non_witness_utxo.is_some());
+        assert_eq!(wpkh_input.bip32_derivation.len(), 1);
+        assert!(tr_input.witness_utxo.is_some() && tr_input.non_witness_utxo.is_none());
+        assert_eq!(tr_input.tap_key_origins.len(), 1);
+        assert!(pkh_input.witness_utxo.is_none() && pkh_input.non_witness_utxo.is_some());
+        assert_eq!(pkh_input.bip32_derivation.len(), 1);
+
+        // The change goes to the keychain paired with the default keychain.
+        let change = &tx.output[1];
+        let (keychain, index) = *wallet
+            .txout_index()
+            .index_of_spk(change.script_pubkey.clone())
+            .unwrap();
+        assert_eq!(keychain, k_tr);
+        assert_eq!(psbt.outputs[1].tap_key_origins.len(), 1);
+        assert!(wallet.txout_index().is_used(k_tr, index));
+        assert_eq!(
+            wallet.staged().unwrap().keyring.change_keychains,
+            [(k_wpkh, k_tr)].into()
+        );
+    }
+
+    #[test]
+    fn spend_from_keychains() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let k2 = descriptor_id(DESCRIPTORS[2]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(100_000));
+        let op1 = receive_confirmed(&mut wallet, k1, Amount::from_sat(50_000));
+        wallet.set_change_keychain(k1, k2);
+
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(10_000))
+            .spend_from([k1])
+            .finish()
+            .unwrap();
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input[0].previous_output, op1);
+        assert_eq!(
+            wallet
+                .txout_index()
+                .index_of_spk(tx.output[1].script_pubkey.clone())
+                .map(|(k, _)| *k),
+            Some(k2)
+        );
+
+        match wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(60_000))
+            .spend_from([k1])
+            .finish()
+        {
+            Err(CreateTxError::InsufficientFunds { needed, available }) => {
+                assert!(needed > Amount::from_sat(60_000));
+                assert_eq!(available, Amount::from_sat(50_000));
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn fee_too_high() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+
+        for fee_rate in [FeeRate::MAX, FeeRate::from_sat_per_vb_u32(u32::MAX)] {
+            assert_eq!(
+                wallet
+                    .build_tx()
+                    .add_recipient(recipient(), Amount::from_sat(10_000))
+                    .fee_rate(fee_rate)
+                    .finish(),
+                Err(CreateTxError::FeeTooHigh)
+            );
+        }
+        assert_eq!(
+            wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(10_000))
+                .fee_absolute(Amount::MAX)
+                .finish(),
+            Err(CreateTxError::FeeTooHigh)
+        );
+    }
+
+    #[test]
+    fn change_to_fresh_address() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+        // Handed out to receive a payment, but not used yet.
+        let handed_out = wallet.reveal_next_address(k0).unwrap();
+
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(10_000))
+            .finish()
+            .unwrap();
+        let change = &psbt.unsigned_tx.output[1].script_pubkey;
+        assert_ne!(*change, handed_out.address.script_pubkey());
+        assert_eq!(
+            wallet.txout_index().index_of_spk(change.clone()),
+            Some(&(k0, handed_out.index + 1))
+        );
+        assert_eq!(
+            wallet.next_unused_address(k0).unwrap().index,
+            handed_out.index
+        );
+    }
+
+    #[test]
+    fn absolute_fee_and_no_change() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+        let staged = wallet.staged().cloned();
+
+        // The remainder is below the dust limit, so it goes to the fee.
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(49_500))
+            .fee_absolute(Amount::from_sat(300))
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(psbt.fee().unwrap(), Amount::from_sat(500));
+        assert_eq!(wallet.staged().cloned(), staged);
+
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(40_000))
+            .fee_absolute(Amount::from_sat(300))
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.output[1].value, Amount::from_sat(9_700));
+        assert_eq!(psbt.fee().unwrap(), Amount::from_sat(300));
+    }
+
+    #[test]
+    fn invalid_builders() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let unknown = descriptor_id(DESCRIPTORS[1]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(50_000));
+
+        assert_eq!(wallet.build_tx().finish(), Err(CreateTxError::NoRecipients));
+        assert_eq!(
+            wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(1))
+                .finish(),
+            Err(CreateTxError::OutputBelowDustLimit(0))
+        );
+        assert_eq!(
+            wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(1_000))
+                .spend_from([unknown])
+                .finish(),
+            Err(CreateTxError::UnknownKeychain(unknown))
+        );
+        assert_eq!(
+            wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(1_000))
+                .change_keychain(unknown)
+                .finish(),
+            Err(CreateTxError::UnknownKeychain(unknown))
+        );
+    }
+
+    #[test]
+    fn custom_coin_selection() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0]]);
+        let k = descriptor_id(DESCRIPTORS[0]);
+        let old = receive_confirmed(&mut wallet, k, Amount::from_sat(20_000));
+        let new = receive_confirmed(&mut wallet, k, Amount::from_sat(50_000));
+
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(10_000))
+            .coin_selection(OldestFirst)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, old);
+
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(10_000))
+            .coin_selection(LargestFirst)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, new);
+    }
+
+    #[test]
+    fn spending_priority() {
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], TR_DESCRIPTOR, PKH_DESCRIPTOR]);
+        let op_wpkh = receive_confirmed(
+            &mut wallet,
+            descriptor_id(DESCRIPTORS[0]),
+            Amount::from_sat(30_000),
+        );
+        let op_tr = receive_confirmed(
+            &mut wallet,
+            descriptor_id(TR_DESCRIPTOR),
+            Amount::from_sat(30_000),
+        );
+        let op_pkh = receive_confirmed(
+            &mut wallet,
+            descriptor_id(PKH_DESCRIPTOR),
+            Amount::from_sat(30_000),
+        );
+        // Drain the legacy keychain while fees are low, prefer taproot otherwise.
+        wallet.set_spending_priority(
+            SpendingPriority::new([Preference::DescriptorType(DescriptorType::Tr)]).up_to_fee_rate(
+                FeeRate::from_sat_per_vb_u32(5),
+                [Preference::DescriptorType(DescriptorType::Pkh)],
+            ),
+        );
+        let mut inputs = |fee_rate, amount| {
+            let psbt = wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(amount))
+                .fee_rate(FeeRate::from_sat_per_vb_u32(fee_rate))
+                .finish()
+                .unwrap();
+            let mut inputs = psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>();
+            inputs.sort();
+            inputs
+        };
+
+        assert_eq!(inputs(2, 10_000), [op_pkh]);
+        assert_eq!(inputs(10, 10_000), [op_tr]);
+        // The preferred keychain is not enough, the others are spent from as well.
+        let spent = inputs(10, 40_000);
+        assert_eq!(spent.len(), 2);
+        assert!(spent.contains(&op_tr));
+        let spent = inputs(2, 80_000);
+        let mut all = vec![op_wpkh, op_tr, op_pkh];
+        all.sort();
+        assert_eq!(spent, all);
+
+        // An absolute fee uses the default order.
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(10_000))
+            .fee_absolute(Amount::from_sat(1_000))
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, op_tr);
+
+        // A large coin of a later tier does not replace the preferred one.
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], TR_DESCRIPTOR]);
+        let op_tr = receive_confirmed(
+            &mut wallet,
+            descriptor_id(TR_DESCRIPTOR),
+            Amount::from_sat(30_000),
+        );
+        let op_wpkh = receive_confirmed(
+            &mut wallet,
+            descriptor_id(DESCRIPTORS[0]),
+            Amount::from_sat(100_000),
+        );
+        wallet.set_spending_priority(SpendingPriority::new([Preference::DescriptorType(
+            DescriptorType::Tr,
+        )]));
+        let psbt = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(40_000))
+            .fee_rate(FeeRate::from_sat_per_vb_u32(10))
+            .finish()
+            .unwrap();
+        let spent = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(spent, [op_tr, op_wpkh].into());
+    }
+
+    #[test]
+    fn spending_path() {
+        use core::str::FromStr;
+
+        use bitcoin::{absolute::LockTime, bip32::DerivationPath, relative};
+
+        let xpub = "tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7";
+        let key = |i: u32| DescriptorPublicKey::from_str(&alloc::format!("{xpub}/{i}/*")).unwrap();
+        let wsh = alloc::format!("wsh(or_d(pk({}),and_v(v:pk({}),older(6))))", key(8), key(9));
+        let tr = alloc::format!("tr({},and_v(v:pk({}),after(100)))", key(10), key(11));
+        let tr_time = alloc::format!("tr({},and_v(v:pk({}),after(1700000000)))", key(12), key(13));
+        let mut wallet = new_wallet(&[DESCRIPTORS[0], &wsh, &tr, &tr_time]);
+        let k_wsh = descriptor_id(&wsh);
+        let k_tr = descriptor_id(&tr);
+        let k_tr_time = descriptor_id(&tr_time);
+        receive_confirmed(&mut wallet, k_wsh, Amount::from_sat(50_000));
+        let wsh_height = wallet.latest_checkpoint().height();
+        receive_confirmed(&mut wallet, k_tr, Amount::from_sat(50_000));
+        receive_confirmed(&mut wallet, k_tr_time, Amount::from_sat(50_000));
+
+        let policy = wallet.policy(&k_wsh).unwrap();
+        assert_eq!(policy.relative_timelocks(), [6]);
+        assert_eq!(policy.n_keys(), 2);
+        assert_eq!(wallet.policy(&descriptor_id(DESCRIPTORS[1])), None);
+
+        let build = |wallet: &mut Wallet<DescriptorId>, keychain, assets: Option<Assets>| {
+            let mut builder = wallet
+                .build_tx()
+                .add_recipient(recipient(), Amount::from_sat(20_000))
+                .spend_from([keychain])
+                .anti_fee_sniping(AntiFeeSniping::Disabled);
+            if let Some(assets) = assets {
+                builder = builder.spending_assets(keychain, assets);
+            }
+            builder.finish()
+        };
+
+        // By default every key of the descriptor is added.
+        let psbt = build(&mut wallet, k_wsh, None).unwrap();
+        assert_eq!(psbt.inputs[0].bip32_derivation.len(), 2);
+
+        let psbt = build(&mut wallet, k_wsh, Some(Assets::new().add(key(8)))).unwrap();
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence,
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+        assert_eq!(psbt.unsigned_tx.lock_time, LockTime::ZERO);
+        let origins = psbt.inputs[0].bip32_derivation.values().collect::<Vec<_>>();
+        assert_eq!(origins.len(), 1);
+        assert_eq!(origins[0].1, DerivationPath::from_str("m/8/0").unwrap());
+
+        // The recovery path, once its timelock is reached.
+        let older = relative::LockTime::from_height(6);
+        let recovery = || Some(Assets::new().add(key(9)).older(older));
+        assert_eq!(
+            build(&mut wallet, k_wsh, recovery()),
+            Err(CreateTxError::NoSpendingPath)
+        );
+        while wallet.latest_checkpoint().height() < wsh_height + 5 {
+            add_block(&mut wallet, vec![]);
+        }
+        let psbt = build(&mut wallet, k_wsh, recovery()).unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, older.to_sequence());
+        let origins = psbt.inputs[0].bip32_derivation.values().collect::<Vec<_>>();
+        assert_eq!(origins.len(), 1);
+        assert_eq!(origins[0].1, DerivationPath::from_str("m/9/0").unwrap());
+        assert_eq!(
+            build(&mut wallet, k_wsh, Some(Assets::new().add(key(9)))),
+            Err(CreateTxError::NoSpendingPath)
+        );
+
+        let after = LockTime::from_height(100).unwrap();
+        let tr_assets = || Some(Assets::new().add(key(11)).after(after));
+        assert_eq!(
+            build(&mut wallet, k_tr, tr_assets()),
+            Err(CreateTxError::NoSpendingPath)
+        );
+        while wallet.latest_checkpoint().height() < 100 {
+            add_block(&mut wallet, vec![]);
+        }
+        let psbt = build(&mut wallet, k_tr, tr_assets()).unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time, after);
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence,
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+        assert_eq!(psbt.inputs[0].tap_key_origins.len(), 1);
+        assert_eq!(psbt.inputs[0].tap_scripts.len(), 1);
+
+        // Timelocks in blocks and time can not be combined.
+        let tx = receive_tx(
+            &mut wallet,
+            descriptor_id(DESCRIPTORS[0]),
+            Amount::from_sat(1_000),
+        );
+        add_block_at(&mut wallet, vec![tx], 1_700_000_001);
+        let time = LockTime::from_time(1_700_000_000).unwrap();
+        let result = wallet
+            .build_tx()
+            .add_recipient(recipient(), Amount::from_sat(90_000))
+            .spend_from([k_tr, k_tr_time])
+            .spending_assets(k_tr, Assets::new().add(key(11)).after(after))
+            .spending_assets(k_tr_time, Assets::new().add(key(13)).after(time))
+            .finish();
+        assert_eq!(result, Err(CreateTxError::IncompatibleLockTimes));
+    }
+}
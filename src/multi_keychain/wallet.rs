@@ -1,5 +1,6 @@
 use alloc::{
     collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::String,
     sync::Arc,
 };
 use core::{
@@ -7,31 +8,50 @@ use core::{
     ops::Deref,
 };
 
-use bitcoin::{Address, Block, Transaction, Txid};
-use miniscript::{Descriptor, DescriptorPublicKey};
+use bdk_wallet::descriptor::IntoWalletDescriptor;
+use bitcoin::{
+    secp256k1::{All, Secp256k1},
+    Address, Block, Network, OutPoint, Transaction, TxOut, Txid,
+};
+use miniscript::{plan::Assets, Descriptor, DescriptorPublicKey};
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "rusqlite")]
 use bdk_chain::rusqlite;
 use bdk_chain::{
     keychain_txout::{
-        FullScanRequestBuilderExt, KeychainTxOutIndex, SyncRequestBuilderExt, DEFAULT_LOOKAHEAD,
+        self, FullScanRequestBuilderExt, InsertDescriptorError, KeychainTxOutIndex,
+        SyncRequestBuilderExt, DEFAULT_LOOKAHEAD,
     },
     local_chain::{ApplyHeaderError, LocalChain},
     spk_client::{
         FullScanRequest, FullScanRequestBuilder, FullScanResponse, SyncRequest, SyncRequestBuilder,
         SyncResponse,
     },
-    BlockId, CanonicalizationParams, CheckPoint, ConfirmationBlockTime, IndexedTxGraph, Merge,
+    BlockId, CanonicalizationParams, ChainPosition, CheckPoint, ConfirmationBlockTime, FullTxOut,
+    IndexedTxGraph, Merge,
 };
 
 use crate::bdk_chain;
-use crate::multi_keychain::{ChangeSet, KeyRing};
+use crate::multi_keychain::{
+    keyring, ChangeSet, KeyRing, NextBlock, QueuedPayment, SpendableFrom, SpendingPriority,
+};
 
 /// Alias for a [`IndexedTxGraph`].
 type KeychainTxGraph<K> = IndexedTxGraph<ConfirmationBlockTime, KeychainTxOutIndex<K>>;
 
-// This is here for dev purposes and can be made a configurable option as part of the final API.
-const USE_SPK_CACHE: bool = false;
+/// Take the script pubkeys derived and cached by the `index` that have not been reported in a
+/// changeset yet.
+fn take_cached_spks<K>(index: &mut KeychainTxOutIndex<K>) -> keychain_txout::ChangeSet
+where
+    K: fmt::Debug + Clone + Ord,
+{
+    // Revealing nothing still hands over the newly cached script pubkeys.
+    let mut changeset = index.reveal_to_target_multi(&BTreeMap::new());
+    // Keychains whose script pubkeys were all found in the cache are reported with no entries.
+    changeset.spk_cache.retain(|_, spks| !spks.is_empty());
+    changeset
+}
 
 /// [`Wallet`] is a structure that stores transaction data that can be indexed by multiple
 /// keychains.
@@ -39,8 +59,15 @@ const USE_SPK_CACHE: bool = false;
 pub struct Wallet<K: Ord> {
     keyring: KeyRing<K>,
     chain: LocalChain,
-    tx_graph: KeychainTxGraph<K>,
+    /// Shared with the checkpoints of [`WalletTransaction`](crate::multi_keychain::WalletTransaction)s
+    /// and copied on write, see [`Wallet::tx_graph_mut`].
+    tx_graph: Arc<KeychainTxGraph<K>>,
     stage: ChangeSet<K>,
+    trusted_keychains: BTreeSet<K>,
+    utxo_locks: BTreeMap<OutPoint, UtxoLock>,
+    payments: BTreeMap<String, QueuedPayment>,
+    spending_priority: SpendingPriority<K>,
+    keychain_assets: BTreeMap<K, Arc<Assets>>,
 }
 
 impl<K> Wallet<K>
@@ -48,7 +75,17 @@ where
     K: fmt::Debug + Clone + Ord,
 {
     /// Construct a new [`Wallet`] with the given `keyring`.
-    pub fn new(mut keyring: KeyRing<K>) -> Self {
+    pub fn new(keyring: KeyRing<K>) -> Self {
+        Self::new_with_spk_cache(keyring, false)
+    }
+
+    /// Construct a new [`Wallet`] with the given `keyring`, choosing whether to cache the derived
+    /// script pubkeys.
+    ///
+    /// If `use_spk_cache` is `true`, every script pubkey derived by the wallet is staged along
+    /// with the rest of the changes so that it can be persisted and reloaded instead of being
+    /// derived again, see [`from_changeset_with_spk_cache`](Self::from_changeset_with_spk_cache).
+    pub fn new_with_spk_cache(mut keyring: KeyRing<K>, use_spk_cache: bool) -> Self {
         let network = keyring.network;
 
         let genesis_hash = bitcoin::constants::genesis_block(network).block_hash();
@@ -56,7 +93,7 @@ where
 
         let keyring_changeset = keyring.initial_changeset();
 
-        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, USE_SPK_CACHE);
+        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, use_spk_cache);
         let descriptors = core::mem::take(&mut keyring.descriptors);
         for (keychain, desc) in descriptors {
             let _inserted = index
@@ -64,14 +101,20 @@ where
                 .expect("err: failed to insert descriptor");
             assert!(_inserted);
         }
+        let index_changeset = take_cached_spks(&mut index);
 
-        let tx_graph = KeychainTxGraph::new(index);
+        let tx_graph = Arc::new(KeychainTxGraph::new(index));
 
         let stage = ChangeSet {
             keyring: keyring_changeset,
             local_chain: chain_changeset,
             tx_graph: bdk_chain::tx_graph::ChangeSet::default(),
-            indexer: bdk_chain::keychain_txout::ChangeSet::default(),
+            indexer: index_changeset,
+            marked_used: BTreeMap::new(),
+            utxo_locks: BTreeMap::new(),
+            payments: BTreeMap::new(),
+            trusted_keychains: BTreeMap::new(),
+            spending_priority: None,
         };
 
         Self {
@@ -79,13 +122,35 @@ where
             chain,
             tx_graph,
             stage,
+            trusted_keychains: BTreeSet::new(),
+            utxo_locks: BTreeMap::new(),
+            payments: BTreeMap::new(),
+            spending_priority: SpendingPriority::default(),
+            keychain_assets: BTreeMap::new(),
         }
     }
 
     /// Construct [`Wallet`] from the provided `changeset`.
     ///
+    /// The script pubkey cache is used if the `changeset` contains cached script pubkeys.
+    ///
     /// Will be `None` if the changeset is empty.
     pub fn from_changeset(changeset: ChangeSet<K>) -> Option<Self> {
+        let use_spk_cache = !changeset.indexer.spk_cache.is_empty();
+        Self::from_changeset_with_spk_cache(changeset, use_spk_cache)
+    }
+
+    /// Construct [`Wallet`] from the provided `changeset`, choosing whether to use the script
+    /// pubkey cache.
+    ///
+    /// If `use_spk_cache` is `true`, the script pubkeys cached in the `changeset` are loaded
+    /// instead of being derived. Script pubkeys missing from the cache are derived and staged.
+    ///
+    /// Will be `None` if the changeset is empty.
+    pub fn from_changeset_with_spk_cache(
+        changeset: ChangeSet<K>,
+        use_spk_cache: bool,
+    ) -> Option<Self> {
         if changeset.is_empty() {
             return None;
         }
@@ -98,7 +163,7 @@ where
         let mut keyring = KeyRing::from_changeset(changeset.keyring)?;
 
         // index
-        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, USE_SPK_CACHE);
+        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, use_spk_cache);
         index.apply_changeset(changeset.indexer);
         for (keychain, descriptor) in core::mem::take(&mut keyring.descriptors) {
             let _inserted = index
@@ -106,18 +171,55 @@ where
                 .expect("failed to insert descriptor");
             assert!(_inserted);
         }
+        let index_changeset = take_cached_spks(&mut index);
 
         // txgraph
         let mut tx_graph = KeychainTxGraph::new(index);
         tx_graph.apply_changeset(changeset.tx_graph.into());
 
-        let stage = ChangeSet::default();
+        // utxo locks
+        let utxo_locks = changeset
+            .utxo_locks
+            .into_iter()
+            .filter_map(|(outpoint, lock)| Some((outpoint, lock?)))
+            .collect();
+
+        // payment queue
+        let payments = changeset
+            .payments
+            .into_iter()
+            .filter_map(|(id, payment)| Some((id, payment?)))
+            .collect();
+
+        // trusted keychains
+        let trusted_keychains = changeset
+            .trusted_keychains
+            .into_iter()
+            .filter(|(_, trusted)| *trusted)
+            .map(|(keychain, _)| keychain)
+            .collect();
+
+        // used marks, applied once the script pubkeys and their outputs are indexed
+        for (keychain, marks) in changeset.marked_used {
+            for (index, used) in marks {
+                if used {
+                    tx_graph.index.mark_used(keychain.clone(), index);
+                }
+            }
+        }
+
+        let stage = index_changeset.into();
 
         Some(Self {
-            tx_graph,
+            tx_graph: Arc::new(tx_graph),
             stage,
             chain,
             keyring,
+            trusted_keychains,
+            utxo_locks,
+            payments,
+            spending_priority: changeset.spending_priority.unwrap_or_default(),
+            keychain_assets: BTreeMap::new(),
         })
     }
 
@@ -132,8 +234,10 @@ where
     ///
     /// This may return the last revealed address in case there are none left to reveal.
     pub fn reveal_next_address(&mut self, keychain: K) -> Option<AddressInfo<K>> {
-        let ((index, spk), index_changeset) =
-            self.tx_graph.index.reveal_next_spk(keychain.clone())?;
+        let ((index, spk), index_changeset) = self
+            .tx_graph_mut()
+            .index
+            .reveal_next_spk(keychain.clone())?;
         let address = Address::from_script(&spk, self.keyring.network)
             .expect("script should have address form");
 
@@ -146,6 +250,164 @@ where
         })
     }
 
+    /// Reveal the addresses of `keychain` up to and including `index`.
+    ///
+    /// Returns the newly revealed addresses, which is empty if they were all revealed already or
+    /// if the wallet has no such keychain.
+    pub fn reveal_addresses_to(
+        &mut self,
+        keychain: K,
+        index: u32,
+    ) -> impl Iterator<Item = AddressInfo<K>> {
+        let (spks, index_changeset) = self
+            .tx_graph_mut()
+            .index
+            .reveal_to_target(keychain.clone(), index)
+            .unwrap_or_default();
+
+        self.stage(index_changeset);
+
+        let network = self.keyring.network;
+        spks.into_iter().map(move |(index, spk)| AddressInfo {
+            index,
+            address: Address::from_script(&spk, network).expect("script should have address form"),
+            keychain: keychain.clone(),
+        })
+    }
+
+    /// Get the lowest revealed address of `keychain` that is unused, revealing the next address
+    /// only if all revealed addresses are used.
+    ///
+    /// Unlike [`reveal_next_address`](Self::reveal_next_address) repeated calls return the same
+    /// address until it is used, either on-chain or by [`mark_used`](Self::mark_used).
+    pub fn next_unused_address(&mut self, keychain: K) -> Option<AddressInfo<K>> {
+        let ((index, spk), index_changeset) = self
+            .tx_graph_mut()
+            .index
+            .next_unused_spk(keychain.clone())?;
+        let address = Address::from_script(&spk, self.keyring.network)
+            .expect("script should have address form");
+
+        self.stage(index_changeset);
+
+        Some(AddressInfo {
+            index,
+            address,
+            keychain,
+        })
+    }
+
+    /// Derive the address of `keychain` at `index` without revealing it.
+    ///
+    /// Returns `None` if the wallet has no such keychain or `index` is hardened.
+    pub fn peek_address(&self, keychain: K, index: u32) -> Option<AddressInfo<K>> {
+        let address = self
+            .tx_graph
+            .index
+            .get_descriptor(keychain.clone())?
+            .at_derivation_index(index)
+            .ok()?
+            .address(self.keyring.network)
+            .expect("descriptor should have address form");
+
+        Some(AddressInfo {
+            index,
+            address,
+            keychain,
+        })
+    }
+
+    /// Mark the revealed address of `keychain` at `index` as used, e.g. because it has been handed
+    /// out, so that [`next_unused_address`](Self::next_unused_address) skips it.
+    ///
+    /// Returns whether the address was unused before. The mark is staged.
+    pub fn mark_used(&mut self, keychain: K, index: u32) -> bool {
+        let marked = self.tx_graph_mut().index.mark_used(keychain.clone(), index);
+        if marked {
+            self.stage_used_mark(keychain, index, true);
+        }
+        marked
+    }
+
+    /// Undo the effect of [`mark_used`](Self::mark_used).
+    ///
+    /// Returns whether the address is unused again, which is not the case if an output paying to
+    /// it has been seen. The change is staged.
+    pub fn unmark_used(&mut self, keychain: K, index: u32) -> bool {
+        let unmarked = self
+            .tx_graph_mut()
+            .index
+            .unmark_used(keychain.clone(), index);
+        if unmarked {
+            self.stage_used_mark(keychain, index, false);
+        }
+        unmarked
+    }
+
+    fn stage_used_mark(&mut self, keychain: K, index: u32, used: bool) {
+        self.stage(ChangeSet {
+            marked_used: BTreeMap::from([(keychain, BTreeMap::from([(index, used)]))]),
+            ..Default::default()
+        });
+    }
+
+    /// The revealed script pubkeys marked as used even though no output pays to them.
+    pub(crate) fn marked_used(&self) -> BTreeMap<K, BTreeMap<u32, bool>> {
+        let index = &self.tx_graph.index;
+        let mut marked_used = BTreeMap::<K, BTreeMap<u32, bool>>::new();
+        for ((keychain, i), _) in index.revealed_spks(..) {
+            let spk_i = (keychain.clone(), i);
+            let has_outputs = index
+                .inner()
+                .outputs_in_range(spk_i.clone()..=spk_i)
+                .next()
+                .is_some();
+            if index.is_used(keychain.clone(), i) && !has_outputs {
+                marked_used.entry(keychain).or_default().insert(i, true);
+            }
+        }
+        marked_used
+    }
+
+    /// Add a `descriptor` to the wallet under the given `keychain`, must not be
+    /// [multipath](Descriptor::is_multipath).
+    ///
+    /// Transactions already in the wallet are re-indexed so that outputs of the new keychain are
+    /// picked up. Returns whether the keychain was added, which is `false` if the same `keychain`
+    /// and `descriptor` pair is already tracked.
+    pub fn add_descriptor(
+        &mut self,
+        keychain: K,
+        descriptor: impl IntoWalletDescriptor,
+    ) -> Result<bool, InsertDescriptorError<K>> {
+        let descriptor = descriptor
+            .into_wallet_descriptor(&self.keyring.secp, self.keyring.network)
+            .expect("err: invalid descriptor")
+            .0;
+        assert!(
+            !descriptor.is_multipath(),
+            "err: multipath descriptors are not supported"
+        );
+
+        let inserted = self
+            .tx_graph_mut()
+            .index
+            .insert_descriptor(keychain.clone(), descriptor.clone())?;
+        if inserted {
+            let mut changeset = ChangeSet {
+                keyring: keyring::ChangeSet {
+                    descriptors: BTreeMap::from([(keychain, descriptor)]),
+                    ..Default::default()
+                },
+                indexer: take_cached_spks(&mut self.tx_graph_mut().index),
+                ..Default::default()
+            };
+            changeset.merge(self.tx_graph_mut().reindex().into());
+            self.stage(changeset);
+        }
+        Ok(inserted)
+    }
+
     /// Iterate over `(keychain, descriptor)` pairs contained in this wallet.
     pub fn keychains(
         &self,
@@ -158,20 +420,463 @@ where
         self.keyring.default_keychain()
     }
 
+    /// Get the network the wallet is on.
+    pub fn network(&self) -> Network {
+        self.keyring.network
+    }
+
+    /// The secp256k1 context of the keyring, for signing and verification.
+    pub(crate) fn secp_ctx(&self) -> &Secp256k1<All> {
+        &self.keyring.secp
+    }
+
     /// Compute the balance.
     pub fn balance(&self) -> bdk_chain::Balance {
-        use bdk_chain::CanonicalizationParams;
+        self.balance_of_outpoints(self.tx_graph.index.outpoints().clone())
+    }
+
+    /// Compute the balance of the given `keychains` taken together.
+    pub fn balance_of(&self, keychains: impl IntoIterator<Item = K>) -> bdk_chain::Balance {
+        let keychains = keychains.into_iter().collect::<BTreeSet<_>>();
+        self.balance_of_outpoints(
+            self.tx_graph
+                .index
+                .outpoints()
+                .iter()
+                .filter(|((keychain, _), _)| keychains.contains(keychain))
+                .cloned(),
+        )
+    }
+
+    /// Compute the balance of every keychain of the wallet.
+    pub fn balance_by_keychain(&self) -> BTreeMap<K, bdk_chain::Balance> {
+        let chain = &self.chain;
+        let tip = chain.tip().block_id();
+        let mut balances = self
+            .keychains()
+            .map(|(keychain, _)| (keychain, bdk_chain::Balance::default()))
+            .collect::<BTreeMap<_, _>>();
+        let unspents = self.tx_graph.graph().filter_chain_unspents(
+            chain,
+            tip,
+            CanonicalizationParams::default(),
+            self.tx_graph.index.outpoints().clone(),
+        );
+        // This mirrors how `TxGraph::balance` classifies outputs.
+        for ((keychain, _), txout) in unspents {
+            let balance = balances.entry(keychain.clone()).or_default();
+            match txout.chain_position {
+                ChainPosition::Confirmed { .. } => {
+                    if txout.is_confirmed_and_spendable(tip.height) {
+                        balance.confirmed += txout.txout.value;
+                    } else if !txout.is_mature(tip.height) {
+                        balance.immature += txout.txout.value;
+                    }
+                }
+                ChainPosition::Unconfirmed { .. } => {
+                    if self.trusted_keychains.contains(&keychain) {
+                        balance.trusted_pending += txout.txout.value;
+                    } else {
+                        balance.untrusted_pending += txout.txout.value;
+                    }
+                }
+            }
+        }
+        balances
+    }
+
+    /// Compute the balance of the keychain group called `name`, if it exists.
+    ///
+    /// See [`KeyRing::add_group`].
+    pub fn balance_of_group(&self, name: &str) -> Option<bdk_chain::Balance> {
+        let keychains = self.keyring.groups.get(name)?;
+        Some(self.balance_of(keychains.iter().cloned()))
+    }
+
+    /// Compute the balance of every keychain group.
+    ///
+    /// See [`KeyRing::add_group`].
+    pub fn balance_by_group(&self) -> BTreeMap<String, bdk_chain::Balance> {
+        let balances = self.balance_by_keychain();
+        self.keyring
+            .groups
+            .iter()
+            .map(|(name, keychains)| {
+                let balance = keychains
+                    .iter()
+                    .filter_map(|keychain| balances.get(keychain).cloned())
+                    .fold(bdk_chain::Balance::default(), |acc, b| acc + b);
+                (name.clone(), balance)
+            })
+            .collect()
+    }
+
+    /// Compute the balance of the indexed `outpoints`.
+    fn balance_of_outpoints(
+        &self,
+        outpoints: impl IntoIterator<Item = ((K, u32), OutPoint)>,
+    ) -> bdk_chain::Balance {
         let chain = &self.chain;
-        let outpoints = self.tx_graph.index.outpoints().clone();
         self.tx_graph.graph().balance(
             chain,
             chain.tip().block_id(),
             CanonicalizationParams::default(),
             outpoints,
-            |_, _| false,
+            |(keychain, _), _| self.trusted_keychains.contains(keychain),
         )
     }
 
+    /// Set whether unconfirmed outputs of the `keychain` are trusted.
+    ///
+    /// Trusted unconfirmed outputs count towards the `trusted_pending` part of the balances and
+    /// are returned by [`Wallet::list_spendable`]. This is typically set for keychains receiving
+    /// the wallet's own change, as opposed to keychains handed out to third parties. By default no
+    /// keychain is trusted. The change is staged.
+    pub fn set_keychain_trusted(&mut self, keychain: K, trusted: bool) {
+        let changed = if trusted {
+            self.trusted_keychains.insert(keychain.clone())
+        } else {
+            self.trusted_keychains.remove(&keychain)
+        };
+        if changed {
+            self.stage(ChangeSet {
+                trusted_keychains: BTreeMap::from([(keychain, trusted)]),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Whether unconfirmed outputs of the `keychain` are trusted.
+    ///
+    /// See [`Wallet::set_keychain_trusted`].
+    pub fn is_keychain_trusted(&self, keychain: &K) -> bool {
+        self.trusted_keychains.contains(keychain)
+    }
+
+    /// The keychains whose unconfirmed outputs are trusted.
+    pub(crate) fn trusted_keychains(&self) -> impl Iterator<Item = K> + '_ {
+        self.trusted_keychains.iter().cloned()
+    }
+
+    /// Set the order in which keychains are spent from by [`Wallet::build_tx`]. By default all
+    /// keychains are spent from alike. The change is staged.
+    pub fn set_spending_priority(&mut self, spending_priority: SpendingPriority<K>) {
+        if spending_priority != self.spending_priority {
+            self.spending_priority = spending_priority.clone();
+            self.stage(ChangeSet {
+                spending_priority: Some(spending_priority),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// The order in which keychains are spent from.
+    ///
+    /// See [`Wallet::set_spending_priority`].
+    pub fn spending_priority(&self) -> &SpendingPriority<K> {
+        &self.spending_priority
+    }
+
+    /// Set the `assets` available to spend the outputs of `keychain`, i.e. the keys that will sign
+    /// and the timelocks that can be satisfied, or unset them with `None`.
+    ///
+    /// The outputs are then expected to be spent through their cheapest spending path with these
+    /// assets, which determines when they become spendable, see [`LocalOutput::spendable_from`],
+    /// and is used by [`Wallet::build_tx`] unless overridden with
+    /// [`TxBuilder::spending_assets`](crate::multi_keychain::TxBuilder::spending_assets). By
+    /// default every key is assumed to sign, and the cheapest spending path available at the tip
+    /// is used, or else the one whose timelocks are reached first.
+    ///
+    /// The assets are not staged: they describe the signers and timelocks available to this
+    /// process rather than the wallet, and can hold private data such as hash preimages, so they
+    /// must be set again after loading the wallet.
+    pub fn set_keychain_assets(&mut self, keychain: K, assets: Option<Assets>) {
+        match assets {
+            Some(assets) => self.keychain_assets.insert(keychain, Arc::new(assets)),
+            None => self.keychain_assets.remove(&keychain),
+        };
+    }
+
+    /// The assets available to spend the outputs of `keychain`, if set.
+    ///
+    /// See [`Wallet::set_keychain_assets`].
+    pub fn keychain_assets(&self, keychain: &K) -> Option<&Assets> {
+        self.keychain_assets
+            .get(keychain)
+            .map(|assets| assets.as_ref())
+    }
+
+    /// Lock the `outpoint` so that it is not spent, until it is unlocked or the tip of the local
+    /// chain reaches the `expiry` height, if any.
+    ///
+    /// Locked outputs are still listed by [`Wallet::list_unspent`] but not by
+    /// [`Wallet::list_spendable`]. Returns `false`, doing nothing, if the `outpoint` is frozen.
+    /// The lock is staged.
+    pub fn lock_outpoint(&mut self, outpoint: OutPoint, expiry: Option<u32>) -> bool {
+        if self.utxo_locks.get(&outpoint) == Some(&UtxoLock::Frozen) {
+            return false;
+        }
+        self.set_utxo_lock(outpoint, Some(UtxoLock::Locked { expiry }));
+        true
+    }
+
+    /// Unlock the `outpoint` locked with [`Wallet::lock_outpoint`].
+    ///
+    /// Returns whether the `outpoint` was locked, expired or not. Frozen outpoints are left
+    /// untouched, see [`Wallet::unfreeze_outpoint`]. The change is staged.
+    pub fn unlock_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        match self.utxo_locks.get(&outpoint) {
+            Some(UtxoLock::Locked { .. }) => {
+                self.set_utxo_lock(outpoint, None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Freeze the `outpoint` so that it is not spent until it is unfrozen, replacing any lock.
+    ///
+    /// The change is staged.
+    pub fn freeze_outpoint(&mut self, outpoint: OutPoint) {
+        self.set_utxo_lock(outpoint, Some(UtxoLock::Frozen));
+    }
+
+    /// Unfreeze the `outpoint` frozen with [`Wallet::freeze_outpoint`].
+    ///
+    /// Returns whether the `outpoint` was frozen. The change is staged.
+    pub fn unfreeze_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        match self.utxo_locks.get(&outpoint) {
+            Some(UtxoLock::Frozen) => {
+                self.set_utxo_lock(outpoint, None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Get the lock in effect on the `outpoint`, if any.
+    ///
+    /// Locks that expired at the current tip are not reported.
+    pub fn outpoint_lock(&self, outpoint: OutPoint) -> Option<UtxoLock> {
+        let lock = *self.utxo_locks.get(&outpoint)?;
+        match lock {
+            UtxoLock::Locked {
+                expiry: Some(expiry),
+            } if self.chain.tip().height() >= expiry => None,
+            lock => Some(lock),
+        }
+    }
+
+    /// Iterate over the outpoints with a lock in effect.
+    pub fn locked_outpoints(&self) -> impl Iterator<Item = (OutPoint, UtxoLock)> + '_ {
+        self.utxo_locks
+            .keys()
+            .filter_map(|&outpoint| Some((outpoint, self.outpoint_lock(outpoint)?)))
+    }
+
+    fn set_utxo_lock(&mut self, outpoint: OutPoint, lock: Option<UtxoLock>) {
+        match lock {
+            Some(lock) => self.utxo_locks.insert(outpoint, lock),
+            None => self.utxo_locks.remove(&outpoint),
+        };
+        self.stage(ChangeSet {
+            utxo_locks: BTreeMap::from([(outpoint, lock)]),
+            ..Default::default()
+        });
+    }
+
+    /// All the locks set on outpoints, including expired ones.
+    pub(crate) fn utxo_locks(&self) -> BTreeMap<OutPoint, Option<UtxoLock>> {
+        self.utxo_locks
+            .iter()
+            .map(|(&outpoint, &lock)| (outpoint, Some(lock)))
+            .collect()
+    }
+
+    /// The payments in the queue by id, see [`Wallet::enqueue_payment`].
+    pub(crate) fn payment_queue(&self) -> &BTreeMap<String, QueuedPayment> {
+        &self.payments
+    }
+
+    /// Set the payment with `id` in the queue, removing it if `None`, and stage the change.
+    pub(crate) fn set_queued_payment(&mut self, id: String, payment: Option<QueuedPayment>) {
+        match &payment {
+            Some(payment) => self.payments.insert(id.clone(), payment.clone()),
+            None => self.payments.remove(&id),
+        };
+        self.stage(ChangeSet {
+            payments: BTreeMap::from([(id, payment)]),
+            ..Default::default()
+        });
+    }
+
+    /// Pair `keychain` with the keychain receiving the change of transactions spending from it.
+    ///
+    /// See [`KeyRing::set_change_keychain`]. The pairing is staged.
+    pub fn set_change_keychain(&mut self, keychain: K, change_keychain: K) {
+        self.keyring
+            .set_change_keychain(keychain.clone(), change_keychain.clone());
+        self.stage(ChangeSet {
+            keyring: keyring::ChangeSet {
+                change_keychains: BTreeMap::from([(keychain, change_keychain)]),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+    }
+
+    /// Returns the keychain receiving the change of transactions spending from `keychain`.
+    ///
+    /// See [`KeyRing::change_keychain`].
+    pub fn change_keychain(&self, keychain: &K) -> K {
+        self.keyring.change_keychain(keychain)
+    }
+
+    /// Define a named group of keychains, replacing any existing group with the same `name`.
+    ///
+    /// Returns whether the group was added, which it is not if `keychains` is empty or holds a
+    /// keychain the wallet does not have. See [`KeyRing::add_group`].
+    pub fn add_keychain_group(
+        &mut self,
+        name: impl Into<String>,
+        keychains: impl IntoIterator<Item = K>,
+    ) -> bool {
+        let name = name.into();
+        let keychains = keychains.into_iter().collect::<BTreeSet<_>>();
+        if keychains.is_empty()
+            || !keychains
+                .iter()
+                .all(|k| self.tx_graph.index.get_descriptor(k.clone()).is_some())
+        {
+            return false;
+        }
+        self.keyring.add_group(name.clone(), keychains.clone());
+        self.stage(ChangeSet {
+            keyring: keyring::ChangeSet {
+                groups: BTreeMap::from([(name, keychains)]),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        true
+    }
+
+    /// Remove the keychain group called `name`. Returns whether the group existed.
+    pub fn remove_keychain_group(&mut self, name: &str) -> bool {
+        if self.keyring.groups.remove(name).is_none() {
+            return false;
+        }
+        self.stage(ChangeSet {
+            keyring: keyring::ChangeSet {
+                groups: BTreeMap::from([(name.into(), BTreeSet::new())]),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        true
+    }
+
+    /// Return the named groups of keychains.
+    pub fn keychain_groups(&self) -> &BTreeMap<String, BTreeSet<K>> {
+        self.keyring.groups()
+    }
+
+    /// List the unspent outputs of the wallet.
+    ///
+    /// Only outputs of canonical transactions are listed.
+    pub fn list_unspent(&self) -> impl Iterator<Item = LocalOutput<K>> + '_ {
+        self.list_unspent_of(self.tx_graph.index.outpoints().clone())
+    }
+
+    /// List the unspent outputs of the wallet that can be spent at the current tip.
+    ///
+    /// These are the mature outputs that are not locked nor frozen, and are either confirmed or
+    /// belong to a trusted keychain, see [`Wallet::set_keychain_trusted`] and
+    /// [`Wallet::lock_outpoint`].
+    pub fn list_spendable(&self) -> impl Iterator<Item = LocalOutput<K>> + '_ {
+        self.list_unspent().filter(|utxo| {
+            utxo.is_spendable
+                && utxo.lock.is_none()
+                && (utxo.chain_position.is_confirmed()
+                    || self.trusted_keychains.contains(&utxo.keychain))
+        })
+    }
+
+    /// List the unspent outputs of the wallet belonging to the given `keychains`.
+    ///
+    /// See [`Wallet::list_unspent`].
+    pub fn list_unspent_from(
+        &self,
+        keychains: impl IntoIterator<Item = K>,
+    ) -> impl Iterator<Item = LocalOutput<K>> + '_ {
+        let keychains = keychains.into_iter().collect::<BTreeSet<_>>();
+        let outpoints = self
+            .tx_graph
+            .index
+            .outpoints()
+            .iter()
+            .filter(|((keychain, _), _)| keychains.contains(keychain))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        self.list_unspent_of(outpoints)
+    }
+
+    /// List the unspent outputs among the indexed `outpoints`.
+    fn list_unspent_of(
+        &self,
+        outpoints: BTreeSet<((K, u32), OutPoint)>,
+    ) -> impl Iterator<Item = LocalOutput<K>> + '_ {
+        let tip = self.chain.tip().block_id();
+        let next = self.next_block();
+        self.tx_graph
+            .graph()
+            .filter_chain_unspents(
+                &self.chain,
+                tip,
+                CanonicalizationParams::default(),
+                outpoints,
+            )
+            .map(move |(index, txout)| self.to_local_output(index, txout, next))
+    }
+
+    /// The output of the wallet at `outpoint`, whether it is spent or not, if the transaction
+    /// creating it is canonical.
+    pub(crate) fn local_output(&self, outpoint: OutPoint) -> Option<LocalOutput<K>> {
+        let (index, _) = self.tx_graph.index.txout(outpoint)?;
+        let tip = self.chain.tip().block_id();
+        self.tx_graph
+            .graph()
+            .filter_chain_txouts(
+                &self.chain,
+                tip,
+                CanonicalizationParams::default(),
+                [(index, outpoint)],
+            )
+            .next()
+            .map(|(index, txout)| self.to_local_output(index, txout, self.next_block()))
+    }
+
+    fn to_local_output(
+        &self,
+        (keychain, derivation_index): (K, u32),
+        txout: FullTxOut<ConfirmationBlockTime>,
+        next: NextBlock,
+    ) -> LocalOutput<K> {
+        let spendable_from =
+            self.spendable_from(&keychain, derivation_index, &txout.chain_position, next);
+        LocalOutput {
+            is_spendable: txout.is_mature(next.height - 1)
+                && spendable_from.map_or(true, |from| next.is_reached(from)),
+            lock: self.outpoint_lock(txout.outpoint),
+            outpoint: txout.outpoint,
+            txout: txout.txout,
+            keychain,
+            derivation_index,
+            chain_position: txout.chain_position,
+            spendable_from,
+        }
+    }
+
     /// Obtain a reference to the indexed transaction graph.
     pub fn tx_graph(&self) -> &IndexedTxGraph<ConfirmationBlockTime, KeychainTxOutIndex<K>> {
         &self.tx_graph
@@ -213,17 +918,59 @@ where
         }
         // index
         changeset.merge(
-            self.tx_graph
+            self.tx_graph_mut()
                 .index
                 .reveal_to_target_multi(&last_active_indices)
                 .into(),
         );
         // tx graph
-        changeset.merge(self.tx_graph.apply_update(tx_update).into());
+        changeset.merge(self.tx_graph_mut().apply_update(tx_update).into());
 
         self.stage(changeset);
     }
 
+    /// The [`keyring::ChangeSet`] describing the current keyring of the wallet.
+    ///
+    /// The descriptors are held by the txout index once the wallet is constructed, so they are
+    /// taken from there.
+    pub(crate) fn keyring_changeset(&self) -> keyring::ChangeSet<K> {
+        keyring::ChangeSet {
+            network: Some(self.keyring.network),
+            descriptors: self
+                .keychains()
+                .map(|(keychain, desc)| (keychain, desc.clone()))
+                .collect(),
+            default_keychain: Some(self.keyring.default_keychain()),
+            groups: self.keyring.groups.clone(),
+            change_keychains: self.keyring.change_keychains.clone(),
+        }
+    }
+
+    /// The transaction graph, to be mutated.
+    ///
+    /// The graph is copied first if it is still shared with the checkpoint of a
+    /// [`WalletTransaction`](crate::multi_keychain::WalletTransaction), so that beginning a
+    /// transaction does not copy it.
+    fn tx_graph_mut(&mut self) -> &mut KeychainTxGraph<K> {
+        Arc::make_mut(&mut self.tx_graph)
+    }
+
+    /// A copy of the wallet with nothing staged, sharing the transaction graph until either of
+    /// them mutates it.
+    pub(crate) fn checkpoint(&self) -> Self {
+        Self {
+            keyring: self.keyring.clone(),
+            chain: self.chain.clone(),
+            tx_graph: Arc::clone(&self.tx_graph),
+            stage: ChangeSet::default(),
+            trusted_keychains: self.trusted_keychains.clone(),
+            utxo_locks: self.utxo_locks.clone(),
+            payments: self.payments.clone(),
+            spending_priority: self.spending_priority.clone(),
+            keychain_assets: self.keychain_assets.clone(),
+        }
+    }
+
     /// Stages anything that can be converted directly into a [`ChangeSet`].
     fn stage(&mut self, changeset: impl Into<ChangeSet<K>>) {
         self.stage.merge(changeset.into());
@@ -237,6 +984,21 @@ where
             Some(&self.stage)
         }
     }
+
+    /// Take the staged changes if any, leaving the stage empty.
+    ///
+    /// The caller is responsible for persisting the returned changes.
+    pub fn take_staged(&mut self) -> Option<ChangeSet<K>> {
+        self.stage.take()
+    }
+}
+
+impl<K: Ord> Wallet<K> {
+    /// Stage `changeset` before the changes already staged, which take precedence.
+    pub(crate) fn restage(&mut self, changeset: ChangeSet<K>) {
+        let staged = core::mem::replace(&mut self.stage, changeset);
+        self.stage.merge(staged);
+    }
 }
 
 #[cfg(feature = "rusqlite")]
@@ -246,6 +1008,8 @@ use bdk_chain::DescriptorId;
 #[cfg(feature = "rusqlite")]
 impl Wallet<DescriptorId> {
     /// Construct [`Wallet`] from SQLite.
+    ///
+    /// The script pubkey cache is used if the database contains cached script pubkeys.
     pub fn from_sqlite(conn: &mut rusqlite::Connection) -> rusqlite::Result<Option<Self>> {
         let tx = conn.transaction()?;
 
@@ -255,6 +1019,22 @@ impl Wallet<DescriptorId> {
         Ok(changeset.and_then(Self::from_changeset))
     }
 
+    /// Construct [`Wallet`] from SQLite, choosing whether to use the script pubkey cache.
+    ///
+    /// See [`Wallet::from_changeset_with_spk_cache`].
+    pub fn from_sqlite_with_spk_cache(
+        conn: &mut rusqlite::Connection,
+        use_spk_cache: bool,
+    ) -> rusqlite::Result<Option<Self>> {
+        let tx = conn.transaction()?;
+
+        let changeset = ChangeSet::initialize(&tx)?;
+        tx.commit()?;
+
+        Ok(changeset
+            .and_then(|changeset| Self::from_changeset_with_spk_cache(changeset, use_spk_cache)))
+    }
+
     /// Persist to SQLite. Returns the newly committed changeset if successful, or `None`
     /// if the stage is currently empty.
     pub fn persist_to_sqlite(
@@ -284,6 +1064,41 @@ impl Wallet<DescriptorId> {
     }
 }
 
+/// An unspent output owned by the [`Wallet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalOutput<K> {
+    /// Reference to the output.
+    pub outpoint: OutPoint,
+    /// The output itself.
+    pub txout: TxOut,
+    /// Keychain the output belongs to.
+    pub keychain: K,
+    /// Derivation index of the output's script pubkey in the keychain.
+    pub derivation_index: u32,
+    /// Position of the transaction creating the output in the chain.
+    pub chain_position: ChainPosition<ConfirmationBlockTime>,
+    /// Whether the output can be spent at the current tip, which is not the case for immature
+    /// coinbase outputs and outputs whose spending path is still timelocked.
+    pub is_spendable: bool,
+    /// The lock in effect on the output, if any, see [`Wallet::lock_outpoint`].
+    pub lock: Option<UtxoLock>,
+    /// When the spending path of the output becomes spendable, if it is timelocked, see
+    /// [`Wallet::set_keychain_assets`].
+    pub spendable_from: Option<SpendableFrom>,
+}
+
+/// A lock preventing an output of the [`Wallet`] from being spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum UtxoLock {
+    /// Locked, e.g. reserved for an in-flight payment, see [`Wallet::lock_outpoint`].
+    Locked {
+        /// Height of the local chain tip at which the lock expires, if any.
+        expiry: Option<u32>,
+    },
+    /// Frozen until explicitly unfrozen, see [`Wallet::freeze_outpoint`].
+    Frozen,
+}
+
 /// A derived address and the index it was found at.
 /// For convenience this automatically derefs to `Address`
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -448,7 +1263,11 @@ where
                 .map_err(ApplyBlockError)?
                 .into(),
         );
-        changeset.merge(self.tx_graph.apply_block_relevant(block, height).into());
+        changeset.merge(
+            self.tx_graph_mut()
+                .apply_block_relevant(block, height)
+                .into(),
+        );
         self.stage.merge(changeset);
         Ok(())
     }
@@ -466,7 +1285,7 @@ where
         unconfirmed_txs: impl IntoIterator<Item = (T, u64)>,
     ) {
         let tx_graph_changeset = self
-            .tx_graph
+            .tx_graph_mut()
             .batch_insert_relevant_unconfirmed(unconfirmed_txs);
         self.stage.merge(tx_graph_changeset.into());
     }
@@ -485,7 +1304,7 @@ where
             .map(|c_tx| c_tx.tx_node.txid)
             .collect();
 
-        let tx_graph_changeset = self.tx_graph.batch_insert_relevant_evicted_at(
+        let tx_graph_changeset = self.tx_graph_mut().batch_insert_relevant_evicted_at(
             evicted_txs
                 .into_iter()
                 .filter(|(txid, _)| canon_txids.contains(txid)),
@@ -496,23 +1315,15 @@ where
 
 #[cfg(test)]
 mod test {
-    use crate::bdk_chain::{DescriptorExt, DescriptorId};
-    use crate::multi_keychain::{KeyRing, Wallet};
-    use bitcoin::{secp256k1::Secp256k1, Network};
-    use miniscript::Descriptor;
-    use tempfile::NamedTempFile;
-
+    use crate::bdk_chain::Balance;
     #[cfg(feature = "rusqlite")]
-    use crate::bdk_chain::rusqlite;
-
-    const DESCRIPTORS: [&str; 6] = ["wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/0/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/1/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/2/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/3/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/4/*)", "wpkh(tpubDCzuCBKnZA5TNKhiJnASku7kq8Q4iqcVF82JV7mHo2NxWpXkLRbrJaGA5ToE7LCuWpcPErBbpDzbdWKN8aTdJzmRy1jQPmZvnqpwwDwCdy7/5/*)"];
-
-    fn descriptor_id(s: &str) -> DescriptorId {
-        let desc = Descriptor::parse_descriptor(&Secp256k1::new(), s)
-            .expect("failed to parse descriptor")
-            .0;
-        desc.descriptor_id()
-    }
+    use crate::bdk_chain::{rusqlite, DescriptorId};
+    use crate::collections::BTreeSet;
+    use crate::multi_keychain::{test_utils::*, KeyRing, UtxoLock, Wallet};
+    use alloc::vec::Vec;
+    use bitcoin::{Amount, Network, OutPoint};
+    #[cfg(feature = "rusqlite")]
+    use tempfile::NamedTempFile;
 
     #[cfg(feature = "rusqlite")]
     #[test]
@@ -535,4 +1346,476 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn list_unspent() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let op0 = receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        let op1 = receive_unconfirmed(&mut wallet, k1, Amount::from_sat(20_000), 100);
+        let _ = receive_unconfirmed(&mut wallet, k1, Amount::from_sat(30_000), 100);
+
+        let utxos = wallet.list_unspent().collect::<Vec<_>>();
+        assert_eq!(utxos.len(), 3);
+        let utxo0 = utxos.iter().find(|utxo| utxo.outpoint == op0).unwrap();
+        assert_eq!(utxo0.keychain, k0);
+        assert_eq!(utxo0.derivation_index, 0);
+        assert_eq!(utxo0.txout.value, Amount::from_sat(10_000));
+        assert!(utxo0.chain_position.is_confirmed());
+        assert!(utxo0.is_spendable);
+        let utxo1 = utxos.iter().find(|utxo| utxo.outpoint == op1).unwrap();
+        assert_eq!(utxo1.keychain, k1);
+        assert!(!utxo1.chain_position.is_confirmed());
+
+        let utxos = wallet.list_unspent_from([k1]).collect::<Vec<_>>();
+        assert_eq!(utxos.len(), 2);
+        assert!(utxos.iter().all(|utxo| utxo.keychain == k1));
+        assert_eq!(
+            utxos
+                .iter()
+                .map(|utxo| utxo.derivation_index)
+                .collect::<BTreeSet<_>>(),
+            [0, 1].into()
+        );
+    }
+
+    #[test]
+    fn balances_by_keychain_and_group() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let k2 = descriptor_id(DESCRIPTORS[2]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        receive_confirmed(&mut wallet, k1, Amount::from_sat(20_000));
+        receive_unconfirmed(&mut wallet, k1, Amount::from_sat(5_000), 100);
+
+        let balances = wallet.balance_by_keychain();
+        assert_eq!(balances.len(), 3);
+        assert_eq!(balances[&k0].confirmed, Amount::from_sat(10_000));
+        assert_eq!(balances[&k1].confirmed, Amount::from_sat(20_000));
+        assert_eq!(balances[&k1].untrusted_pending, Amount::from_sat(5_000));
+        assert_eq!(balances[&k2].total(), Amount::ZERO);
+        assert_eq!(
+            balances
+                .values()
+                .cloned()
+                .fold(Balance::default(), |acc, b| acc + b),
+            wallet.balance()
+        );
+        assert_eq!(wallet.balance_of([k1, k2]), balances[&k1]);
+
+        assert!(wallet.add_keychain_group("savings", [k0, k2]));
+        assert!(wallet.add_keychain_group("spending", [k1]));
+        assert!(!wallet.add_keychain_group("empty", []));
+        assert!(!wallet.add_keychain_group("unknown", [descriptor_id(DESCRIPTORS[5])]));
+        assert!(!wallet.keychain_groups().contains_key("empty"));
+        assert!(!wallet.keychain_groups().contains_key("unknown"));
+        assert_eq!(
+            wallet.balance_of_group("savings"),
+            Some(balances[&k0].clone())
+        );
+        assert_eq!(wallet.balance_of_group("checking"), None);
+        let group_balances = wallet.balance_by_group();
+        assert_eq!(group_balances["savings"], balances[&k0]);
+        assert_eq!(group_balances["spending"], balances[&k1]);
+
+        assert!(wallet.remove_keychain_group("spending"));
+        assert!(!wallet.remove_keychain_group("spending"));
+        let restored = Wallet::from_changeset(wallet.staged().cloned().unwrap()).unwrap();
+        assert_eq!(restored.keychain_groups(), wallet.keychain_groups());
+        assert_eq!(restored.keychain_groups().len(), 1);
+    }
+
+    #[test]
+    fn trusted_keychains() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let op0 = receive_unconfirmed(&mut wallet, k0, Amount::from_sat(10_000), 100);
+        receive_unconfirmed(&mut wallet, k1, Amount::from_sat(20_000), 100);
+        let op2 = receive_confirmed(&mut wallet, k1, Amount::from_sat(30_000));
+
+        assert_eq!(wallet.balance().trusted_pending, Amount::ZERO);
+        assert_eq!(
+            wallet
+                .list_spendable()
+                .map(|utxo| utxo.outpoint)
+                .collect::<Vec<_>>(),
+            [op2]
+        );
+
+        wallet.set_keychain_trusted(k0, true);
+        assert!(wallet.is_keychain_trusted(&k0));
+        let balance = wallet.balance();
+        assert_eq!(balance.trusted_pending, Amount::from_sat(10_000));
+        assert_eq!(balance.untrusted_pending, Amount::from_sat(20_000));
+        let balances = wallet.balance_by_keychain();
+        assert_eq!(balances[&k0].trusted_pending, Amount::from_sat(10_000));
+        assert_eq!(balances[&k1].untrusted_pending, Amount::from_sat(20_000));
+        let spendable = wallet
+            .list_spendable()
+            .map(|utxo| utxo.outpoint)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(spendable, [op0, op2].into());
+
+        wallet.set_keychain_trusted(k0, false);
+        assert_eq!(wallet.balance().trusted_pending, Amount::ZERO);
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_trusted_keychains() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        receive_unconfirmed(&mut wallet, k0, Amount::from_sat(10_000), 100);
+        wallet.set_keychain_trusted(k0, true);
+        wallet.set_keychain_trusted(k1, true);
+        wallet.persist_to_sqlite(&mut conn)?;
+        wallet.set_keychain_trusted(k1, false);
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert!(wallet.is_keychain_trusted(&k0));
+        assert!(!wallet.is_keychain_trusted(&k1));
+        assert_eq!(wallet.balance().trusted_pending, Amount::from_sat(10_000));
+        let restored = Wallet::from_snapshot(wallet.to_snapshot()).unwrap();
+        assert!(restored.is_keychain_trusted(&k0));
+        assert_eq!(restored.balance(), wallet.balance());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_keychain_groups() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        assert!(wallet.add_keychain_group("all", [k0, k1]));
+        assert!(wallet.add_keychain_group("first", [k0]));
+        wallet.persist_to_sqlite(&mut conn)?;
+        assert!(wallet.add_keychain_group("all", [k1]));
+        wallet.remove_keychain_group("first");
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert_eq!(
+            wallet.keychain_groups(),
+            &[("all".into(), [k1].into())].into()
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_spending_priority() -> anyhow::Result<()> {
+        use bitcoin::FeeRate;
+        use miniscript::descriptor::DescriptorType;
+
+        use crate::multi_keychain::{Preference, SpendingPriority};
+
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..2]);
+        wallet.set_spending_priority(SpendingPriority::new([Preference::Keychain(k1)]));
+        wallet.persist_to_sqlite(&mut conn)?;
+        let priority = SpendingPriority::new([
+            Preference::DescriptorType(DescriptorType::Tr),
+            Preference::Keychain(k1),
+        ])
+        .up_to_fee_rate(
+            FeeRate::from_sat_per_vb_u32(5),
+            [Preference::DescriptorType(DescriptorType::ShWpkh)],
+        )
+        .up_to_fee_rate(FeeRate::from_sat_per_vb_u32(20), []);
+        wallet.set_spending_priority(priority.clone());
+        wallet.persist_to_sqlite(&mut conn)?;
+        wallet.set_spending_priority(priority.clone());
+        assert!(wallet.staged().is_none());
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert_eq!(wallet.spending_priority(), &priority);
+        let json = serde_json::to_string(&wallet.to_snapshot())?;
+        let restored = Wallet::from_snapshot(serde_json::from_str(&json)?)?;
+        assert_eq!(restored.spending_priority(), &priority);
+
+        Ok(())
+    }
+
+    #[test]
+    fn next_unused_address() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+
+        let first = wallet.next_unused_address(k0).unwrap();
+        assert_eq!(first.index, 0);
+        assert_eq!(wallet.next_unused_address(k0).unwrap(), first);
+        assert_eq!(wallet.peek_address(k0, 0).unwrap(), first);
+        assert_eq!(wallet.txout_index().last_revealed_index(k0), Some(0));
+
+        // Handed out off-chain.
+        assert!(wallet.mark_used(k0, 0));
+        assert!(!wallet.mark_used(k0, 0));
+        let second = wallet.next_unused_address(k0).unwrap();
+        assert_eq!(second.index, 1);
+
+        // Peeking does not reveal.
+        assert_eq!(wallet.peek_address(k0, 7).unwrap().index, 7);
+        assert_eq!(wallet.txout_index().last_revealed_index(k0), Some(1));
+        assert!(wallet.peek_address(k0, 1 << 31).is_none());
+
+        assert!(wallet.unmark_used(k0, 0));
+        assert_eq!(wallet.next_unused_address(k0).unwrap(), first);
+        assert_eq!(
+            wallet.staged().unwrap().marked_used,
+            [(k0, [(0, false)].into())].into()
+        );
+
+        // Addresses that received funds can not be unmarked.
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        assert!(wallet.txout_index().is_used(k0, 2));
+        assert!(!wallet.unmark_used(k0, 2));
+    }
+
+    #[test]
+    fn used_marks_are_snapshotted() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        let _ = wallet.next_unused_address(k0);
+        assert!(wallet.mark_used(k0, 1));
+
+        // Only the mark of the address without outputs is kept.
+        let snapshot = wallet.to_snapshot();
+        assert_eq!(
+            snapshot.changeset.marked_used,
+            [(k0, [(1, true)].into())].into()
+        );
+        let mut restored = Wallet::from_snapshot(snapshot).unwrap();
+        assert_eq!(restored.next_unused_address(k0).unwrap().index, 2);
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_used_marks() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        for _ in 0..3 {
+            let _ = wallet.reveal_next_address(k0);
+        }
+        assert!(wallet.mark_used(k0, 0));
+        assert!(wallet.mark_used(k0, 1));
+        wallet.persist_to_sqlite(&mut conn)?;
+        assert!(wallet.unmark_used(k0, 0));
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let mut wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert!(!wallet.txout_index().is_used(k0, 0));
+        assert!(wallet.txout_index().is_used(k0, 1));
+        assert_eq!(wallet.next_unused_address(k0).unwrap().index, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn locked_and_frozen_outpoints() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let op0 = receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        let op1 = receive_confirmed(&mut wallet, k0, Amount::from_sat(20_000));
+        let op2 = receive_confirmed(&mut wallet, k0, Amount::from_sat(30_000));
+        let tip = wallet.latest_checkpoint().height();
+
+        assert!(wallet.lock_outpoint(op0, None));
+        assert!(wallet.lock_outpoint(op1, Some(tip + 1)));
+        wallet.freeze_outpoint(op2);
+        assert!(!wallet.lock_outpoint(op2, None));
+
+        let lock_of = |wallet: &Wallet<_>, outpoint| {
+            wallet
+                .list_unspent()
+                .find(|utxo| utxo.outpoint == outpoint)
+                .unwrap()
+                .lock
+        };
+        assert_eq!(
+            lock_of(&wallet, op0),
+            Some(UtxoLock::Locked { expiry: None })
+        );
+        assert_eq!(lock_of(&wallet, op2), Some(UtxoLock::Frozen));
+        assert_eq!(wallet.list_spendable().count(), 0);
+        assert_eq!(wallet.locked_outpoints().count(), 3);
+
+        // The lock on op1 expires with the next block.
+        add_block(&mut wallet, vec![]);
+        assert_eq!(lock_of(&wallet, op1), None);
+        assert_eq!(
+            wallet
+                .list_spendable()
+                .map(|utxo| utxo.outpoint)
+                .collect::<Vec<_>>(),
+            [op1]
+        );
+
+        assert!(!wallet.unlock_outpoint(op2));
+        assert!(wallet.unlock_outpoint(op0));
+        assert!(wallet.unfreeze_outpoint(op2));
+        assert!(!wallet.unfreeze_outpoint(op2));
+        assert_eq!(wallet.list_spendable().count(), 3);
+        assert_eq!(
+            wallet.staged().unwrap().utxo_locks,
+            [
+                (op0, None),
+                (
+                    op1,
+                    Some(UtxoLock::Locked {
+                        expiry: Some(tip + 1)
+                    })
+                ),
+                (op2, None)
+            ]
+            .into()
+        );
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_utxo_locks() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let op0 = receive_confirmed(&mut wallet, k0, Amount::from_sat(10_000));
+        let op1 = receive_confirmed(&mut wallet, k0, Amount::from_sat(20_000));
+        let op2 = receive_confirmed(&mut wallet, k0, Amount::from_sat(30_000));
+        wallet.lock_outpoint(op0, Some(1_000));
+        wallet.freeze_outpoint(op1);
+        wallet.lock_outpoint(op2, None);
+        wallet.persist_to_sqlite(&mut conn)?;
+        wallet.unlock_outpoint(op2);
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert_eq!(
+            wallet.locked_outpoints().collect::<Vec<_>>(),
+            [
+                (
+                    op0,
+                    UtxoLock::Locked {
+                        expiry: Some(1_000)
+                    }
+                ),
+                (op1, UtxoLock::Frozen)
+            ]
+            .into_iter()
+            .collect::<Vec<_>>()
+        );
+        let restored = Wallet::from_snapshot(wallet.to_snapshot()).unwrap();
+        assert_eq!(
+            restored.locked_outpoints().collect::<Vec<_>>(),
+            wallet.locked_outpoints().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_change_keychains() -> anyhow::Result<()> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let k1 = descriptor_id(DESCRIPTORS[1]);
+        let k2 = descriptor_id(DESCRIPTORS[2]);
+        assert!(Wallet::from_sqlite(&mut conn)?.is_none());
+
+        let mut wallet = new_wallet(&DESCRIPTORS[..3]);
+        wallet.set_change_keychain(k0, k1);
+        wallet.persist_to_sqlite(&mut conn)?;
+        wallet.set_change_keychain(k0, k2);
+        wallet.persist_to_sqlite(&mut conn)?;
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert_eq!(wallet.change_keychain(&k0), k2);
+        assert_eq!(wallet.change_keychain(&k1), k1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn immature_coinbase_is_not_spendable() {
+        let mut wallet = new_wallet(&DESCRIPTORS[..1]);
+        let k0 = descriptor_id(DESCRIPTORS[0]);
+        let mut coinbase = receive_tx(&mut wallet, k0, Amount::from_sat(50_000));
+        coinbase.input[0].previous_output = OutPoint::null();
+        add_block(&mut wallet, vec![coinbase]);
+
+        let utxo = wallet.list_unspent().next().unwrap();
+        assert!(!utxo.is_spendable);
+        for _ in 0..99 {
+            add_block(&mut wallet, vec![]);
+        }
+        let utxo = wallet.list_unspent().next().unwrap();
+        assert!(utxo.is_spendable);
+    }
+
+    #[test]
+    fn spk_cache_is_staged_and_reloaded() {
+        let keychain = descriptor_id(DESCRIPTORS[0]);
+        let keyring = KeyRing::new(Network::Signet, keychain, DESCRIPTORS[0]);
+        let mut wallet = Wallet::new_with_spk_cache(keyring, true);
+        let _ = wallet.reveal_next_address(keychain);
+
+        let changeset = wallet.staged().cloned().unwrap();
+        let lookahead = wallet.txout_index().lookahead() as usize;
+        assert_eq!(changeset.indexer.spk_cache[&keychain].len(), lookahead + 1);
+
+        // Nothing is left to derive, so nothing is staged on load.
+        let wallet = Wallet::from_changeset(changeset.clone()).unwrap();
+        assert!(wallet.staged().is_none());
+        assert_eq!(
+            wallet.to_snapshot().changeset.indexer.spk_cache,
+            changeset.indexer.spk_cache
+        );
+
+        // Without the cache the script pubkeys are derived and the cache is dropped.
+        let wallet = Wallet::from_changeset_with_spk_cache(changeset, false).unwrap();
+        assert!(wallet.to_snapshot().changeset.indexer.spk_cache.is_empty());
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_spk_cache() -> anyhow::Result<()> {
+        let db_file = NamedTempFile::new()?;
+        let mut conn = rusqlite::Connection::open(db_file.path())?;
+        let keychain = descriptor_id(DESCRIPTORS[0]);
+        let keyring = KeyRing::new(Network::Signet, keychain, DESCRIPTORS[0]);
+
+        assert!(Wallet::<DescriptorId>::from_sqlite(&mut conn)?.is_none());
+        let mut wallet = Wallet::new_with_spk_cache(keyring, true);
+        let _ = wallet.reveal_next_address(keychain);
+        wallet.persist_to_sqlite(&mut conn)?;
+        let expected = wallet.to_snapshot().changeset.indexer.spk_cache;
+        assert!(!expected.is_empty());
+
+        let wallet = Wallet::from_sqlite(&mut conn)?.unwrap();
+        assert!(wallet.staged().is_none());
+        assert_eq!(wallet.to_snapshot().changeset.indexer.spk_cache, expected);
+
+        Ok(())
+    }
 }